@@ -0,0 +1,114 @@
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+use crate::{cidr::Cidr, config::AclConfig};
+
+/// What a client is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AclAction {
+    /// Full service, including recursion.
+    Allow,
+    /// Local data and cached answers only, anything else is refused.
+    NoRecurse,
+    /// Every query is answered with REFUSED.
+    Refuse,
+}
+
+/// Client access rules, matched against the source address in order; the
+/// first rule whose network contains the client decides.
+#[derive(Debug)]
+pub struct Acl {
+    rules: Vec<(Cidr, AclAction)>,
+    default: AclAction,
+}
+
+impl Acl {
+    pub fn new(config: &AclConfig) -> Result<Acl, String> {
+        let mut rules = Vec::new();
+
+        for rule in &config.rules {
+            for network in &rule.networks {
+                rules.push((network.parse::<Cidr>()?, rule.action));
+            }
+        }
+
+        Ok(Acl {
+            rules,
+            default: config.default,
+        })
+    }
+
+    pub fn check(&self, addr: IpAddr) -> AclAction {
+        self.rules
+            .iter()
+            .find(|(network, _)| network.contains(addr))
+            .map(|(_, action)| *action)
+            .unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AclRule;
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn default_serves_only_local_networks() {
+        let acl = Acl::new(&AclConfig::default()).unwrap();
+
+        for allowed in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.20.0.1",
+            "192.168.1.1",
+            "::1",
+            "fd00::1",
+        ] {
+            assert_eq!(acl.check(addr(allowed)), AclAction::Allow, "{allowed}");
+        }
+        for refused in ["8.8.8.8", "100.64.0.1", "172.32.0.1", "2001:db8::1"] {
+            assert_eq!(acl.check(addr(refused)), AclAction::Refuse, "{refused}");
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let config = AclConfig {
+            default: AclAction::Allow,
+            rules: vec![
+                AclRule {
+                    networks: vec!["192.0.2.1".to_string()],
+                    action: AclAction::Refuse,
+                },
+                AclRule {
+                    networks: vec!["192.0.2.0/24".to_string(), "2001:db8::/32".to_string()],
+                    action: AclAction::NoRecurse,
+                },
+            ],
+        };
+        let acl = Acl::new(&config).unwrap();
+
+        assert_eq!(acl.check(addr("192.0.2.1")), AclAction::Refuse);
+        assert_eq!(acl.check(addr("192.0.2.2")), AclAction::NoRecurse);
+        assert_eq!(acl.check(addr("2001:db8::53")), AclAction::NoRecurse);
+        assert_eq!(acl.check(addr("198.51.100.1")), AclAction::Allow);
+    }
+
+    #[test]
+    fn rejects_bad_networks() {
+        let config = AclConfig {
+            default: AclAction::Refuse,
+            rules: vec![AclRule {
+                networks: vec!["10.0.0.0/40".to_string()],
+                action: AclAction::Allow,
+            }],
+        };
+        assert!(Acl::new(&config).is_err());
+    }
+}
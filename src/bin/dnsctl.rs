@@ -0,0 +1,71 @@
+//! Sends a command to a running dns-server over its control socket and
+//! prints the output.
+
+use std::{
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    process::ExitCode,
+};
+
+const DEFAULT_SOCKET: &str = "/run/dns-server/control.sock";
+
+const USAGE: &str = "usage: dnsctl [-s SOCKET] COMMAND
+
+commands:
+  flush               remove every cache entry
+  flush NAME          remove the cache entries for NAME
+  flush-tree NAME     remove the cache entries for NAME and names below it
+  dump                list the cache contents
+  stats               show query and cache counters
+  reload              reread the config file, zones, hosts, blocklists, RPZ and ACL
+  reload WHAT         reread zones, hosts, blocklists or rpz
+  querylog [on|off]   show or switch query logging";
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut socket = DEFAULT_SOCKET.to_string();
+    if args.first().map(String::as_str) == Some("-s") {
+        if args.len() < 2 {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+        socket = args.remove(1);
+        args.remove(0);
+    }
+
+    if args.is_empty() || args[0] == "-h" || args[0] == "--help" {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    }
+
+    match send(&socket, &args.join(" ")) {
+        Ok(response) => match response.split_once('\n') {
+            Some(("OK", output)) => {
+                print!("{output}");
+                ExitCode::SUCCESS
+            }
+            _ => {
+                let reason = response.trim_end();
+                eprintln!("dnsctl: {}", reason.strip_prefix("ERR ").unwrap_or(reason));
+                ExitCode::FAILURE
+            }
+        },
+        Err(e) => {
+            eprintln!("dnsctl: {socket}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn send(socket: &str, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(format!("{command}\n").as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    Ok(response)
+}
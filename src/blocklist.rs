@@ -0,0 +1,308 @@
+use std::{
+    collections::HashSet,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::{
+    config::{BlockResponse, BlocklistConfig},
+    dns::{DnsPacket, DnsRecord, QueryType, ResultCode},
+    master::normalize_name,
+    zone::parent_name,
+};
+
+// Names hosts-format lists map to the null address that aren't meant as
+// blocks.
+const HOSTS_BOILERPLATE: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "0.0.0.0",
+];
+
+/// Domains to filter, loaded from hosts-format, plain domain-list and
+/// Adblock-style files. Blocking a domain blocks all its subdomains, unless an
+/// allowlist entry covers the name.
+#[derive(Debug)]
+pub struct Blocklist {
+    blocked: HashSet<String>,
+    allowed: HashSet<String>,
+    response: BlockResponse,
+    sinkhole_ipv4: Option<Ipv4Addr>,
+    sinkhole_ipv6: Option<Ipv6Addr>,
+    ttl: u32,
+}
+
+impl Default for Blocklist {
+    fn default() -> Self {
+        Self {
+            blocked: HashSet::new(),
+            allowed: HashSet::new(),
+            response: BlockResponse::Nxdomain,
+            sinkhole_ipv4: None,
+            sinkhole_ipv6: None,
+            ttl: 0,
+        }
+    }
+}
+
+impl Blocklist {
+    pub fn load(config: &BlocklistConfig) -> Result<Blocklist, String> {
+        let mut list = Blocklist {
+            response: config.response,
+            sinkhole_ipv4: config.sinkhole_ipv4,
+            sinkhole_ipv6: config.sinkhole_ipv6,
+            ttl: config.ttl,
+            ..Default::default()
+        };
+
+        if config.response == BlockResponse::Sinkhole
+            && config.sinkhole_ipv4.is_none()
+            && config.sinkhole_ipv6.is_none()
+        {
+            return Err("blocklist sinkhole response needs a sinkhole address".into());
+        }
+
+        for path in &config.files {
+            let raw = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+
+            for line in raw.lines() {
+                list.parse_line(line);
+            }
+        }
+
+        for name in &config.allow {
+            list.allowed.insert(normalize_name(name));
+        }
+
+        Ok(list)
+    }
+
+    /// Parses one line of a list, detecting its format from the line itself so
+    /// that files mixing formats still load.
+    fn parse_line(&mut self, line: &str) {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with(['#', '!', '[']) {
+            return;
+        }
+
+        if let Some(rule) = line.strip_prefix("@@||") {
+            if let Some(name) = adblock_domain(rule) {
+                self.allowed.insert(name);
+            }
+            return;
+        }
+
+        if let Some(rule) = line.strip_prefix("||") {
+            if let Some(name) = adblock_domain(rule) {
+                self.blocked.insert(name);
+            }
+            return;
+        }
+
+        let line = line.split('#').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        let names = match fields.as_slice() {
+            [_] => &fields[..],
+            [addr, names @ ..] if addr.parse::<IpAddr>().is_ok() => names,
+            _ => return,
+        };
+
+        for name in names {
+            let name = normalize_name(name);
+            if is_domain(&name) && !HOSTS_BOILERPLATE.contains(&name.as_str()) {
+                self.blocked.insert(name);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocked.len()
+    }
+
+    pub fn is_blocked(&self, qname: &str) -> bool {
+        matches_suffix(&self.blocked, qname) && !matches_suffix(&self.allowed, qname)
+    }
+
+    /// Builds the configured block response when `qname` is blocked.
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        if !self.is_blocked(qname) {
+            return None;
+        }
+
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+
+        let (ipv4, ipv6) = match self.response {
+            BlockResponse::Nxdomain => {
+                packet.header.rescode = ResultCode::NXDOMAIN;
+                return Some(packet);
+            }
+            BlockResponse::Refused => {
+                packet.header.rescode = ResultCode::REFUSED;
+                return Some(packet);
+            }
+            BlockResponse::Null => (Some(Ipv4Addr::UNSPECIFIED), Some(Ipv6Addr::UNSPECIFIED)),
+            BlockResponse::Sinkhole => (self.sinkhole_ipv4, self.sinkhole_ipv6),
+        };
+
+        let domain = qname.to_string();
+        let ttl = self.ttl;
+        match qtype {
+            QueryType::A => {
+                packet
+                    .answers
+                    .extend(ipv4.map(|addr| DnsRecord::A { domain, addr, ttl }))
+            }
+            QueryType::AAAA => {
+                packet
+                    .answers
+                    .extend(ipv6.map(|addr| DnsRecord::AAAA { domain, addr, ttl }))
+            }
+            _ => {}
+        }
+
+        Some(packet)
+    }
+}
+
+/// Returns true if `name` or one of its parents is in `set`.
+fn matches_suffix(set: &HashSet<String>, name: &str) -> bool {
+    let mut node = Some(name);
+
+    while let Some(name) = node {
+        if !name.is_empty() && set.contains(name) {
+            return true;
+        }
+        node = parent_name(name);
+    }
+
+    false
+}
+
+/// Extracts the domain of a `||domain^` rule. Rules with paths, wildcards or
+/// other patterns can't be expressed in DNS and are skipped.
+fn adblock_domain(rule: &str) -> Option<String> {
+    let rule = rule.split('$').next()?;
+    let name = rule.strip_suffix('^').unwrap_or(rule);
+    let name = normalize_name(name);
+
+    is_domain(&name).then_some(name)
+}
+
+fn is_domain(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(lines: &[&str]) -> Blocklist {
+        let mut list = Blocklist::default();
+        for line in lines {
+            list.parse_line(line);
+        }
+        list
+    }
+
+    #[test]
+    fn parses_hosts_format() {
+        let list = parse(&[
+            "# StevenBlack-style hosts file",
+            "127.0.0.1 localhost",
+            "::1 ip6-localhost ip6-loopback",
+            "0.0.0.0 0.0.0.0",
+            "0.0.0.0 Ads.Example.com tracker.example.net # inline comment",
+            "127.0.0.1\tmetrics.example.org",
+        ]);
+
+        assert_eq!(list.len(), 3);
+        assert!(list.is_blocked("ads.example.com"));
+        assert!(list.is_blocked("tracker.example.net"));
+        assert!(list.is_blocked("metrics.example.org"));
+        assert!(!list.is_blocked("localhost"));
+    }
+
+    #[test]
+    fn parses_domain_lists() {
+        let list = parse(&[
+            "example.com",
+            "  spaced.example  ",
+            "not a domain",
+            "bad!name",
+        ]);
+
+        assert_eq!(list.len(), 2);
+        assert!(list.is_blocked("example.com"));
+        assert!(list.is_blocked("spaced.example"));
+    }
+
+    #[test]
+    fn parses_adblock_rules() {
+        let list = parse(&[
+            "[Adblock Plus 2.0]",
+            "! comment",
+            "||ads.example.com^",
+            "||tracker.example.net^$third-party",
+            "||example.org/path^",
+            "||*.wild.example^",
+            "@@||good.ads.example.com^",
+        ]);
+
+        assert_eq!(list.len(), 2);
+        assert!(list.is_blocked("ads.example.com"));
+        assert!(list.is_blocked("tracker.example.net"));
+        assert!(!list.is_blocked("good.ads.example.com"));
+        assert!(list.is_blocked("bad.ads.example.com"));
+    }
+
+    #[test]
+    fn blocks_subdomains_unless_allowed() {
+        let mut list = parse(&["example.com"]);
+        list.allowed.insert("cdn.example.com".to_string());
+
+        assert!(list.is_blocked("example.com"));
+        assert!(list.is_blocked("a.b.example.com"));
+        assert!(!list.is_blocked("cdn.example.com"));
+        assert!(!list.is_blocked("img.cdn.example.com"));
+        assert!(!list.is_blocked("notexample.com"));
+        assert!(!list.is_blocked("com"));
+    }
+
+    #[test]
+    fn answers_with_the_configured_response() {
+        let mut list = parse(&["example.com"]);
+        assert!(list.lookup("example.org", QueryType::A).is_none());
+
+        let packet = list.lookup("www.example.com", QueryType::A).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+
+        list.response = BlockResponse::Null;
+        let packet = list.lookup("www.example.com", QueryType::AAAA).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(
+            packet.answers[0].to_string(),
+            "www.example.com. 0 IN AAAA ::"
+        );
+
+        list.response = BlockResponse::Sinkhole;
+        list.sinkhole_ipv4 = Some(Ipv4Addr::new(192, 0, 2, 1));
+        list.ttl = 60;
+        let packet = list.lookup("example.com", QueryType::A).unwrap();
+        assert_eq!(
+            packet.answers[0].to_string(),
+            "example.com. 60 IN A 192.0.2.1"
+        );
+        let packet = list.lookup("example.com", QueryType::AAAA).unwrap();
+        assert!(packet.answers.is_empty());
+    }
+}
@@ -1,13 +1,24 @@
+/// Size of a plain UDP message (RFC 1035 section 4.2.1).
+pub const UDP_MESSAGE_SIZE: usize = 512;
+
+/// Largest message the two byte length prefix of TCP allows.
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+
 #[derive(Debug)]
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
 }
 
 impl BytePacketBuffer {
     pub fn new() -> BytePacketBuffer {
+        Self::with_size(UDP_MESSAGE_SIZE)
+    }
+
+    /// A buffer holding messages of up to `size` bytes.
+    pub fn with_size(size: usize) -> BytePacketBuffer {
         Self {
-            buf: [0; 512],
+            buf: vec![0; size],
             pos: 0,
         }
     }
@@ -22,14 +33,14 @@ impl BytePacketBuffer {
         Ok(())
     }
 
-    fn seek(&mut self, pos: usize) -> Result<(), String> {
+    pub fn seek(&mut self, pos: usize) -> Result<(), String> {
         self.pos = pos;
 
         Ok(())
     }
 
     fn read(&mut self) -> Result<u8, String> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
 
@@ -40,7 +51,7 @@ impl BytePacketBuffer {
     }
 
     fn get(&mut self, pos: usize) -> Result<u8, String> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
 
@@ -48,13 +59,17 @@ impl BytePacketBuffer {
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8], String> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             return Err("End of buffer".into());
         }
 
         Ok(&self.buf[start..start + len])
     }
 
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        self.read()
+    }
+
     pub fn read_u16(&mut self) -> Result<u16, String> {
         let res = ((self.read()? as u16) << 8) | (self.read()? as u16);
 
@@ -70,7 +85,25 @@ impl BytePacketBuffer {
         Ok(res)
     }
 
+    pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, String> {
+        let bytes = self.get_range(self.pos, len)?.to_vec();
+        self.pos += len;
+
+        Ok(bytes)
+    }
+
     pub fn read_qname(&mut self, outstr: &mut String) -> Result<(), String> {
+        self.read_name(outstr, true)
+    }
+
+    /// Reads a name as it is on the wire, for the few places where case is
+    /// significant, like the next name in NSEC records (RFC 6840 section
+    /// 5.1).
+    pub fn read_qname_keep_case(&mut self, outstr: &mut String) -> Result<(), String> {
+        self.read_name(outstr, false)
+    }
+
+    fn read_name(&mut self, outstr: &mut String, lowercase: bool) -> Result<(), String> {
         let mut pos = self.pos;
 
         let mut jumped = false;
@@ -109,7 +142,12 @@ impl BytePacketBuffer {
                 outstr.push_str(delim);
 
                 let str_buffer = self.get_range(pos, len as usize)?;
-                outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+                let label = String::from_utf8_lossy(str_buffer);
+                if lowercase {
+                    outstr.push_str(&label.to_lowercase());
+                } else {
+                    outstr.push_str(&label);
+                }
 
                 delim = ".";
 
@@ -125,7 +163,7 @@ impl BytePacketBuffer {
     }
 
     fn write(&mut self, val: u8) -> Result<(), String> {
-        if self.pos() >= 512 {
+        if self.pos() >= self.buf.len() {
             return Err(String::from("End of buffer"));
         }
         self.buf[self.pos] = val;
@@ -155,8 +193,19 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.pos + bytes.len() > self.buf.len() {
+            return Err(String::from("End of buffer"));
+        }
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+
+        Ok(())
+    }
+
     pub fn write_qname(&mut self, qname: &str) -> Result<(), String> {
-        for label in qname.split('.') {
+        // The root name has no labels, only the terminating zero.
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
             let len = label.len();
             if len > 0x3F {
                 return Err(String::from("Single label exceeds 63 characters"));
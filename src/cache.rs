@@ -0,0 +1,1116 @@
+use std::{
+    fmt::Write,
+    fs,
+    hash::{BuildHasher, RandomState},
+    mem,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task,
+};
+use tracing::{error, info};
+
+use crate::{
+    config::CacheConfig,
+    dns::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode},
+    dnssec,
+    metrics::qtype_label,
+    zone::is_subdomain,
+    DNS_CACHE, METRICS,
+};
+
+// Independently locked parts of the cache, so that concurrent lookups rarely
+// wait on each other.
+const SHARDS: usize = 16;
+
+/// Whether an entry holds answers or an NXDOMAIN/NODATA response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Positive,
+    Negative,
+}
+
+impl EntryKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EntryKind::Positive => "positive",
+            EntryKind::Negative => "negative",
+        }
+    }
+}
+
+/// A cached response, with the absolute time at which it expires. Negative
+/// entries have no answers and keep the SOA from the authority section.
+#[derive(Debug)]
+struct CacheEntry {
+    rcode: ResultCode,
+    answers: Vec<DnsRecord>,
+    authorities: Vec<DnsRecord>,
+    /// Whether the response passed DNSSEC validation.
+    secure: bool,
+    /// The name servers of the delegation the response came from, for RPZ
+    /// NSDNAME triggers on later hits.
+    nameservers: Vec<String>,
+    expires: SystemTime,
+    /// The TTL the entry was cached with, in seconds.
+    ttl: u32,
+    /// Cache hits since the entry was stored.
+    hits: u32,
+    /// Whether a prefetch has been started for the entry.
+    prefetching: bool,
+    /// After a failed refresh, stale data is served without retrying until
+    /// this time.
+    retry_after: Option<SystemTime>,
+    /// Estimated memory held by the entry and its key.
+    size: usize,
+}
+
+impl CacheEntry {
+    fn new(
+        question: &DnsQuestion,
+        rcode: ResultCode,
+        answers: Vec<DnsRecord>,
+        authorities: Vec<DnsRecord>,
+        secure: bool,
+        ttl: u32,
+        expires: SystemTime,
+    ) -> CacheEntry {
+        let size = mem::size_of::<(DnsQuestion, CacheEntry)>()
+            + question.name.len()
+            + answers
+                .iter()
+                .chain(&authorities)
+                .map(record_size)
+                .sum::<usize>();
+
+        CacheEntry {
+            rcode,
+            answers,
+            authorities,
+            secure,
+            nameservers: Vec::new(),
+            expires,
+            ttl,
+            hits: 0,
+            prefetching: false,
+            retry_after: None,
+            size,
+        }
+    }
+
+    fn with_nameservers(mut self, nameservers: Vec<String>) -> CacheEntry {
+        self.size += nameservers
+            .iter()
+            .map(|ns| ns.len() + mem::size_of::<String>())
+            .sum::<usize>();
+        self.nameservers = nameservers;
+
+        self
+    }
+
+    /// Seconds left before the entry expires, at least one, or `None` once
+    /// it has.
+    fn remaining(&self, now: SystemTime) -> Option<u32> {
+        let remaining = self.expires.duration_since(now).ok()?;
+        if remaining.is_zero() {
+            return None;
+        }
+
+        Some(remaining.as_secs().max(1) as u32)
+    }
+
+    /// Negative entries, and only they, carry the SOA of the zone that
+    /// denied the name, after any CNAME chain leading there.
+    fn kind(&self) -> EntryKind {
+        if self
+            .authorities
+            .iter()
+            .any(|rec| matches!(rec, DnsRecord::SOA { .. }))
+        {
+            EntryKind::Negative
+        } else {
+            EntryKind::Positive
+        }
+    }
+
+    /// Builds the response with TTLs counted down to the time left, or
+    /// `None` if the entry has expired.
+    fn to_packet(&self, now: SystemTime) -> Option<DnsPacket> {
+        Some(self.to_packet_with_ttl(self.remaining(now)?))
+    }
+
+    fn to_packet_with_ttl(&self, ttl: u32) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = self.rcode;
+        packet.header.authed_data = self.secure;
+        packet.answers = self.answers.iter().map(|r| r.with_ttl(ttl)).collect();
+        packet.authorities = self.authorities.iter().map(|r| r.with_ttl(ttl)).collect();
+
+        packet
+    }
+}
+
+/// Rough inline and heap size of a record.
+fn record_size(rec: &DnsRecord) -> usize {
+    let data = match rec {
+        DnsRecord::NS { host, .. }
+        | DnsRecord::CNAME { host, .. }
+        | DnsRecord::PTR { host, .. }
+        | DnsRecord::MX { host, .. } => host.len(),
+        DnsRecord::SOA { mname, rname, .. } => mname.len() + rname.len(),
+        DnsRecord::DS { digest, .. } => digest.len(),
+        DnsRecord::RRSIG {
+            signer, signature, ..
+        } => signer.len() + signature.len(),
+        DnsRecord::DNSKEY { public_key, .. } => public_key.len(),
+        DnsRecord::NSEC {
+            next_domain, types, ..
+        } => next_domain.len() + types.len() * mem::size_of::<QueryType>(),
+        DnsRecord::NSEC3 {
+            salt,
+            next_hashed,
+            types,
+            ..
+        } => salt.len() + next_hashed.len() + types.len() * mem::size_of::<QueryType>(),
+        DnsRecord::NSEC3PARAM { salt, .. } => salt.len(),
+        DnsRecord::UNKNOWN { data, .. } => data.len(),
+        DnsRecord::TXT { data, .. } => data
+            .iter()
+            .map(|s| s.len() + mem::size_of::<String>())
+            .sum(),
+        _ => 0,
+    };
+
+    mem::size_of::<DnsRecord>() + rec.domain().len() + data
+}
+
+/// One LRU list, bounded by both entry count and estimated bytes.
+#[derive(Debug)]
+struct Lru {
+    entries: LruCache<DnsQuestion, CacheEntry>,
+    bytes: usize,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+impl Lru {
+    fn new(max_entries: usize, max_bytes: usize) -> Lru {
+        Lru {
+            entries: LruCache::unbounded(),
+            bytes: 0,
+            max_entries,
+            max_bytes,
+        }
+    }
+
+    /// Inserts an entry, evicting the least recently used ones past the
+    /// limits. Returns how many were evicted.
+    fn insert(&mut self, question: DnsQuestion, entry: CacheEntry) -> usize {
+        self.bytes += entry.size;
+        if let Some(old) = self.entries.put(question, entry) {
+            self.bytes -= old.size;
+        }
+
+        let mut evicted = 0;
+        while self.entries.len() > self.max_entries || self.bytes > self.max_bytes {
+            let Some((_, old)) = self.entries.pop_lru() else {
+                break;
+            };
+            self.bytes -= old.size;
+            evicted += 1;
+        }
+
+        evicted
+    }
+
+    fn remove(&mut self, question: &DnsQuestion) -> Option<CacheEntry> {
+        let entry = self.entries.pop(question)?;
+        self.bytes -= entry.size;
+
+        Some(entry)
+    }
+}
+
+#[derive(Debug)]
+struct Shard {
+    positive: Lru,
+    negative: Lru,
+}
+
+impl Shard {
+    fn lru(&mut self, kind: EntryKind) -> &mut Lru {
+        match kind {
+            EntryKind::Positive => &mut self.positive,
+            EntryKind::Negative => &mut self.negative,
+        }
+    }
+}
+
+/// A response found in the cache.
+#[derive(Debug)]
+pub struct CacheHit {
+    pub packet: DnsPacket,
+    /// Set on a popular entry close to expiry, once per entry; the caller
+    /// is expected to refresh it in the background.
+    pub prefetch: bool,
+    /// Whether this is expired data, served because refreshing it recently
+    /// failed.
+    pub stale: bool,
+    /// The name servers the response came from.
+    pub nameservers: Vec<String>,
+}
+
+/// Entry counts and estimated memory use of the cache.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub positive: usize,
+    pub negative: usize,
+    pub bytes: usize,
+}
+
+/// A cache entry as written to disk.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedEntry {
+    name: String,
+    qtype: QueryType,
+    /// Expiry time in seconds since the Unix epoch.
+    expires: u64,
+    #[serde(default)]
+    rcode: ResultCode,
+    records: Vec<DnsRecord>,
+    #[serde(default)]
+    authorities: Vec<DnsRecord>,
+    #[serde(default)]
+    secure: bool,
+    #[serde(default)]
+    nameservers: Vec<String>,
+}
+
+/// Responses from recursive lookups: answers, kept for the lowest TTL among
+/// them, and NXDOMAIN/NODATA responses, kept for the SOA's negative TTL
+/// (RFC 2308). Both are bounded and evicted least recently used first.
+#[derive(Debug)]
+pub struct Cache {
+    shards: Vec<Mutex<Shard>>,
+    hasher: RandomState,
+    negative_max_ttl: u32,
+    prefetch_threshold: u32,
+    prefetch_min_hits: u32,
+    stale_window: Duration,
+    stale_ttl: u32,
+    stale_timeout: Duration,
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig) -> Cache {
+        let shards = (0..SHARDS)
+            .map(|_| {
+                Mutex::new(Shard {
+                    positive: Lru::new(
+                        config.max_entries.div_ceil(SHARDS),
+                        config.max_bytes.div_ceil(SHARDS),
+                    ),
+                    negative: Lru::new(
+                        config.negative_max_entries.div_ceil(SHARDS),
+                        config.negative_max_bytes.div_ceil(SHARDS),
+                    ),
+                })
+            })
+            .collect();
+
+        Cache {
+            shards,
+            hasher: RandomState::new(),
+            negative_max_ttl: config.negative_max_ttl,
+            prefetch_threshold: config.prefetch_threshold.min(100) as u32,
+            prefetch_min_hits: config.prefetch_min_hits,
+            stale_window: Duration::from_secs(config.stale_window),
+            stale_ttl: config.stale_ttl,
+            stale_timeout: Duration::from_millis(config.stale_timeout_ms),
+        }
+    }
+
+    fn shard(&self, question: &DnsQuestion) -> MutexGuard<'_, Shard> {
+        let index = self.hasher.hash_one(question) as usize % SHARDS;
+        self.shards[index].lock().unwrap()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            stats.positive += shard.positive.entries.len();
+            stats.negative += shard.negative.entries.len();
+            stats.bytes += shard.positive.bytes + shard.negative.bytes;
+        }
+
+        stats
+    }
+
+    /// How long a lookup may take before stale data is served instead.
+    pub fn stale_timeout(&self) -> Duration {
+        self.stale_timeout
+    }
+
+    /// Whether something that expired at `expires` is still within the stale
+    /// window.
+    fn is_stale_usable(&self, expires: SystemTime, now: SystemTime) -> bool {
+        !self.stale_window.is_zero() && expires + self.stale_window > now
+    }
+
+    /// Returns the cached response with its TTLs counted down. Expired
+    /// entries are dropped once past the stale window, and until then only
+    /// returned, marked stale, while a failed refresh is waiting for a retry.
+    pub fn get(&self, question: &DnsQuestion) -> Option<CacheHit> {
+        let now = SystemTime::now();
+        let mut shard = self.shard(question);
+
+        let kind = if shard.positive.entries.contains(question) {
+            EntryKind::Positive
+        } else {
+            EntryKind::Negative
+        };
+        let lru = shard.lru(kind);
+
+        let entry = lru.entries.get_mut(question)?;
+        let Some(packet) = entry.to_packet(now) else {
+            if !self.is_stale_usable(entry.expires, now) {
+                lru.remove(question);
+                return None;
+            }
+
+            if entry.retry_after.is_some_and(|retry| retry > now) {
+                return Some(CacheHit {
+                    packet: entry.to_packet_with_ttl(self.stale_ttl),
+                    prefetch: false,
+                    stale: true,
+                    nameservers: entry.nameservers.clone(),
+                });
+            }
+
+            return None;
+        };
+
+        entry.hits = entry.hits.saturating_add(1);
+
+        // Refresh entries in the last `prefetch_threshold` percent of their
+        // TTL that have been asked for often enough to be worth it.
+        let remaining = entry.remaining(now).unwrap_or(0);
+        let prefetch = !entry.prefetching
+            && entry.hits >= self.prefetch_min_hits
+            && remaining as u64 * 100 <= entry.ttl as u64 * self.prefetch_threshold as u64;
+        if prefetch {
+            entry.prefetching = true;
+        }
+
+        Some(CacheHit {
+            packet,
+            prefetch,
+            stale: false,
+            nameservers: entry.nameservers.clone(),
+        })
+    }
+
+    /// Returns an entry, expired or not, that is still within the stale
+    /// window, with the TTL set for stale answers (RFC 8767).
+    pub fn get_stale(&self, question: &DnsQuestion) -> Option<CacheHit> {
+        let now = SystemTime::now();
+        let shard = self.shard(question);
+
+        let entry = match shard.positive.entries.peek(question) {
+            Some(entry) => entry,
+            None => shard.negative.entries.peek(question)?,
+        };
+
+        self.is_stale_usable(entry.expires, now).then(|| CacheHit {
+            packet: entry.to_packet_with_ttl(self.stale_ttl),
+            prefetch: false,
+            stale: true,
+            nameservers: entry.nameservers.clone(),
+        })
+    }
+
+    /// Records that refreshing an entry failed, so that stale data is served
+    /// straight away, without retrying, for the next `stale_ttl` seconds.
+    pub fn mark_failed(&self, question: &DnsQuestion) {
+        let retry = SystemTime::now() + Duration::from_secs(self.stale_ttl as u64);
+        let mut shard = self.shard(question);
+
+        if let Some(entry) = shard.positive.entries.peek_mut(question) {
+            entry.retry_after = Some(retry);
+        } else if let Some(entry) = shard.negative.entries.peek_mut(question) {
+            entry.retry_after = Some(retry);
+        }
+    }
+
+    /// Lets an entry be prefetched again once a prefetch is over without
+    /// having replaced it, after another `prefetch_min_hits` hits so that a
+    /// failing upstream isn't retried on every one.
+    pub fn end_prefetch(&self, question: &DnsQuestion) {
+        let mut shard = self.shard(question);
+
+        let entry = match shard.positive.entries.peek_mut(question) {
+            Some(entry) => entry,
+            None => match shard.negative.entries.peek_mut(question) {
+                Some(entry) => entry,
+                None => return,
+            },
+        };
+        if entry.prefetching {
+            entry.prefetching = false;
+            entry.hits = 0;
+        }
+    }
+
+    /// Caches a response from recursion if it is an answer or a negative
+    /// response carrying an SOA, along with the `nameservers` it came from.
+    /// Anything else, or a TTL of zero, is skipped.
+    pub fn insert(&self, question: DnsQuestion, response: &DnsPacket, nameservers: &[String]) {
+        let rcode = response.header.rescode;
+
+        let answer_ttl = response.answers.iter().map(|r| r.ttl()).min();
+        let soa = response.authorities.iter().find_map(|rec| match rec {
+            DnsRecord::SOA { ttl, minimum, .. } => Some((rec.clone(), *ttl.min(minimum))),
+            _ => None,
+        });
+        // Answers without the type asked for are a CNAME chain leading to a
+        // denial if an SOA comes with them, and otherwise the answer itself,
+        // as for queries of type ANY.
+        let answered = response
+            .answers
+            .iter()
+            .any(|rec| rec.qtype() == question.qtype)
+            || (soa.is_none() && answer_ttl.is_some());
+
+        let (answers, authorities, ttl) = match (rcode, soa) {
+            (ResultCode::NOERROR, _) if answered => (
+                response.answers.clone(),
+                Vec::new(),
+                answer_ttl.unwrap_or(0),
+            ),
+            // A CNAME chain ending in a denial is kept whole, and expires
+            // with the first of its links or the denial.
+            (ResultCode::NOERROR | ResultCode::NXDOMAIN, Some((soa, soa_ttl))) => {
+                let ttl = answer_ttl.map_or(soa_ttl, |ttl| ttl.min(soa_ttl));
+
+                // The SOA's signatures and the denial records go along, for
+                // clients asking for DNSSEC data.
+                let mut authorities = vec![soa];
+                authorities.extend(
+                    response
+                        .authorities
+                        .iter()
+                        .filter(|rec| dnssec::is_dnssec_type(rec.qtype()))
+                        .cloned(),
+                );
+
+                (
+                    response.answers.clone(),
+                    authorities,
+                    ttl.min(self.negative_max_ttl),
+                )
+            }
+            _ => return,
+        };
+
+        if ttl == 0 {
+            return;
+        }
+
+        let expires = SystemTime::now() + Duration::from_secs(ttl as u64);
+        let entry = CacheEntry::new(
+            &question,
+            rcode,
+            answers,
+            authorities,
+            response.header.authed_data,
+            ttl,
+            expires,
+        )
+        .with_nameservers(nameservers.to_vec());
+        self.insert_entry(question, entry);
+    }
+
+    fn insert_entry(&self, question: DnsQuestion, entry: CacheEntry) {
+        let kind = entry.kind();
+        let other = match kind {
+            EntryKind::Positive => EntryKind::Negative,
+            EntryKind::Negative => EntryKind::Positive,
+        };
+
+        let mut shard = self.shard(&question);
+        shard.lru(other).remove(&question);
+        let evicted = shard.lru(kind).insert(question, entry);
+        drop(shard);
+
+        for _ in 0..evicted {
+            METRICS.cache_evictions.inc(&[kind.as_str()]);
+        }
+    }
+
+    /// Removes every entry, returning how many there were.
+    pub fn flush(&self) -> usize {
+        let mut flushed = 0;
+        for shard in &self.shards {
+            let shard = &mut *shard.lock().unwrap();
+            for lru in [&mut shard.positive, &mut shard.negative] {
+                flushed += lru.entries.len();
+                lru.entries.clear();
+                lru.bytes = 0;
+            }
+        }
+
+        flushed
+    }
+
+    /// Removes the entries for `name`, of any type, and with `subtree` also
+    /// those for names below it. Returns how many were removed.
+    pub fn flush_name(&self, name: &str, subtree: bool) -> usize {
+        let matches = |question: &DnsQuestion| {
+            question.name == name || (subtree && is_subdomain(&question.name, name))
+        };
+
+        let mut flushed = 0;
+        for shard in &self.shards {
+            let shard = &mut *shard.lock().unwrap();
+            for lru in [&mut shard.positive, &mut shard.negative] {
+                let doomed: Vec<DnsQuestion> = lru
+                    .entries
+                    .iter()
+                    .map(|(question, _)| question)
+                    .filter(|question| matches(question))
+                    .cloned()
+                    .collect();
+                for question in doomed {
+                    lru.remove(&question);
+                    flushed += 1;
+                }
+            }
+        }
+
+        flushed
+    }
+
+    /// Lists the entries in master file format, sorted by name, each headed
+    /// by a comment with its type, remaining TTL and hit count.
+    pub fn dump(&self) -> String {
+        let now = SystemTime::now();
+
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+            let all = shard
+                .positive
+                .entries
+                .iter()
+                .chain(shard.negative.entries.iter());
+            entries.extend(all.map(|(question, entry)| {
+                let mut out = String::new();
+                let state = match entry.remaining(now) {
+                    Some(ttl) => format!("ttl={ttl}"),
+                    None => "stale".to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    "; {} {} {} {:?} {state} hits={}",
+                    question.name,
+                    qtype_label(question.qtype),
+                    entry.kind().as_str(),
+                    entry.rcode,
+                    entry.hits
+                );
+                let ttl = entry.remaining(now).unwrap_or(0);
+                for rec in entry.answers.iter().chain(&entry.authorities) {
+                    let _ = writeln!(out, "{}", rec.with_ttl(ttl));
+                }
+                (question.name.clone(), question.qtype.to_num(), out)
+            }));
+        }
+
+        entries.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+        entries.into_iter().map(|(_, _, out)| out).collect()
+    }
+
+    /// Writes the unexpired entries to `path`, returning how many there were.
+    /// The file is replaced atomically, so a crash mid-write leaves the
+    /// previous dump intact.
+    pub fn save(&self, path: &Path) -> Result<usize, String> {
+        let now = SystemTime::now();
+
+        let mut saved = Vec::new();
+        for shard in &self.shards {
+            let shard = shard.lock().unwrap();
+
+            // Least recently used first, so that loading restores the order.
+            let entries = shard.positive.entries.iter().rev();
+            let entries = entries.chain(shard.negative.entries.iter().rev());
+
+            saved.extend(entries.filter(|(_, entry)| entry.expires > now).map(
+                |(question, entry)| SavedEntry {
+                    name: question.name.clone(),
+                    qtype: question.qtype,
+                    expires: unix_secs(entry.expires),
+                    rcode: entry.rcode,
+                    records: entry.answers.clone(),
+                    authorities: entry.authorities.clone(),
+                    secure: entry.secure,
+                    nameservers: entry.nameservers.clone(),
+                },
+            ));
+        }
+
+        let data = serde_json::to_vec(&saved).map_err(|e| e.to_string())?;
+
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data).map_err(|e| format!("{}: {e}", tmp.display()))?;
+        fs::rename(&tmp, path).map_err(|e| format!("{}: {e}", path.display()))?;
+
+        Ok(saved.len())
+    }
+
+    /// Loads entries saved by [`Cache::save`], skipping those that expired
+    /// since. A missing file is not an error. Returns how many were loaded.
+    pub fn load(&self, path: &Path) -> Result<usize, String> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(format!("{}: {e}", path.display())),
+        };
+
+        let saved: Vec<SavedEntry> =
+            serde_json::from_slice(&data).map_err(|e| format!("{}: {e}", path.display()))?;
+
+        let now = SystemTime::now();
+        let mut loaded = 0;
+        for entry in saved {
+            let expires = UNIX_EPOCH + Duration::from_secs(entry.expires);
+            if expires <= now && !self.is_stale_usable(expires, now) {
+                continue;
+            }
+
+            let question = DnsQuestion::new(entry.name, entry.qtype);
+            // The original TTL isn't saved; what's left of it will do, and
+            // entries that are already stale get the stale TTL.
+            let ttl = match expires.duration_since(now) {
+                Ok(left) => left.as_secs() as u32,
+                Err(_) => self.stale_ttl,
+            };
+            let entry = CacheEntry::new(
+                &question,
+                entry.rcode,
+                entry.records,
+                entry.authorities,
+                entry.secure,
+                ttl,
+                expires,
+            )
+            .with_nameservers(entry.nameservers);
+            self.insert_entry(question, entry);
+            loaded += 1;
+        }
+
+        Ok(loaded)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Saves the cache to `path`, logging the outcome.
+pub fn save_to(path: &Path) {
+    match DNS_CACHE.get().unwrap().save(path) {
+        Ok(count) => info!("saved {count} cache entries to {}", path.display()),
+        Err(e) => error!("failed to save cache: {e}"),
+    }
+}
+
+/// Saves the cache to `path` whenever the process receives SIGUSR1.
+pub fn spawn_saver(path: String) -> Result<(), String> {
+    let mut signals = signal(SignalKind::user_defined1()).map_err(|e| e.to_string())?;
+
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            // Writing out a large cache takes a while, which must not hold up
+            // the worker threads answering queries.
+            let path = path.clone();
+            if let Err(e) = task::spawn_blocking(move || save_to(Path::new(&path))).await {
+                error!("failed to save cache: {e}");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::util::TempFile;
+
+    fn question(name: &str) -> DnsQuestion {
+        DnsQuestion::new(name.to_string(), QueryType::A)
+    }
+
+    fn response(rcode: ResultCode, answers: Vec<DnsRecord>, soa_ttl: Option<u32>) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.header.rescode = rcode;
+        packet.answers = answers;
+        if let Some(ttl) = soa_ttl {
+            packet.authorities.push(DnsRecord::SOA {
+                domain: "example.net".to_string(),
+                mname: "ns.example.net".to_string(),
+                rname: "hostmaster.example.net".to_string(),
+                serial: 1,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 300,
+                ttl,
+            });
+        }
+
+        packet
+    }
+
+    fn cname(name: &str, target: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: name.to_string(),
+            host: target.to_string(),
+            ttl,
+        }
+    }
+
+    fn a(name: &str, ttl: u32) -> DnsRecord {
+        DnsRecord::A {
+            domain: name.to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl,
+        }
+    }
+
+    /// The kind and TTL of the entry cached for `question`.
+    fn entry(cache: &Cache, question: &DnsQuestion) -> Option<(EntryKind, u32)> {
+        let shard = cache.shard(question);
+        let entry = match shard.positive.entries.peek(question) {
+            Some(entry) => entry,
+            None => shard.negative.entries.peek(question)?,
+        };
+
+        Some((entry.kind(), entry.ttl))
+    }
+
+    #[test]
+    fn keeps_cname_chains_leading_to_denials() {
+        let cache = Cache::new(&CacheConfig::default());
+
+        let nxdomain = question("www.example.com");
+        let chain = vec![cname("www.example.com", "gone.example.net", 60)];
+        cache.insert(
+            nxdomain.clone(),
+            &response(ResultCode::NXDOMAIN, chain, Some(600)),
+            &[],
+        );
+        assert_eq!(entry(&cache, &nxdomain), Some((EntryKind::Negative, 60)));
+
+        let packet = cache.get(&nxdomain).unwrap().packet;
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(packet.answers.len(), 1);
+        assert!(matches!(packet.answers[0], DnsRecord::CNAME { .. }));
+        assert!(matches!(packet.authorities[0], DnsRecord::SOA { .. }));
+
+        // NODATA at the end of the chain, expiring with the SOA minimum.
+        let nodata = question("mail.example.com");
+        let chain = vec![cname("mail.example.com", "mx.example.net", 3600)];
+        cache.insert(
+            nodata.clone(),
+            &response(ResultCode::NOERROR, chain, Some(600)),
+            &[],
+        );
+        assert_eq!(entry(&cache, &nodata), Some((EntryKind::Negative, 300)));
+    }
+
+    #[test]
+    fn caches_answers_behind_cname_chains_as_positive() {
+        let cache = Cache::new(&CacheConfig::default());
+
+        let question = question("www.example.com");
+        let answers = vec![
+            cname("www.example.com", "web.example.net", 600),
+            a("web.example.net", 120),
+        ];
+        cache.insert(
+            question.clone(),
+            &response(ResultCode::NOERROR, answers, Some(600)),
+            &[],
+        );
+        assert_eq!(entry(&cache, &question), Some((EntryKind::Positive, 120)));
+        assert!(cache.get(&question).unwrap().packet.authorities.is_empty());
+    }
+
+    fn saved(name: &str, expires: SystemTime) -> SavedEntry {
+        SavedEntry {
+            name: name.to_string(),
+            qtype: QueryType::A,
+            expires: unix_secs(expires),
+            rcode: ResultCode::NOERROR,
+            records: vec![a(name, 300)],
+            authorities: Vec::new(),
+            secure: false,
+            nameservers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn save_and_load_keep_absolute_expiry_times() {
+        let cache = Cache::new(&CacheConfig::default());
+        let question = question("www.example.com");
+        let answers = vec![a("www.example.com", 300)];
+        let nameservers = ["ns1.example.com".to_string()];
+        cache.insert(
+            question.clone(),
+            &response(ResultCode::NOERROR, answers, None),
+            &nameservers,
+        );
+        let expires = cache
+            .shard(&question)
+            .positive
+            .entries
+            .peek(&question)
+            .unwrap()
+            .expires;
+
+        let file = TempFile::new("");
+        assert_eq!(cache.save(file.path()), Ok(1));
+
+        let restored = Cache::new(&CacheConfig::default());
+        assert_eq!(restored.load(file.path()), Ok(1));
+
+        let shard = restored.shard(&question);
+        let entry = shard.positive.entries.peek(&question).unwrap();
+        assert_eq!(unix_secs(entry.expires), unix_secs(expires));
+        assert_eq!(entry.nameservers, nameservers);
+    }
+
+    #[test]
+    fn load_skips_entries_that_expired_while_down() {
+        let now = SystemTime::now();
+        let entries = [
+            saved("fresh.example.com", now + Duration::from_secs(600)),
+            saved("expired.example.com", now - Duration::from_secs(600)),
+            saved("ancient.example.com", now - Duration::from_secs(2 * 86400)),
+        ];
+        let file = TempFile::new(&serde_json::to_string(&entries).unwrap());
+
+        let cache = Cache::new(&CacheConfig::default());
+        assert_eq!(cache.load(file.path()), Ok(1));
+        assert!(cache.get(&question("fresh.example.com")).is_some());
+        assert!(cache.get_stale(&question("expired.example.com")).is_none());
+
+        // Within the stale window they come back as stale data, to be served
+        // only if refreshing them fails.
+        let config = CacheConfig {
+            stale_window: 86400,
+            ..Default::default()
+        };
+        let cache = Cache::new(&config);
+        assert_eq!(cache.load(file.path()), Ok(2));
+
+        let expired = question("expired.example.com");
+        assert!(cache.get(&expired).is_none());
+        let hit = cache.get_stale(&expired).unwrap();
+        assert_eq!(
+            hit.packet.answers[0].to_string(),
+            "expired.example.com. 30 IN A 192.0.2.1"
+        );
+        assert!(cache.get_stale(&question("ancient.example.com")).is_none());
+    }
+
+    fn lru_entry(name: &str) -> (DnsQuestion, CacheEntry) {
+        let question = question(name);
+        let expires = SystemTime::now() + Duration::from_secs(300);
+        let answers = vec![a(name, 300)];
+        let entry = CacheEntry::new(
+            &question,
+            ResultCode::NOERROR,
+            answers,
+            Vec::new(),
+            false,
+            300,
+            expires,
+        );
+
+        (question, entry)
+    }
+
+    #[test]
+    fn evicts_least_recently_used_entries_past_the_limits() {
+        let mut lru = Lru::new(2, usize::MAX);
+        for name in ["a.example.com", "b.example.com"] {
+            let (key, entry) = lru_entry(name);
+            assert_eq!(lru.insert(key, entry), 0);
+        }
+
+        // Using a makes b the least recently used.
+        lru.entries.get(&question("a.example.com"));
+        let (key, entry) = lru_entry("c.example.com");
+        assert_eq!(lru.insert(key, entry), 1);
+        assert!(lru.entries.contains(&question("a.example.com")));
+        assert!(!lru.entries.contains(&question("b.example.com")));
+
+        // The byte limit applies as well, and the bytes are accounted for.
+        let (key, entry) = lru_entry("d.example.com");
+        let size = entry.size;
+        let mut lru = Lru::new(100, size * 2);
+        lru.insert(key, entry);
+        for name in ["e.example.com", "f.example.com"] {
+            let (key, entry) = lru_entry(name);
+            lru.insert(key, entry);
+        }
+        assert_eq!(lru.entries.len(), 2);
+        assert_eq!(lru.bytes, size * 2);
+        assert!(!lru.entries.contains(&question("d.example.com")));
+    }
+
+    #[test]
+    fn negative_entries_do_not_evict_answers() {
+        let config = CacheConfig {
+            negative_max_entries: SHARDS,
+            ..Default::default()
+        };
+        let cache = Cache::new(&config);
+
+        let answered = question("www.example.com");
+        let answers = vec![a("www.example.com", 300)];
+        cache.insert(
+            answered.clone(),
+            &response(ResultCode::NOERROR, answers, None),
+            &[],
+        );
+        for n in 0..100 {
+            let denied = question(&format!("{n}.example.com"));
+            cache.insert(
+                denied,
+                &response(ResultCode::NXDOMAIN, Vec::new(), Some(600)),
+                &[],
+            );
+        }
+
+        let stats = cache.stats();
+        assert_eq!(stats.positive, 1);
+        assert!(stats.negative <= SHARDS, "{}", stats.negative);
+        assert!(cache.get(&answered).is_some());
+    }
+
+    #[test]
+    fn clamps_negative_ttls() {
+        let config = CacheConfig {
+            negative_max_ttl: 200,
+            ..Default::default()
+        };
+        let cache = Cache::new(&config);
+
+        // The SOA TTL, capped by its minimum field (RFC 2308 section 5) and
+        // then by the configured maximum.
+        for (soa_ttl, expected) in [(100, 100), (250, 200), (86400, 200)] {
+            let denied = question(&format!("{soa_ttl}.example.com"));
+            cache.insert(
+                denied.clone(),
+                &response(ResultCode::NXDOMAIN, Vec::new(), Some(soa_ttl)),
+                &[],
+            );
+            assert_eq!(
+                entry(&cache, &denied),
+                Some((EntryKind::Negative, expected))
+            );
+        }
+
+        let cache = Cache::new(&CacheConfig::default());
+        let nodata = question("www.example.com");
+        cache.insert(
+            nodata.clone(),
+            &response(ResultCode::NOERROR, Vec::new(), Some(86400)),
+            &[],
+        );
+        assert_eq!(entry(&cache, &nodata), Some((EntryKind::Negative, 300)));
+
+        // Without an SOA there is no TTL to go by, so nothing is cached.
+        let bare = question("nosoa.example.com");
+        cache.insert(
+            bare.clone(),
+            &response(ResultCode::NXDOMAIN, Vec::new(), None),
+            &[],
+        );
+        assert_eq!(entry(&cache, &bare), None);
+    }
+
+    /// Caches an answer with a TTL of 100 seconds, `left` of which remain.
+    fn insert_aged(cache: &Cache, name: &str, left: u64) -> DnsQuestion {
+        let question = question(name);
+        let expires = SystemTime::now() + Duration::from_secs(left);
+        let entry = CacheEntry::new(
+            &question,
+            ResultCode::NOERROR,
+            vec![a(name, 100)],
+            Vec::new(),
+            false,
+            100,
+            expires,
+        );
+        cache.insert_entry(question.clone(), entry);
+
+        question
+    }
+
+    fn prefetches(cache: &Cache, question: &DnsQuestion, lookups: usize) -> Vec<bool> {
+        (0..lookups)
+            .map(|_| cache.get(question).unwrap().prefetch)
+            .collect()
+    }
+
+    #[test]
+    fn prefetches_popular_entries_near_expiry_once() {
+        let cache = Cache::new(&CacheConfig::default());
+
+        // Within the last 10% of the TTL, from the third hit on.
+        let expiring = insert_aged(&cache, "expiring.example.com", 9);
+        assert_eq!(
+            prefetches(&cache, &expiring, 5),
+            [false, false, true, false, false]
+        );
+
+        let fresh = insert_aged(&cache, "fresh.example.com", 50);
+        assert_eq!(prefetches(&cache, &fresh, 5), [false; 5]);
+    }
+
+    #[test]
+    fn retries_prefetches_after_failures() {
+        let cache = Cache::new(&CacheConfig::default());
+        let question = insert_aged(&cache, "www.example.com", 9);
+        assert_eq!(prefetches(&cache, &question, 3), [false, false, true]);
+
+        // The prefetch failed, leaving the entry in place: it is tried again,
+        // but only after another round of hits.
+        cache.end_prefetch(&question);
+        assert_eq!(
+            prefetches(&cache, &question, 4),
+            [false, false, true, false]
+        );
+
+        // A prefetch that replaced the entry starts it afresh.
+        let question = insert_aged(&cache, "www.example.com", 9);
+        cache.end_prefetch(&question);
+        assert_eq!(prefetches(&cache, &question, 3), [false, false, true]);
+    }
+}
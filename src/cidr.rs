@@ -0,0 +1,142 @@
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Cidr, String> {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > max {
+            return Err(format!("prefix length {prefix} too long for {addr}"));
+        }
+
+        Ok(Cidr {
+            addr: mask(addr, prefix),
+            prefix,
+        })
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4-mapped IPv6 clients match IPv4 networks.
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            addr => addr,
+        };
+
+        addr.is_ipv4() == self.addr.is_ipv4() && mask(addr, self.prefix) == self.addr
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parses `addr/prefix`, or a bare address as a host network.
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (
+                addr,
+                Some(prefix.parse::<u8>().map_err(|e| format!("{s}: {e}"))?),
+            ),
+            None => (s, None),
+        };
+
+        let addr = addr.parse::<IpAddr>().map_err(|e| format!("{s}: {e}"))?;
+        let prefix = prefix.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// Clears all bits of `addr` past the first `prefix` ones.
+pub fn mask(addr: IpAddr, prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4);
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            IpAddr::V4((bits & mask).into())
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6);
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            IpAddr::V6((bits & mask).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks_and_clears_host_bits() {
+        assert_eq!(cidr("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("192.0.2.7").to_string(), "192.0.2.7/32");
+        assert_eq!(cidr("2001:db8::1/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+        assert_eq!(cidr("172.31.255.255/12").prefix(), 12);
+
+        for bad in [
+            "10.0.0.0/33",
+            "::/129",
+            "10.0.0/8",
+            "10.0.0.0/x",
+            "example.com",
+        ] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn matches_addresses_on_prefix_boundaries() {
+        let net = cidr("172.16.0.0/12");
+        assert!(net.contains(addr("172.16.0.0")));
+        assert!(net.contains(addr("172.31.255.255")));
+        assert!(!net.contains(addr("172.32.0.0")));
+        assert!(!net.contains(addr("172.15.255.255")));
+
+        let net = cidr("2001:db8:8000::/33");
+        assert!(net.contains(addr("2001:db8:ffff::1")));
+        assert!(!net.contains(addr("2001:db8:7fff::1")));
+
+        assert!(cidr("0.0.0.0/0").contains(addr("203.0.113.9")));
+        assert!(cidr("::/0").contains(addr("2001:db8::1")));
+        assert!(cidr("192.0.2.1/32").contains(addr("192.0.2.1")));
+        assert!(!cidr("192.0.2.1/32").contains(addr("192.0.2.2")));
+    }
+
+    #[test]
+    fn keeps_address_families_apart() {
+        assert!(!cidr("0.0.0.0/0").contains(addr("::1")));
+        assert!(!cidr("::/0").contains(addr("127.0.0.1")));
+
+        // IPv4-mapped addresses match as IPv4.
+        assert!(cidr("192.0.2.0/24").contains(addr("::ffff:192.0.2.1")));
+        assert!(!cidr("::ffff:0:0/96").contains(addr("::ffff:192.0.2.1")));
+    }
+}
@@ -0,0 +1,493 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+};
+
+use serde::Deserialize;
+
+use crate::acl::AclAction;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub zones: Vec<ZoneConfig>,
+    pub hosts: HostsConfig,
+    pub blocklist: BlocklistConfig,
+    /// Response policy zones, in order of precedence.
+    pub rpz: Vec<ZoneConfig>,
+    pub acl: AclConfig,
+    pub rrl: RrlConfig,
+    pub ratelimit: RateLimitConfig,
+    pub metrics: MetricsConfig,
+    pub log: LogConfig,
+    pub dnstap: DnstapConfig,
+    pub cache: CacheConfig,
+    pub control: ControlConfig,
+    pub dnssec: DnssecConfig,
+    pub tls: TlsConfig,
+    pub dot: DotConfig,
+    pub doh: DohConfig,
+    pub doq: DoqConfig,
+    pub forward: ForwardConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Seconds to let in-flight queries finish after SIGINT or SIGTERM.
+    pub shutdown_timeout: u64,
+    /// Most plain TCP connections open at once; more are closed as they
+    /// come in.
+    pub max_tcp_connections: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            shutdown_timeout: 5,
+            max_tcp_connections: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZoneConfig {
+    /// Origin of the zone, used until the master file sets its own `$ORIGIN`.
+    pub origin: String,
+    /// Path to the RFC 1035 master file holding the zone data.
+    pub file: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HostsConfig {
+    /// Files in `/etc/hosts` format.
+    pub files: Vec<String>,
+    /// Static records, mapping a name to its addresses.
+    pub records: HashMap<String, Vec<String>>,
+    pub ttl: u32,
+    /// Seconds between checks of the files for changes, 0 disables reloading.
+    pub reload_interval: u64,
+}
+
+impl Default for HostsConfig {
+    fn default() -> Self {
+        Self {
+            files: Vec::new(),
+            records: HashMap::new(),
+            ttl: 300,
+            reload_interval: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BlocklistConfig {
+    /// Hosts-format, plain domain-list or Adblock-style (`||domain^`) files.
+    pub files: Vec<String>,
+    /// Domains that are never blocked, along with their subdomains.
+    pub allow: Vec<String>,
+    pub response: BlockResponse,
+    pub sinkhole_ipv4: Option<Ipv4Addr>,
+    pub sinkhole_ipv6: Option<Ipv6Addr>,
+    pub ttl: u32,
+    /// Seconds between checks of the files for changes, 0 disables reloading.
+    pub reload_interval: u64,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        Self {
+            files: Vec::new(),
+            allow: Vec::new(),
+            response: BlockResponse::Nxdomain,
+            sinkhole_ipv4: None,
+            sinkhole_ipv6: None,
+            ttl: 60,
+            reload_interval: 3600,
+        }
+    }
+}
+
+/// How a blocked query is answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockResponse {
+    Nxdomain,
+    Refused,
+    /// `0.0.0.0` for A and `::` for AAAA queries.
+    Null,
+    /// The configured sinkhole addresses.
+    Sinkhole,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AclConfig {
+    /// Applies to clients matching none of the rules.
+    pub default: AclAction,
+    pub rules: Vec<AclRule>,
+}
+
+impl Default for AclConfig {
+    /// Recursion for loopback and private networks only, so that an
+    /// unconfigured server isn't an open resolver.
+    fn default() -> Self {
+        Self {
+            default: AclAction::Refuse,
+            rules: vec![AclRule {
+                networks: [
+                    "127.0.0.0/8",
+                    "10.0.0.0/8",
+                    "172.16.0.0/12",
+                    "192.168.0.0/16",
+                    "::1",
+                    "fc00::/7",
+                ]
+                .map(String::from)
+                .to_vec(),
+                action: AclAction::Allow,
+            }],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AclRule {
+    /// Networks in CIDR notation, or bare addresses.
+    pub networks: Vec<String>,
+    pub action: AclAction,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RrlConfig {
+    /// Identical responses a client network may get per second, 0 disables
+    /// rate limiting.
+    pub responses_per_second: u32,
+    /// Every `slip`th limited response is sent truncated instead of dropped,
+    /// 0 never slips.
+    pub slip: u32,
+    /// Seconds of responses a flood can run into debt.
+    pub window: u64,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Only log what would be limited, for tuning the rate.
+    pub log_only: bool,
+}
+
+impl Default for RrlConfig {
+    fn default() -> Self {
+        Self {
+            responses_per_second: 0,
+            slip: 2,
+            window: 15,
+            ipv4_prefix: 24,
+            ipv6_prefix: 56,
+            log_only: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Queries a client may send per second, 0 disables the limit.
+    pub queries_per_second: u32,
+    /// How many queries a client may send at once before being limited.
+    pub burst: u32,
+    /// Clients are grouped by these prefix lengths to share a bucket.
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    /// Networks that are never limited.
+    pub exempt: Vec<String>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            queries_per_second: 0,
+            burst: 100,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// File the cache is saved to on shutdown and SIGUSR1, and loaded from at
+    /// startup.
+    pub file: Option<String>,
+    /// Limits on answers; the least recently used are evicted past either.
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    /// Limits on NXDOMAIN and NODATA responses, kept apart so that a flood of
+    /// random names can't push out real answers.
+    pub negative_max_entries: usize,
+    pub negative_max_bytes: usize,
+    /// Upper bound on how long negative responses are cached, in seconds.
+    pub negative_max_ttl: u32,
+    /// Entries hit in the last this many percent of their TTL are refreshed
+    /// in the background, 0 disables prefetching.
+    pub prefetch_threshold: u8,
+    /// Hits an entry needs before it is worth prefetching.
+    pub prefetch_min_hits: u32,
+    /// Seconds past expiry that entries are kept around to be served stale
+    /// (RFC 8767) when they can't be refreshed, 0 disables serve-stale.
+    pub stale_window: u64,
+    /// TTL of stale answers, and how long to wait before retrying a failed
+    /// refresh.
+    pub stale_ttl: u32,
+    /// Milliseconds a lookup may take before stale data is served instead.
+    /// The lookup carries on in the background.
+    pub stale_timeout_ms: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            max_entries: 100_000,
+            max_bytes: 64 * 1024 * 1024,
+            negative_max_entries: 20_000,
+            negative_max_bytes: 8 * 1024 * 1024,
+            negative_max_ttl: 3600,
+            prefetch_threshold: 10,
+            prefetch_min_hits: 3,
+            stale_window: 0,
+            stale_ttl: 30,
+            stale_timeout_ms: 1800,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DnssecConfig {
+    /// Whether to validate the answers of recursive lookups.
+    pub validate: bool,
+    /// DS records of the keys to trust, in master file format, e.g.
+    /// `. IN DS 20326 8 2 E06D...`. The root zone's keys are used if empty.
+    pub trust_anchors: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Address of the HTTP listener serving `/metrics`, disabled if unset.
+    pub listen: Option<SocketAddr>,
+    /// Most connections open at once; more are closed as they come in.
+    pub max_connections: usize,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            max_connections: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf first.
+    pub cert: Option<String>,
+    /// PEM file with the certificate's private key.
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DotConfig {
+    /// Address of the DNS-over-TLS listener, disabled if unset. Needs the
+    /// certificate and key in `[tls]`.
+    pub listen: Option<SocketAddr>,
+    /// Seconds a connection may sit without sending a query.
+    pub idle_timeout: u64,
+    /// Most connections open at once; more are closed as they come in.
+    pub max_connections: usize,
+}
+
+impl Default for DotConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            idle_timeout: 30,
+            max_connections: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DohConfig {
+    /// Address of the DNS-over-HTTPS listener, disabled if unset. Needs the
+    /// certificate and key in `[tls]`.
+    pub listen: Option<SocketAddr>,
+    /// Seconds a connection may sit without a request in flight.
+    pub idle_timeout: u64,
+    /// Most connections open at once; more are closed as they come in.
+    pub max_connections: usize,
+}
+
+impl Default for DohConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            idle_timeout: 30,
+            max_connections: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DoqConfig {
+    /// UDP address of the DNS-over-QUIC listener, disabled if unset. Needs
+    /// the certificate and key in `[tls]`.
+    pub listen: Option<SocketAddr>,
+    /// Seconds a connection may sit without traffic before it is closed.
+    pub idle_timeout: u64,
+    /// Most connections open at once; more are refused as they come in.
+    pub max_connections: usize,
+    /// Most queries a connection may have in flight at once.
+    pub max_streams: u32,
+}
+
+impl Default for DoqConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            idle_timeout: 30,
+            max_connections: 1000,
+            max_streams: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ForwardConfig {
+    /// Encrypted upstreams to forward queries to instead of recursing, tried
+    /// in order.
+    pub upstreams: Vec<UpstreamConfig>,
+    /// PEM bundle of the CAs upstream certificates are checked against.
+    pub ca_file: String,
+}
+
+impl Default for ForwardConfig {
+    fn default() -> Self {
+        Self {
+            upstreams: Vec::new(),
+            ca_file: "/etc/ssl/certs/ca-certificates.crt".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamConfig {
+    pub protocol: UpstreamProtocol,
+    pub address: SocketAddr,
+    /// Name the upstream's certificate must be valid for, also sent as the
+    /// HTTP authority for DNS over HTTPS.
+    pub name: String,
+    /// Path of the DNS over HTTPS endpoint.
+    #[serde(default = "default_doh_path")]
+    pub path: String,
+    /// Base64 SHA-256 digests of SubjectPublicKeyInfo, one of which the
+    /// upstream's certificate must have if any are given (RFC 7469).
+    #[serde(default)]
+    pub spki_pins: Vec<String>,
+}
+
+fn default_doh_path() -> String {
+    "/dns-query".to_string()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    Tls,
+    Https,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// Unix socket taking admin commands from `dnsctl`, disabled if unset.
+    pub socket: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Filter directives, e.g. `info` or `warn,dns_server::rrl=debug`.
+    /// `RUST_LOG` takes precedence when set.
+    pub level: String,
+    pub format: LogFormat,
+    /// Whether to log a line for every query answered.
+    pub queries: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            queries: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DnstapConfig {
+    /// Unix socket of a Frame Streams consumer, e.g. `fstrm_capture`.
+    pub socket: Option<String>,
+    /// File to write the frames to instead of a socket.
+    pub file: Option<String>,
+    pub identity: Option<String>,
+    /// Frames waiting for the writer; more than this are dropped.
+    pub queue_size: usize,
+    /// Whether to log queries from and responses to clients.
+    pub client: bool,
+    /// Whether to log queries to and responses from upstream servers.
+    pub resolver: bool,
+}
+
+impl Default for DnstapConfig {
+    fn default() -> Self {
+        Self {
+            socket: None,
+            file: None,
+            identity: None,
+            queue_size: 10000,
+            client: true,
+            resolver: true,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let raw = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+
+        toml::from_str(&raw).map_err(|e| format!("{}: {e}", path.display()))
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    fmt::Write,
+    fs,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    task,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    acl::Acl, blocklist::Blocklist, config::Config, hosts::Hosts, logging, master::normalize_name,
+    rpz::Rpz, zone::Authority, ACL, AUTHORITY, BLOCKLIST, CONFIG, DNS_CACHE, FORWARDER, HOSTS,
+    METRICS, RPZ,
+};
+
+// Longest command line accepted, to bound what a client can make us buffer.
+const MAX_COMMAND_LEN: u64 = 4096;
+
+/// Binds the control socket at `path`, replacing a stale one left behind by
+/// a previous run. Only the owner may connect.
+pub fn bind(path: &str) -> Result<UnixListener, String> {
+    match fs::remove_file(path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("{path}: {e}")),
+    }
+
+    // The socket is created according to the umask, so it is bound inside a
+    // directory only we can enter and moved into place once restricted.
+    let private = PathBuf::from(format!("{path}.{}", process::id()));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .map_err(|e| format!("{}: {e}", private.display()))?;
+
+    let listener = bind_private(&private.join("control.sock"), Path::new(path));
+    let _ = fs::remove_dir_all(&private);
+
+    listener
+}
+
+fn bind_private(private: &Path, path: &Path) -> Result<UnixListener, String> {
+    let listener =
+        UnixListener::bind(private).map_err(|e| format!("{}: {e}", private.display()))?;
+    fs::set_permissions(private, fs::Permissions::from_mode(0o600))
+        .map_err(|e| format!("{}: {e}", private.display()))?;
+    fs::rename(private, path).map_err(|e| format!("{}: {e}", path.display()))?;
+
+    Ok(listener)
+}
+
+/// Takes one command per connection, as a single line, and answers with
+/// `OK` followed by the output, or `ERR` and the reason. `config_path` is
+/// the file `reload` reads the config from again.
+pub async fn serve(listener: UnixListener, config_path: Option<PathBuf>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("failed to accept control connection: {e}");
+                continue;
+            }
+        };
+
+        let config_path = config_path.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, config_path).await {
+                debug!("control connection failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: UnixStream,
+    config_path: Option<PathBuf>,
+) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+
+    let mut line = String::new();
+    BufReader::new(read.take(MAX_COMMAND_LEN))
+        .read_line(&mut line)
+        .await?;
+
+    // Reloads parse whole files, which mustn't hold up a runtime worker that
+    // is answering queries.
+    let response = task::spawn_blocking(move || {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match run(&args, config_path.as_deref()) {
+            Ok(output) => {
+                info!("control command: {}", args.join(" "));
+                format!("OK\n{output}")
+            }
+            Err(e) => {
+                warn!("control command {:?} failed: {e}", args.join(" "));
+                format!("ERR {e}\n")
+            }
+        }
+    })
+    .await?;
+
+    write.write_all(response.as_bytes()).await?;
+    write.shutdown().await
+}
+
+fn run(args: &[&str], config_path: Option<&Path>) -> Result<String, String> {
+    let cache = DNS_CACHE.get().unwrap();
+
+    match args {
+        ["flush"] => Ok(format!("flushed {} entries\n", cache.flush())),
+        ["flush", name] => {
+            let flushed = cache.flush_name(&normalize_name(name), false);
+            Ok(format!("flushed {flushed} entries\n"))
+        }
+        ["flush-tree", name] => {
+            let flushed = cache.flush_name(&normalize_name(name), true);
+            Ok(format!("flushed {flushed} entries\n"))
+        }
+        ["dump"] => Ok(cache.dump()),
+        ["stats"] => Ok(stats()),
+        ["reload"] => reload_config(config_path),
+        ["reload", what] => reload(what),
+        ["querylog"] => Ok(querylog_status()),
+        ["querylog", "on"] => {
+            logging::set_query_log(true);
+            Ok(querylog_status())
+        }
+        ["querylog", "off"] => {
+            logging::set_query_log(false);
+            Ok(querylog_status())
+        }
+        [] => Err("empty command".to_string()),
+        _ => Err(format!("unknown command: {}", args.join(" "))),
+    }
+}
+
+fn stats() -> String {
+    let cache = DNS_CACHE.get().unwrap().stats();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "queries: {}", METRICS.queries.total());
+    let _ = writeln!(out, "cache.hits: {}", METRICS.cache_hits.total());
+    let _ = writeln!(out, "cache.misses: {}", METRICS.cache_misses.total());
+    let _ = writeln!(out, "cache.entries.positive: {}", cache.positive);
+    let _ = writeln!(out, "cache.entries.negative: {}", cache.negative);
+    let _ = writeln!(out, "cache.bytes: {}", cache.bytes);
+    let _ = writeln!(out, "cache.evictions: {}", METRICS.cache_evictions.total());
+    let _ = writeln!(
+        out,
+        "cache.prefetches: {}",
+        METRICS.cache_prefetches.total()
+    );
+    let _ = writeln!(out, "cache.stale: {}", METRICS.cache_stale.total());
+    let _ = writeln!(
+        out,
+        "upstream.queries: {}",
+        METRICS.upstream_queries.total()
+    );
+    let _ = writeln!(
+        out,
+        "upstream.timeouts: {}",
+        METRICS.upstream_timeouts.total()
+    );
+    let _ = writeln!(out, "blocked: {}", METRICS.blocked.total());
+    let _ = writeln!(out, "rrl.limited: {}", METRICS.rrl_limited.total());
+
+    out
+}
+
+fn querylog_status() -> String {
+    let state = if logging::query_log_enabled() {
+        "on"
+    } else {
+        "off"
+    };
+    format!("query logging is {state}\n")
+}
+
+/// Reloads one kind of data from the files named in the running config.
+fn reload(what: &str) -> Result<String, String> {
+    let config = CONFIG.get().unwrap().read().unwrap().clone();
+
+    match what {
+        "zones" => {
+            let authority = Authority::load(&config.zones)?;
+            *AUTHORITY.get().unwrap().write().unwrap() = authority;
+            Ok(format!("reloaded {} zone(s)\n", config.zones.len()))
+        }
+        "hosts" => {
+            let hosts = Hosts::load(&config.hosts)?;
+            *HOSTS.get().unwrap().write().unwrap() = hosts;
+            Ok("reloaded hosts\n".to_string())
+        }
+        "blocklists" => {
+            let blocklist = Blocklist::load(&config.blocklist)?;
+            let len = blocklist.len();
+            *BLOCKLIST.get().unwrap().write().unwrap() = blocklist;
+            Ok(format!("reloaded blocklists: {len} domains\n"))
+        }
+        "rpz" => {
+            let rpz = Rpz::load(&config.rpz, FORWARDER.get().is_some())?;
+            *RPZ.get().unwrap().write().unwrap() = rpz;
+            Ok(format!(
+                "reloaded {} response policy zone(s)\n",
+                config.rpz.len()
+            ))
+        }
+        _ => Err(format!("can't reload {what}")),
+    }
+}
+
+/// Reads the config file again and swaps in everything that can change
+/// without a restart. Nothing is replaced unless all of it loads.
+fn reload_config(config_path: Option<&Path>) -> Result<String, String> {
+    let path = config_path.ok_or("the server was started without a config file")?;
+    let config = Config::load(path)?;
+
+    let authority = Authority::load(&config.zones)?;
+    let hosts = Hosts::load(&config.hosts)?;
+    let blocklist = Blocklist::load(&config.blocklist)?;
+    let rpz = Rpz::load(&config.rpz, FORWARDER.get().is_some())?;
+    let acl = Acl::new(&config.acl)?;
+
+    *AUTHORITY.get().unwrap().write().unwrap() = authority;
+    *HOSTS.get().unwrap().write().unwrap() = hosts;
+    *BLOCKLIST.get().unwrap().write().unwrap() = blocklist;
+    *RPZ.get().unwrap().write().unwrap() = rpz;
+    *ACL.get().unwrap().write().unwrap() = acl;
+    logging::set_query_log(config.log.queries);
+    *CONFIG.get().unwrap().write().unwrap() = config;
+
+    Ok(format!("reloaded {}\n", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileTypeExt;
+
+    use super::*;
+    use crate::util::TempFile;
+
+    #[tokio::test]
+    async fn binds_a_socket_only_the_owner_may_use() {
+        // Replaces what is at the path, as a stale socket would be.
+        let file = TempFile::new("");
+        let path = file.path().to_str().unwrap();
+
+        let listener = bind(path).unwrap();
+
+        let meta = fs::metadata(path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert!(!Path::new(&format!("{path}.{}", process::id())).exists());
+
+        let (client, accepted) = tokio::join!(UnixStream::connect(path), listener.accept());
+        client.unwrap();
+        accepted.unwrap();
+    }
+}
@@ -1,9 +1,26 @@
 use std::{
+    fmt,
     hash::Hash,
     net::{Ipv4Addr, Ipv6Addr},
 };
 
-use crate::buffer::BytePacketBuffer;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::buffer::{BytePacketBuffer, MAX_MESSAGE_SIZE};
+
+/// Size of the fixed message header; anything shorter can't be answered.
+pub const HEADER_SIZE: usize = 12;
+
+/// The standard query, the only opcode we implement.
+pub const OPCODE_QUERY: u8 = 0;
+
+/// UDP payload size advertised in our OPT records, small enough to avoid IP
+/// fragmentation on common paths.
+pub const EDNS_UDP_SIZE: u16 = 1232;
+
+// Type of the EDNS(0) OPT pseudo-record (RFC 6891).
+const OPT_TYPE: u16 = 41;
 
 #[derive(Debug, Clone)]
 pub struct DnsPacket {
@@ -12,6 +29,8 @@ pub struct DnsPacket {
     pub answers: Vec<DnsRecord>,
     pub authorities: Vec<DnsRecord>,
     pub resources: Vec<DnsRecord>,
+    /// From the OPT record, which is kept out of `resources`.
+    pub edns: Option<Edns>,
 }
 
 impl DnsPacket {
@@ -22,6 +41,7 @@ impl DnsPacket {
             answers: Vec::new(),
             authorities: Vec::new(),
             resources: Vec::new(),
+            edns: None,
         }
     }
 
@@ -46,6 +66,15 @@ impl DnsPacket {
         }
 
         for _ in 0..result.header.resource_entries {
+            let start = buffer.pos();
+            let mut name = String::new();
+            buffer.read_qname(&mut name)?;
+            if buffer.read_u16()? == OPT_TYPE {
+                result.edns = Some(Edns::read(buffer)?);
+                continue;
+            }
+
+            buffer.seek(start)?;
             let rec = DnsRecord::read(buffer)?;
             result.resources.push(rec);
         }
@@ -57,7 +86,7 @@ impl DnsPacket {
         self.header.questions = self.questions.len() as u16;
         self.header.answers = self.answers.len() as u16;
         self.header.authoritative_entries = self.authorities.len() as u16;
-        self.header.resource_entries = self.resources.len() as u16;
+        self.header.resource_entries = (self.resources.len() + self.edns.is_some() as usize) as u16;
 
         self.header.write(buffer)?;
 
@@ -77,6 +106,10 @@ impl DnsPacket {
             rec.write(buffer)?;
         }
 
+        if let Some(edns) = &self.edns {
+            edns.write(buffer)?;
+        }
+
         Ok(())
     }
 
@@ -119,6 +152,40 @@ impl DnsPacket {
     }
 }
 
+/// The EDNS(0) parameters of a message (RFC 6891).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edns {
+    /// Largest UDP message the sender can take.
+    pub udp_size: u16,
+    /// Whether the sender wants DNSSEC records (RFC 3225).
+    pub dnssec_ok: bool,
+}
+
+impl Edns {
+    /// Reads the rest of an OPT record, after its owner name and type.
+    fn read(buffer: &mut BytePacketBuffer) -> Result<Edns, String> {
+        let udp_size = buffer.read_u16()?;
+        let flags = buffer.read_u32()?;
+        let data_len = buffer.read_u16()?;
+        buffer.step(data_len as usize)?;
+
+        Ok(Edns {
+            udp_size,
+            dnssec_ok: flags & (1 << 15) != 0,
+        })
+    }
+
+    fn write(&self, buffer: &mut BytePacketBuffer) -> Result<(), String> {
+        buffer.write_qname("")?;
+        buffer.write_u16(OPT_TYPE)?;
+        buffer.write_u16(self.udp_size)?;
+        buffer.write_u32((self.dnssec_ok as u32) << 15)?;
+        buffer.write_u16(0)?;
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DnsHeader {
     pub id: u16,
@@ -218,8 +285,9 @@ impl DnsHeader {
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub enum ResultCode {
+    #[default]
     NOERROR = 0,
     FORMERR = 1,
     SERVFAIL = 2,
@@ -272,15 +340,24 @@ impl DnsQuestion {
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub enum QueryType {
     UNKNOWN(u16),
     #[default]
     A, //1
-    NS,    //2
-    CNAME, //5
-    MX,    //15
-    AAAA,  //28
+    NS,         //2
+    CNAME,      //5
+    SOA,        //6
+    PTR,        //12
+    MX,         //15
+    TXT,        //16
+    AAAA,       //28
+    DS,         //43
+    RRSIG,      //46
+    NSEC,       //47
+    DNSKEY,     //48
+    NSEC3,      //50
+    NSEC3PARAM, //51
 }
 
 impl QueryType {
@@ -290,8 +367,17 @@ impl QueryType {
             Self::A => 1,
             Self::NS => 2,
             Self::CNAME => 5,
+            Self::SOA => 6,
+            Self::PTR => 12,
             Self::MX => 15,
+            Self::TXT => 16,
             Self::AAAA => 28,
+            Self::DS => 43,
+            Self::RRSIG => 46,
+            Self::NSEC => 47,
+            Self::DNSKEY => 48,
+            Self::NSEC3 => 50,
+            Self::NSEC3PARAM => 51,
         }
     }
 
@@ -300,21 +386,43 @@ impl QueryType {
             1 => Self::A,
             2 => Self::NS,
             5 => Self::CNAME,
+            6 => Self::SOA,
+            12 => Self::PTR,
             15 => Self::MX,
+            16 => Self::TXT,
             28 => Self::AAAA,
+            43 => Self::DS,
+            46 => Self::RRSIG,
+            47 => Self::NSEC,
+            48 => Self::DNSKEY,
+            50 => Self::NSEC3,
+            51 => Self::NSEC3PARAM,
             other => QueryType::UNKNOWN(other),
         }
     }
 }
 
+/// Formats the type as in master files, e.g. `A` or `TYPE65`.
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{num}"),
+            known => write!(f, "{known:?}"),
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        /// The RDATA as received, kept so the record can be passed on and
+        /// its signature checked.
+        #[serde(default)]
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
@@ -332,17 +440,94 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 12
     MX {
         domain: String,
         priority: u16,
         host: String,
         ttl: u32,
     }, // 15
+    TXT {
+        domain: String,
+        data: Vec<String>,
+        ttl: u32,
+    }, // 16
     AAAA {
         domain: String,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    }, // 43
+    RRSIG {
+        domain: String,
+        type_covered: QueryType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        /// Validity period, in seconds since the Unix epoch.
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer: String,
+        signature: Vec<u8>,
+        ttl: u32,
+    }, // 46
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    }, // 48
+    NSEC {
+        domain: String,
+        next_domain: String,
+        /// The types present at the owner name.
+        types: Vec<QueryType>,
+        ttl: u32,
+    }, // 47
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        /// The next owner name's hash, unencoded.
+        next_hashed: Vec<u8>,
+        types: Vec<QueryType>,
+        ttl: u32,
+    }, // 50
+    NSEC3PARAM {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        ttl: u32,
+    }, // 51
 }
 
 impl DnsRecord {
@@ -408,6 +593,54 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::SOA => {
+                let mut mname = String::new();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = String::new();
+                buffer.read_qname(&mut rname)?;
+
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+
+                Ok(DnsRecord::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    ttl,
+                })
+            }
+            QueryType::PTR => {
+                let mut ptr = String::new();
+                buffer.read_qname(&mut ptr)?;
+
+                Ok(DnsRecord::PTR {
+                    domain,
+                    host: ptr,
+                    ttl,
+                })
+            }
+            QueryType::TXT => {
+                let end = buffer.pos() + data_len as usize;
+                let mut data = Vec::new();
+
+                while buffer.pos() < end {
+                    let len = buffer.read_u8()? as usize;
+                    let pos = buffer.pos();
+                    let txt = String::from_utf8_lossy(buffer.get_range(pos, len)?).to_string();
+                    buffer.step(len)?;
+                    data.push(txt);
+                }
+
+                Ok(DnsRecord::TXT { domain, data, ttl })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let mut mx = String::new();
@@ -420,13 +653,124 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::DS => {
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read_u8()?;
+                let digest_type = buffer.read_u8()?;
+                let digest = buffer.read_bytes((data_len as usize).saturating_sub(4))?;
+
+                Ok(DnsRecord::DS {
+                    domain,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ttl,
+                })
+            }
+            QueryType::RRSIG => {
+                let end = buffer.pos() + data_len as usize;
+
+                let type_covered = QueryType::from_num(buffer.read_u16()?);
+                let algorithm = buffer.read_u8()?;
+                let labels = buffer.read_u8()?;
+                let original_ttl = buffer.read_u32()?;
+                let expiration = buffer.read_u32()?;
+                let inception = buffer.read_u32()?;
+                let key_tag = buffer.read_u16()?;
+                let mut signer = String::new();
+                buffer.read_qname(&mut signer)?;
+                let signature = buffer.read_bytes(end.saturating_sub(buffer.pos()))?;
+
+                Ok(DnsRecord::RRSIG {
+                    domain,
+                    type_covered,
+                    algorithm,
+                    labels,
+                    original_ttl,
+                    expiration,
+                    inception,
+                    key_tag,
+                    signer,
+                    signature,
+                    ttl,
+                })
+            }
+            QueryType::DNSKEY => {
+                let flags = buffer.read_u16()?;
+                let protocol = buffer.read_u8()?;
+                let algorithm = buffer.read_u8()?;
+                let public_key = buffer.read_bytes((data_len as usize).saturating_sub(4))?;
+
+                Ok(DnsRecord::DNSKEY {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                    ttl,
+                })
+            }
+            QueryType::NSEC => {
+                let end = buffer.pos() + data_len as usize;
+
+                let mut next_domain = String::new();
+                buffer.read_qname_keep_case(&mut next_domain)?;
+                let types = read_type_bitmap(buffer, end)?;
+
+                Ok(DnsRecord::NSEC {
+                    domain,
+                    next_domain,
+                    types,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3 => {
+                let end = buffer.pos() + data_len as usize;
+
+                let hash_algorithm = buffer.read_u8()?;
+                let flags = buffer.read_u8()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read_u8()?;
+                let salt = buffer.read_bytes(salt_len as usize)?;
+                let hash_len = buffer.read_u8()?;
+                let next_hashed = buffer.read_bytes(hash_len as usize)?;
+                let types = read_type_bitmap(buffer, end)?;
+
+                Ok(DnsRecord::NSEC3 {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed,
+                    types,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3PARAM => {
+                let hash_algorithm = buffer.read_u8()?;
+                let flags = buffer.read_u8()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read_u8()?;
+                let salt = buffer.read_bytes(salt_len as usize)?;
+
+                Ok(DnsRecord::NSEC3PARAM {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    ttl,
+                })
+            }
             QueryType::UNKNOWN(_) => {
-                buffer.step(data_len as usize)?;
+                let data = buffer.read_bytes(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    data,
                     ttl,
                 })
             }
@@ -490,6 +834,79 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::TXT {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for txt in data {
+                    for chunk in txt.as_bytes().chunks(255) {
+                        buffer.write_u8(chunk.len() as u8)?;
+                        for b in chunk {
+                            buffer.write_u8(*b)?;
+                        }
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::MX {
                 ref domain,
                 priority,
@@ -525,11 +942,661 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::DS {
+                ref domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DS.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4 + digest.len() as u16)?;
+
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(digest_type)?;
+                buffer.write_bytes(digest)?;
+            }
+            DnsRecord::RRSIG {
+                ref domain,
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                ref signer,
+                ref signature,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::RRSIG.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(type_covered.to_num())?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(labels)?;
+                buffer.write_u32(original_ttl)?;
+                buffer.write_u32(expiration)?;
+                buffer.write_u32(inception)?;
+                buffer.write_u16(key_tag)?;
+                buffer.write_qname(signer)?;
+                buffer.write_bytes(signature)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::DNSKEY {
+                ref domain,
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNSKEY.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4 + public_key.len() as u16)?;
+
+                buffer.write_u16(flags)?;
+                buffer.write_u8(protocol)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_bytes(public_key)?;
+            }
+            DnsRecord::NSEC {
+                ref domain,
+                ref next_domain,
+                ref types,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(next_domain)?;
+                write_type_bitmap(buffer, types)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::NSEC3 {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed,
+                ref types,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                buffer.write_u8(salt.len() as u8)?;
+                buffer.write_bytes(salt)?;
+                buffer.write_u8(next_hashed.len() as u8)?;
+                buffer.write_bytes(next_hashed)?;
+                write_type_bitmap(buffer, types)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::NSEC3PARAM {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3PARAM.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(5 + salt.len() as u16)?;
+
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                buffer.write_u8(salt.len() as u8)?;
+                buffer.write_bytes(salt)?;
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(data)?;
             }
         }
 
         Ok(buffer.pos() - start_pos)
     }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::NSEC3PARAM { domain, .. } => domain,
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match *self {
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype),
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+            DnsRecord::NSEC3PARAM { .. } => QueryType::NSEC3PARAM,
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match *self {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::NSEC3PARAM { ttl, .. } => ttl,
+        }
+    }
+
+    /// Returns a copy of the record with its TTL replaced.
+    pub fn with_ttl(&self, new_ttl: u32) -> DnsRecord {
+        let mut rec = self.clone();
+        match &mut rec {
+            DnsRecord::UNKNOWN { ttl, .. }
+            | DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::NSEC3PARAM { ttl, .. } => *ttl = new_ttl,
+        }
+
+        rec
+    }
+
+    /// Encodes the record in canonical form (RFC 4034 section 6.2), as it is
+    /// signed: names lowercased and uncompressed, with `original_ttl`.
+    pub fn to_canonical(&self, original_ttl: u32) -> Result<Vec<u8>, String> {
+        let mut rec = self.with_ttl(original_ttl);
+        match &mut rec {
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. }
+            | DnsRecord::MX { host, .. } => host.make_ascii_lowercase(),
+            DnsRecord::SOA { mname, rname, .. } => {
+                mname.make_ascii_lowercase();
+                rname.make_ascii_lowercase();
+            }
+            DnsRecord::RRSIG { signer, .. } => signer.make_ascii_lowercase(),
+            // The next name in NSEC keeps its case (RFC 6840 section 5.1).
+            _ => {}
+        }
+        let rec = rec.with_domain(&self.domain().to_ascii_lowercase());
+
+        let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+        rec.write(&mut buffer)?;
+
+        Ok(buffer.buf[..buffer.pos()].to_vec())
+    }
+
+    /// Returns the record's RDATA in canonical form, the key canonical
+    /// ordering sorts the records of a set by.
+    pub fn canonical_rdata(&self) -> Result<Vec<u8>, String> {
+        let wire = self.to_canonical(0)?;
+        // Skip the owner name and the type, class, TTL and length fields.
+        let mut pos = 0;
+        while wire[pos] != 0 {
+            pos += wire[pos] as usize + 1;
+        }
+
+        Ok(wire[pos + 11..].to_vec())
+    }
+
+    /// Returns a copy of the record with its owner name replaced.
+    pub fn with_domain(&self, name: &str) -> DnsRecord {
+        let mut rec = self.clone();
+        match &mut rec {
+            DnsRecord::UNKNOWN { domain, .. }
+            | DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::NSEC3PARAM { domain, .. } => *domain = name.to_string(),
+        }
+
+        rec
+    }
+}
+
+/// Formats the record as a master file line, e.g.
+/// `example.com. 300 IN A 192.0.2.1`.
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}. {} IN ", self.domain(), self.ttl())?;
+        match self {
+            DnsRecord::UNKNOWN { qtype, data, .. } => {
+                write!(f, "TYPE{qtype} \\# {}", data.len())?;
+                if !data.is_empty() {
+                    write!(f, " {}", hex(data))?;
+                }
+                Ok(())
+            }
+            DnsRecord::A { addr, .. } => write!(f, "A {addr}"),
+            DnsRecord::NS { host, .. } => write!(f, "NS {host}."),
+            DnsRecord::CNAME { host, .. } => write!(f, "CNAME {host}."),
+            DnsRecord::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "SOA {mname}. {rname}. {serial} {refresh} {retry} {expire} {minimum}"
+            ),
+            DnsRecord::PTR { host, .. } => write!(f, "PTR {host}."),
+            DnsRecord::MX { priority, host, .. } => write!(f, "MX {priority} {host}."),
+            DnsRecord::TXT { data, .. } => {
+                f.write_str("TXT")?;
+                for s in data {
+                    write!(f, " \"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))?;
+                }
+                Ok(())
+            }
+            DnsRecord::AAAA { addr, .. } => write!(f, "AAAA {addr}"),
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => write!(
+                f,
+                "DS {key_tag} {algorithm} {digest_type} {}",
+                hex(digest)
+            ),
+            DnsRecord::RRSIG {
+                type_covered,
+                algorithm,
+                labels,
+                original_ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer,
+                signature,
+                ..
+            } => write!(
+                f,
+                "RRSIG {type_covered} {algorithm} {labels} {original_ttl} {expiration} {inception} {key_tag} {signer}. {}",
+                BASE64.encode(signature)
+            ),
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => write!(
+                f,
+                "DNSKEY {flags} {protocol} {algorithm} {}",
+                BASE64.encode(public_key)
+            ),
+            DnsRecord::NSEC {
+                next_domain, types, ..
+            } => {
+                write!(f, "NSEC {next_domain}.")?;
+                for qtype in types {
+                    write!(f, " {qtype}")?;
+                }
+                Ok(())
+            }
+            DnsRecord::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+                ..
+            } => {
+                write!(
+                    f,
+                    "NSEC3 {hash_algorithm} {flags} {iterations} {} {}",
+                    salt_text(salt),
+                    base32hex(next_hashed)
+                )?;
+                for qtype in types {
+                    write!(f, " {qtype}")?;
+                }
+                Ok(())
+            }
+            DnsRecord::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                ..
+            } => write!(
+                f,
+                "NSEC3PARAM {hash_algorithm} {flags} {iterations} {}",
+                salt_text(salt)
+            ),
+        }
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02X}")).collect()
+}
+
+/// NSEC3 salts are written in hex, or `-` when empty.
+fn salt_text(salt: &[u8]) -> String {
+    if salt.is_empty() {
+        "-".to_string()
+    } else {
+        hex(salt)
+    }
+}
+
+/// Encodes `data` in unpadded base32 with the extended hex alphabet, as
+/// NSEC3 hashes are (RFC 4648 section 7).
+pub fn base32hex(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut bits, mut acc) = (0, 0u32);
+    for byte in data {
+        acc = (acc << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((acc >> bits) & 0x1F) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((acc << (5 - bits)) & 0x1F) as usize] as char);
+    }
+
+    out
+}
+
+/// Reads the type bitmap of an NSEC or NSEC3 record, which runs to `end`
+/// (RFC 4034 section 4.1.2).
+fn read_type_bitmap(buffer: &mut BytePacketBuffer, end: usize) -> Result<Vec<QueryType>, String> {
+    let mut types = Vec::new();
+    while buffer.pos() < end {
+        let window = buffer.read_u8()? as u16;
+        let len = buffer.read_u8()?;
+        if len == 0 || len > 32 {
+            return Err(format!("invalid type bitmap length {len}"));
+        }
+
+        for (i, byte) in buffer.read_bytes(len as usize)?.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    let num = (window << 8) | (i as u16 * 8 + bit);
+                    types.push(QueryType::from_num(num));
+                }
+            }
+        }
+    }
+
+    Ok(types)
+}
+
+/// Writes `types` as a type bitmap, one block per 256-type window in use.
+fn write_type_bitmap(buffer: &mut BytePacketBuffer, types: &[QueryType]) -> Result<(), String> {
+    let mut nums: Vec<u16> = types.iter().map(|t| t.to_num()).collect();
+    nums.sort_unstable();
+    nums.dedup();
+
+    for window in nums.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let mut bitmap = [0u8; 32];
+        for num in window {
+            let low = (num & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+        }
+        let len = window.last().map_or(0, |num| (num & 0xFF) as usize / 8 + 1);
+
+        buffer.write_u8((window[0] >> 8) as u8)?;
+        buffer.write_u8(len as u8)?;
+        buffer.write_bytes(&bitmap[..len])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(rec: &DnsRecord) -> DnsRecord {
+        let mut buffer = BytePacketBuffer::new();
+        rec.write(&mut buffer).unwrap();
+        buffer.seek(0).unwrap();
+        DnsRecord::read(&mut buffer).unwrap()
+    }
+
+    #[test]
+    fn nsec_type_bitmap_matches_rfc_4034_example() {
+        // alfa.example.com. NSEC host.example.com. A MX RRSIG NSEC TYPE1234
+        // (RFC 4034 section 4.3).
+        let types = vec![
+            QueryType::A,
+            QueryType::MX,
+            QueryType::RRSIG,
+            QueryType::NSEC,
+            QueryType::UNKNOWN(1234),
+        ];
+        let rec = DnsRecord::NSEC {
+            domain: "alfa.example.com".to_string(),
+            next_domain: "host.example.com".to_string(),
+            types: types.clone(),
+            ttl: 86400,
+        };
+
+        let rdata = rec.canonical_rdata().unwrap();
+        let bitmap = &rdata["host.example.com".len() + 2..];
+        let mut expected = vec![0x00, 0x06, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03, 0x04, 0x1b];
+        expected.extend_from_slice(&[0; 26]);
+        expected.push(0x20);
+        assert_eq!(bitmap, expected);
+
+        let DnsRecord::NSEC {
+            next_domain,
+            types: read_types,
+            ..
+        } = round_trip(&rec)
+        else {
+            panic!("not an NSEC record");
+        };
+        assert_eq!(next_domain, "host.example.com");
+        assert_eq!(read_types, types);
+    }
+
+    #[test]
+    fn type_bitmap_is_sorted_and_deduplicated() {
+        let rec = DnsRecord::NSEC3 {
+            domain: "example".to_string(),
+            hash_algorithm: 1,
+            flags: 0,
+            iterations: 0,
+            salt: Vec::new(),
+            next_hashed: vec![0xAB; 20],
+            types: vec![QueryType::AAAA, QueryType::A, QueryType::AAAA],
+            ttl: 3600,
+        };
+
+        let DnsRecord::NSEC3 { types, .. } = round_trip(&rec) else {
+            panic!("not an NSEC3 record");
+        };
+        assert_eq!(types, vec![QueryType::A, QueryType::AAAA]);
+    }
+
+    #[test]
+    fn canonical_form_lowercases_names_but_not_the_nsec_next_name() {
+        let ns = DnsRecord::NS {
+            domain: "Example.COM".to_string(),
+            host: "NS1.Example.com".to_string(),
+            ttl: 60,
+        };
+        let mut expected =
+            b"\x07example\x03com\x00\x00\x02\x00\x01\x00\x00\x0e\x10\x00\x11".to_vec();
+        expected.extend_from_slice(b"\x03ns1\x07example\x03com\x00");
+        assert_eq!(ns.to_canonical(3600).unwrap(), expected);
+
+        let nsec = DnsRecord::NSEC {
+            domain: "a.example".to_string(),
+            next_domain: "B.example".to_string(),
+            types: vec![QueryType::A],
+            ttl: 60,
+        };
+        let DnsRecord::NSEC { next_domain, .. } = round_trip(&nsec) else {
+            panic!("not an NSEC record");
+        };
+        assert_eq!(next_domain, "B.example");
+        assert!(nsec
+            .canonical_rdata()
+            .unwrap()
+            .starts_with(b"\x01B\x07example\x00"));
+    }
+
+    #[test]
+    fn canonical_rdata_orders_by_octets() {
+        // RFC 4034 section 6.3: RDATA compares as left-justified unsigned
+        // octet sequences, so the length byte of a name comes first.
+        let ns = |host: &str| DnsRecord::NS {
+            domain: "example".to_string(),
+            host: host.to_string(),
+            ttl: 60,
+        };
+        let a = |addr: [u8; 4]| DnsRecord::A {
+            domain: "example".to_string(),
+            addr: addr.into(),
+            ttl: 60,
+        };
+
+        let mut hosts = [ns("ab.example"), ns("B.example"), ns("a.example")];
+        hosts.sort_by_key(|rec| rec.canonical_rdata().unwrap());
+        let hosts: Vec<_> = hosts
+            .iter()
+            .map(|rec| match rec {
+                DnsRecord::NS { host, .. } => host.as_str(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(hosts, ["a.example", "B.example", "ab.example"]);
+
+        let mut addrs = [a([10, 0, 0, 10]), a([9, 0, 0, 1]), a([10, 0, 0, 2])];
+        addrs.sort_by_key(|rec| rec.canonical_rdata().unwrap());
+        let addrs: Vec<_> = addrs
+            .iter()
+            .map(|rec| match rec {
+                DnsRecord::A { addr, .. } => addr.to_string(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(addrs, ["9.0.0.1", "10.0.0.2", "10.0.0.10"]);
+    }
+
+    #[test]
+    fn base32hex_matches_rfc_4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "CO"),
+            ("fo", "CPNG"),
+            ("foo", "CPNMU"),
+            ("foob", "CPNMUOG"),
+            ("fooba", "CPNMUOJ1"),
+            ("foobar", "CPNMUOJ1E8"),
+        ];
+        for (input, encoded) in vectors {
+            assert_eq!(base32hex(input.as_bytes()), encoded);
+        }
+    }
 }
@@ -0,0 +1,1076 @@
+use std::{
+    future::Future,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use lru::LruCache;
+use ring::{digest, signature};
+use tracing::debug;
+
+use crate::{
+    buffer::{BytePacketBuffer, MAX_MESSAGE_SIZE},
+    config::DnssecConfig,
+    dns::{DnsPacket, DnsQuestion, DnsRecord, QueryType, ResultCode},
+    master::{normalize_name, parse_hex},
+    nsec,
+    util::recursive_lookup,
+    zone::{is_subdomain, parent_name},
+};
+
+// DS records of the root zone's key signing keys, KSK-2017 and KSK-2024,
+// as published at https://data.iana.org/root-anchors/.
+const ROOT_ANCHORS: &[&str] = &[
+    ". IN DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    ". IN DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+];
+
+// Zones whose keys are remembered between validations.
+const KEY_CACHE_SIZE: usize = 10_000;
+
+// Names whose zone is remembered, so that unsigned answers don't each cost
+// a SOA lookup.
+const ZONE_CACHE_SIZE: usize = 10_000;
+
+// Upper bound, in seconds, on how long a zone's keys are trusted before its
+// chain is checked again.
+const MAX_KEY_TTL: u32 = 3600;
+
+// How long a zone that failed validation is remembered as bogus, so that its
+// keys aren't fetched again for every query (RFC 4035 section 4.7).
+const BOGUS_TTL: u32 = 60;
+
+// DNSKEY flag marking a zone key, the only kind that may sign records.
+const ZONE_KEY_FLAG: u16 = 0x0100;
+
+/// Outcome of validating a response (RFC 4035 section 4.3).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Security {
+    /// Signed, with a chain of trust up to a trust anchor.
+    Secure,
+    /// From a zone with no chain of trust, because a parent has no DS
+    /// records for it or only ones we can't use.
+    Insecure,
+    /// Missing or broken signatures where there should be valid ones.
+    Bogus(String),
+}
+
+impl Security {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Security::Secure => "secure",
+            Security::Insecure => "insecure",
+            Security::Bogus(_) => "bogus",
+        }
+    }
+}
+
+/// What is known about a zone's keys once its chain of trust is followed.
+#[derive(Debug, Clone)]
+enum ZoneKeys {
+    /// The zone's DNSKEY set, authenticated from the zone above.
+    Secure(Vec<DnsRecord>),
+    Insecure,
+    Bogus(String),
+}
+
+/// Validates recursive answers against the trust anchors, fetching and
+/// checking the DS and DNSKEY records of every zone on the way down.
+#[derive(Debug)]
+pub struct Validator {
+    anchors: Vec<DnsRecord>,
+    zones: Mutex<LruCache<String, (ZoneKeys, Instant)>>,
+    /// The zone each name looked up by `find_zone` belongs to.
+    names: Mutex<LruCache<String, (String, Instant)>>,
+}
+
+impl Validator {
+    pub fn new(config: &DnssecConfig) -> Result<Validator, String> {
+        let anchors: Result<Vec<_>, String> = if config.trust_anchors.is_empty() {
+            ROOT_ANCHORS.iter().map(|ds| parse_ds(ds)).collect()
+        } else {
+            config.trust_anchors.iter().map(|ds| parse_ds(ds)).collect()
+        };
+
+        Ok(Validator {
+            anchors: anchors?,
+            zones: Mutex::new(LruCache::new(NonZeroUsize::new(KEY_CACHE_SIZE).unwrap())),
+            names: Mutex::new(LruCache::new(NonZeroUsize::new(ZONE_CACHE_SIZE).unwrap())),
+        })
+    }
+
+    /// Checks the signatures on a response to `question`. Answers are
+    /// validated set by set. Where the data asked for is missing, as in
+    /// NXDOMAIN and NODATA responses or at the end of a CNAME chain, the
+    /// signed SOA of the zone and the NSEC or NSEC3 records proving the
+    /// denial are checked.
+    pub async fn validate(&self, question: &DnsQuestion, response: &DnsPacket) -> Security {
+        let now = unix_now();
+
+        let mut security = Security::Secure;
+        for (owner, rtype) in rrsets(&response.answers) {
+            let result = self.validate_rrset(response, &owner, rtype, now).await;
+            security = combine(security, result);
+            if let Security::Bogus(_) = security {
+                return security;
+            }
+        }
+
+        let target = cname_target(&question.name, question.qtype, &response.answers);
+        let answered = response
+            .answers
+            .iter()
+            .any(|rec| rec.domain() == target && rec.qtype() == question.qtype);
+        let denied = response.answers.is_empty()
+            || response.header.rescode == ResultCode::NXDOMAIN
+            || find_soa(&response.authorities).is_some();
+        if answered || !denied {
+            return security;
+        }
+
+        let denial = self
+            .validate_denial(&target, question.qtype, response, now)
+            .await;
+        combine(security, denial)
+    }
+
+    /// Checks a response saying there is no `qtype` data at `qname`.
+    async fn validate_denial(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        response: &DnsPacket,
+        now: u32,
+    ) -> Security {
+        let zone = match find_soa(&response.authorities) {
+            Some(soa) => soa.domain().to_string(),
+            None => self.find_zone(qname).await,
+        };
+        if !is_subdomain(qname, &zone) {
+            return Security::Bogus(format!("denial of {qname} from {zone}"));
+        }
+
+        match self.zone_keys(&zone).await {
+            ZoneKeys::Secure(keys) => {
+                let (set, sigs) = rrset(&response.authorities, &zone, QueryType::SOA);
+                if set.is_empty() {
+                    return Security::Bogus(format!("denial from {zone} has no SOA"));
+                }
+                if let Err(e) = verify_rrset(&set, &sigs, &keys, now) {
+                    return Security::Bogus(e);
+                }
+
+                // A replayed SOA proves nothing by itself; the NSEC or NSEC3
+                // records have to cover the name.
+                let nxdomain = response.header.rescode == ResultCode::NXDOMAIN;
+                match self.authenticated_denials(&response.authorities, now).await {
+                    Ok(denials) => nsec::prove_denial(qname, qtype, nxdomain, &denials),
+                    Err(bogus) => bogus,
+                }
+            }
+            ZoneKeys::Insecure => Security::Insecure,
+            ZoneKeys::Bogus(reason) => Security::Bogus(reason),
+        }
+    }
+
+    /// Picks out the NSEC and NSEC3 records in `records` whose signatures
+    /// verify. Unsigned ones and those from insecure zones are left out, as
+    /// they can't prove anything; a bad signature makes the whole response
+    /// bogus.
+    async fn authenticated_denials<'a>(
+        &self,
+        records: &'a [DnsRecord],
+        now: u32,
+    ) -> Result<Vec<&'a DnsRecord>, Security> {
+        let mut denials = Vec::new();
+
+        for (owner, rtype) in rrsets(records) {
+            if !matches!(rtype, QueryType::NSEC | QueryType::NSEC3) {
+                continue;
+            }
+
+            let (set, sigs) = rrset(records, &owner, rtype);
+            let Some(signer) = signer(&owner, &sigs) else {
+                continue;
+            };
+
+            match self.zone_keys(signer).await {
+                ZoneKeys::Secure(keys) => match verify_rrset(&set, &sigs, &keys, now) {
+                    Ok(_) => denials.extend(set),
+                    Err(e) => return Err(Security::Bogus(e)),
+                },
+                ZoneKeys::Insecure => {}
+                ZoneKeys::Bogus(reason) => return Err(Security::Bogus(reason)),
+            }
+        }
+
+        Ok(denials)
+    }
+
+    async fn validate_rrset(
+        &self,
+        response: &DnsPacket,
+        owner: &str,
+        rtype: QueryType,
+        now: u32,
+    ) -> Security {
+        let (set, sigs) = rrset(&response.answers, owner, rtype);
+
+        let Some(signer) = signer(owner, &sigs) else {
+            // Unsigned data is only fine from a zone that isn't signed.
+            let zone = self.find_zone(owner).await;
+            return match self.zone_keys(&zone).await {
+                ZoneKeys::Secure(_) => {
+                    Security::Bogus(format!("no signature on {owner} {rtype} from {zone}"))
+                }
+                ZoneKeys::Insecure => Security::Insecure,
+                ZoneKeys::Bogus(reason) => Security::Bogus(reason),
+            };
+        };
+
+        let keys = match self.zone_keys(signer).await {
+            ZoneKeys::Secure(keys) => keys,
+            ZoneKeys::Insecure => return Security::Insecure,
+            ZoneKeys::Bogus(reason) => return Security::Bogus(reason),
+        };
+        let labels = match verify_rrset(&set, &sigs, &keys, now) {
+            Ok(DnsRecord::RRSIG { labels, .. }) => *labels,
+            Ok(_) => unreachable!("verify_rrset returns a signature"),
+            Err(e) => return Security::Bogus(e),
+        };
+
+        // Synthesised from a wildcard: the name itself mustn't exist.
+        if (labels as usize) < label_count(owner) {
+            return match self.authenticated_denials(&response.authorities, now).await {
+                Ok(denials) => nsec::prove_wildcard_expansion(owner, labels, &denials),
+                Err(bogus) => bogus,
+            };
+        }
+
+        Security::Secure
+    }
+
+    /// Finds the zone `name` belongs to, from the SOA record that comes with
+    /// the answer to a SOA query. Falls back to the root, so that nothing
+    /// escapes validation by hiding its zone.
+    async fn find_zone(&self, name: &str) -> String {
+        if let Some(zone) = self.cached_zone(name) {
+            return zone;
+        }
+
+        let response = match recursive_lookup(name, QueryType::SOA, &mut Vec::new()).await {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("failed to find the zone of {name}: {e}");
+                return String::new();
+            }
+        };
+
+        let soa = find_soa(&response.answers)
+            .or_else(|| find_soa(&response.authorities))
+            .filter(|soa| is_subdomain(name, soa.domain()));
+        let (zone, ttl) = match soa {
+            Some(soa) => (soa.domain().to_string(), soa.ttl().min(MAX_KEY_TTL)),
+            None => (String::new(), BOGUS_TTL),
+        };
+
+        let expires = Instant::now() + Duration::from_secs(ttl as u64);
+        self.names
+            .lock()
+            .unwrap()
+            .put(name.to_string(), (zone.clone(), expires));
+
+        zone
+    }
+
+    /// The zone of `name` if `find_zone` looked it up before, or the nearest
+    /// ancestor already known to be insecure, since everything below one is
+    /// insecure too and needs no lookup.
+    fn cached_zone(&self, name: &str) -> Option<String> {
+        let now = Instant::now();
+
+        if let Some((zone, expires)) = self.names.lock().unwrap().get(name) {
+            if *expires > now {
+                return Some(zone.clone());
+            }
+        }
+
+        let zones = self.zones.lock().unwrap();
+        let mut ancestor = Some(name);
+        while let Some(zone) = ancestor {
+            if let Some((ZoneKeys::Insecure, expires)) = zones.peek(zone) {
+                if *expires > now {
+                    return Some(zone.to_string());
+                }
+            }
+            ancestor = parent_name(zone);
+        }
+
+        None
+    }
+
+    /// Returns the authenticated keys of `zone`, following its chain of
+    /// trust up to the anchors as far as it isn't cached.
+    fn zone_keys<'a>(
+        &'a self,
+        zone: &'a str,
+    ) -> Pin<Box<dyn Future<Output = ZoneKeys> + Send + 'a>> {
+        Box::pin(async move {
+            if let Some((keys, expires)) = self.zones.lock().unwrap().get(zone) {
+                if *expires > Instant::now() {
+                    return keys.clone();
+                }
+            }
+
+            let (keys, ttl) = self.fetch_zone_keys(zone).await;
+            match &keys {
+                ZoneKeys::Secure(_) => debug!("zone {zone:?} is secure"),
+                ZoneKeys::Insecure => debug!("zone {zone:?} is insecure"),
+                ZoneKeys::Bogus(reason) => debug!("zone {zone:?} is bogus: {reason}"),
+            }
+
+            let expires = Instant::now() + Duration::from_secs(ttl.min(MAX_KEY_TTL) as u64);
+            self.zones
+                .lock()
+                .unwrap()
+                .put(zone.to_string(), (keys.clone(), expires));
+
+            keys
+        })
+    }
+
+    /// Authenticates the DS set of `zone` with the keys of its parent, then
+    /// its DNSKEY set with the DS set. Returns the result with how long it
+    /// may be cached.
+    async fn fetch_zone_keys(&self, zone: &str) -> (ZoneKeys, u32) {
+        let now = unix_now();
+
+        let (ds_set, ds_ttl) = if zone.is_empty() {
+            (self.anchors.clone(), MAX_KEY_TTL)
+        } else {
+            let response = match recursive_lookup(zone, QueryType::DS, &mut Vec::new()).await {
+                Ok(response) => response,
+                Err(e) => return bogus(format!("DS lookup for {zone} failed: {e}")),
+            };
+
+            let (set, sigs) = rrset(&response.answers, zone, QueryType::DS);
+            if set.is_empty() {
+                return self.check_unsigned_delegation(zone, &response, now).await;
+            }
+
+            let parent = sigs.iter().find_map(|sig| match sig {
+                DnsRecord::RRSIG { signer, .. } if signer != zone && is_subdomain(zone, signer) => {
+                    Some(signer)
+                }
+                _ => None,
+            });
+            let Some(parent) = parent else {
+                return bogus(format!("DS set of {zone} isn't signed by a parent zone"));
+            };
+
+            let ttl = min_ttl(&set);
+            match self.zone_keys(parent).await {
+                ZoneKeys::Secure(keys) => {
+                    if let Err(e) = verify_rrset(&set, &sigs, &keys, now) {
+                        return bogus(e);
+                    }
+                }
+                other => return (other, ttl),
+            }
+
+            (set.into_iter().cloned().collect::<Vec<_>>(), ttl)
+        };
+
+        // DS records we can't check a key against don't count; a zone with
+        // none left is treated as unsigned (RFC 4035 section 5.2).
+        let usable: Vec<&DnsRecord> = ds_set
+            .iter()
+            .filter(|ds| match ds {
+                DnsRecord::DS {
+                    algorithm,
+                    digest_type,
+                    ..
+                } => is_supported_algorithm(*algorithm) && digest_algorithm(*digest_type).is_some(),
+                _ => false,
+            })
+            .collect();
+        if usable.is_empty() {
+            return (ZoneKeys::Insecure, ds_ttl);
+        }
+
+        let response = match recursive_lookup(zone, QueryType::DNSKEY, &mut Vec::new()).await {
+            Ok(response) => response,
+            Err(e) => return bogus(format!("DNSKEY lookup for {zone} failed: {e}")),
+        };
+
+        let (keys, sigs) = rrset(&response.answers, zone, QueryType::DNSKEY);
+        let trusted: Vec<DnsRecord> = keys
+            .iter()
+            .filter(|key| usable.iter().any(|ds| ds_matches(ds, key)))
+            .map(|key| (*key).clone())
+            .collect();
+        if trusted.is_empty() {
+            return bogus(format!("no DNSKEY of {zone} matches its DS records"));
+        }
+
+        if let Err(e) = verify_rrset(&keys, &sigs, &trusted, now) {
+            return bogus(e);
+        }
+
+        let ttl = ds_ttl.min(min_ttl(&keys));
+        (ZoneKeys::Secure(keys.into_iter().cloned().collect()), ttl)
+    }
+
+    /// Handles a DS lookup that came back empty: the child zone is unsigned,
+    /// provided the parent signed the response.
+    async fn check_unsigned_delegation(
+        &self,
+        zone: &str,
+        response: &DnsPacket,
+        now: u32,
+    ) -> (ZoneKeys, u32) {
+        let Some(soa) = find_soa(&response.authorities) else {
+            return bogus(format!(
+                "DS lookup for {zone} returned neither records nor a SOA"
+            ));
+        };
+
+        let parent = soa.domain();
+        if parent == zone || !is_subdomain(zone, parent) {
+            return bogus(format!("DS lookup for {zone} was answered by {parent}"));
+        }
+
+        let ttl = soa.ttl();
+        match self.zone_keys(parent).await {
+            ZoneKeys::Secure(keys) => {
+                let (set, sigs) = rrset(&response.authorities, parent, QueryType::SOA);
+                if let Err(e) = verify_rrset(&set, &sigs, &keys, now) {
+                    return bogus(e);
+                }
+
+                // Without a proof that the DS is missing, an attacker could
+                // strip it to turn a signed zone into an unsigned one.
+                let nxdomain = response.header.rescode == ResultCode::NXDOMAIN;
+                let denials = match self.authenticated_denials(&response.authorities, now).await {
+                    Ok(denials) => denials,
+                    Err(Security::Bogus(reason)) => return bogus(reason),
+                    Err(_) => Vec::new(),
+                };
+                match nsec::prove_denial(zone, QueryType::DS, nxdomain, &denials) {
+                    Security::Bogus(reason) => bogus(reason),
+                    _ => (ZoneKeys::Insecure, ttl),
+                }
+            }
+            other => (other, ttl),
+        }
+    }
+}
+
+fn bogus(reason: String) -> (ZoneKeys, u32) {
+    (ZoneKeys::Bogus(reason), BOGUS_TTL)
+}
+
+/// The weaker of two results: bogus over insecure over secure.
+fn combine(a: Security, b: Security) -> Security {
+    match (a, b) {
+        (bogus @ Security::Bogus(_), _) | (_, bogus @ Security::Bogus(_)) => bogus,
+        (Security::Secure, Security::Secure) => Security::Secure,
+        _ => Security::Insecure,
+    }
+}
+
+/// The zone that signed the set at `owner`, from the first of `sigs` by a
+/// zone `owner` is in.
+fn signer<'a>(owner: &str, sigs: &[&'a DnsRecord]) -> Option<&'a str> {
+    sigs.iter().find_map(|sig| match sig {
+        DnsRecord::RRSIG { signer, .. } if is_subdomain(owner, signer) => Some(signer.as_str()),
+        _ => None,
+    })
+}
+
+/// Follows the CNAMEs in `answers` from `qname` to the name that should hold
+/// the data asked for.
+fn cname_target(qname: &str, qtype: QueryType, answers: &[DnsRecord]) -> String {
+    let mut target = qname.to_string();
+    if qtype == QueryType::CNAME {
+        return target;
+    }
+
+    // Bounded, in case the CNAMEs loop.
+    for _ in 0..answers.len() {
+        let next = answers.iter().find_map(|rec| match rec {
+            DnsRecord::CNAME { domain, host, .. } if *domain == target => Some(host.clone()),
+            _ => None,
+        });
+        match next {
+            Some(host) => target = host,
+            None => break,
+        }
+    }
+
+    target
+}
+
+/// Labels in `owner` as RRSIG counts them, without the root or a leading
+/// wildcard (RFC 4034 section 3.1.3).
+fn label_count(owner: &str) -> usize {
+    let labels = owner.split('.').filter(|l| !l.is_empty());
+    labels.clone().count() - usize::from(owner.starts_with("*."))
+}
+
+/// Whether a type only carries DNSSEC data, which clients get only when
+/// they ask for it with the DO bit.
+pub fn is_dnssec_type(qtype: QueryType) -> bool {
+    matches!(qtype, QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3)
+}
+
+/// Parses a DS record in master file format, as given for a trust anchor.
+fn parse_ds(text: &str) -> Result<DnsRecord, String> {
+    let fields: Vec<&str> = text.split_whitespace().collect();
+    let invalid = || format!("invalid trust anchor: {text}");
+
+    let ds = fields.iter().position(|f| f.eq_ignore_ascii_case("DS"));
+    let (Some(owner), Some(ds)) = (fields.first(), ds) else {
+        return Err(invalid());
+    };
+    let [key_tag, algorithm, digest_type, digest @ ..] = &fields[ds + 1..] else {
+        return Err(invalid());
+    };
+
+    let digest = parse_hex(&digest.concat()).ok_or_else(invalid)?;
+
+    Ok(DnsRecord::DS {
+        domain: normalize_name(owner),
+        key_tag: key_tag.parse().map_err(|_| invalid())?,
+        algorithm: algorithm.parse().map_err(|_| invalid())?,
+        digest_type: digest_type.parse().map_err(|_| invalid())?,
+        digest,
+        ttl: 0,
+    })
+}
+
+fn unix_now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as u32
+}
+
+fn find_soa(records: &[DnsRecord]) -> Option<&DnsRecord> {
+    records
+        .iter()
+        .find(|rec| matches!(rec, DnsRecord::SOA { .. }))
+}
+
+fn min_ttl(records: &[&DnsRecord]) -> u32 {
+    records.iter().map(|rec| rec.ttl()).min().unwrap_or(0)
+}
+
+/// The distinct owner and type pairs of the signed-for records in `records`.
+fn rrsets(records: &[DnsRecord]) -> Vec<(String, QueryType)> {
+    let mut sets: Vec<(String, QueryType)> = Vec::new();
+    for rec in records {
+        let key = (rec.domain().to_string(), rec.qtype());
+        if key.1 != QueryType::RRSIG && !sets.contains(&key) {
+            sets.push(key);
+        }
+    }
+
+    sets
+}
+
+/// Picks the records of one set out of `records`, along with the
+/// signatures covering them.
+fn rrset<'a>(
+    records: &'a [DnsRecord],
+    owner: &str,
+    rtype: QueryType,
+) -> (Vec<&'a DnsRecord>, Vec<&'a DnsRecord>) {
+    let owned = records.iter().filter(|rec| rec.domain() == owner);
+
+    let set = owned.clone().filter(|rec| rec.qtype() == rtype).collect();
+    let sigs = owned
+        .filter(
+            |rec| matches!(rec, DnsRecord::RRSIG { type_covered, .. } if *type_covered == rtype),
+        )
+        .collect();
+
+    (set, sigs)
+}
+
+/// Checks that one of `sigs` is a currently valid signature over `set` by
+/// one of `keys` (RFC 4035 section 5.3), returning the one that is.
+fn verify_rrset<'a>(
+    set: &[&DnsRecord],
+    sigs: &[&'a DnsRecord],
+    keys: &[DnsRecord],
+    now: u32,
+) -> Result<&'a DnsRecord, String> {
+    let Some(first) = set.first() else {
+        return Err("no records to verify".to_string());
+    };
+    let (owner, rtype) = (first.domain(), first.qtype());
+
+    let mut error = "no signature";
+    for sig in sigs {
+        let DnsRecord::RRSIG {
+            algorithm,
+            expiration,
+            inception,
+            key_tag: tag,
+            signer,
+            signature,
+            ..
+        } = sig
+        else {
+            continue;
+        };
+
+        if !is_supported_algorithm(*algorithm) {
+            error = "no signature with a supported algorithm";
+            continue;
+        }
+        if !serial_le(*inception, now) || !serial_le(now, *expiration) {
+            error = "signature expired or not yet valid";
+            continue;
+        }
+
+        let data = signed_data(sig, set)?;
+        let verified = keys.iter().any(|key| match key {
+            DnsRecord::DNSKEY {
+                domain,
+                flags,
+                protocol: 3,
+                algorithm: key_algorithm,
+                public_key,
+                ..
+            } => {
+                domain == signer
+                    && flags & ZONE_KEY_FLAG != 0
+                    && key_algorithm == algorithm
+                    && key_tag(key) == *tag
+                    && verify_signature(*algorithm, public_key, &data, signature)
+            }
+            _ => false,
+        });
+        if verified {
+            return Ok(sig);
+        }
+        error = "signature doesn't verify";
+    }
+
+    Err(format!("{owner} {rtype}: {error}"))
+}
+
+/// Compares RRSIG timestamps in serial number arithmetic (RFC 1982), so that
+/// they keep working after 2106.
+fn serial_le(a: u32, b: u32) -> bool {
+    b.wrapping_sub(a) < 1 << 31
+}
+
+/// Builds the data an RRSIG signs: its own RDATA up to the signature,
+/// followed by the set in canonical form and order (RFC 4034 section 3.1.8.1).
+fn signed_data(sig: &DnsRecord, set: &[&DnsRecord]) -> Result<Vec<u8>, String> {
+    let DnsRecord::RRSIG {
+        type_covered,
+        algorithm,
+        labels,
+        original_ttl,
+        expiration,
+        inception,
+        key_tag,
+        signer,
+        ..
+    } = sig
+    else {
+        return Err("not a signature".to_string());
+    };
+
+    let mut buffer = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+    buffer.write_u16(type_covered.to_num())?;
+    buffer.write_u8(*algorithm)?;
+    buffer.write_u8(*labels)?;
+    buffer.write_u32(*original_ttl)?;
+    buffer.write_u32(*expiration)?;
+    buffer.write_u32(*inception)?;
+    buffer.write_u16(*key_tag)?;
+    buffer.write_qname(signer)?;
+
+    // A wildcard answer is signed under the wildcard's own name.
+    let owner = set[0].domain();
+    let owner_labels: Vec<&str> = owner.split('.').filter(|l| !l.is_empty()).collect();
+    let owner = if (*labels as usize) < owner_labels.len() {
+        let closest = &owner_labels[owner_labels.len() - *labels as usize..];
+        std::iter::once("*")
+            .chain(closest.iter().copied())
+            .collect::<Vec<_>>()
+            .join(".")
+    } else {
+        owner.to_string()
+    };
+
+    let mut records = Vec::new();
+    for rec in set {
+        let rec = rec.with_domain(&owner);
+        records.push((rec.canonical_rdata()?, rec.to_canonical(*original_ttl)?));
+    }
+    records.sort();
+    records.dedup();
+
+    let mut data = buffer.buf[..buffer.pos()].to_vec();
+    for (_, rr) in records {
+        data.extend_from_slice(&rr);
+    }
+
+    Ok(data)
+}
+
+/// Computes the tag that identifies a key in DS and RRSIG records
+/// (RFC 4034 appendix B).
+fn key_tag(key: &DnsRecord) -> u16 {
+    let mut acc: u32 = 0;
+    for (i, byte) in key.canonical_rdata().unwrap_or_default().iter().enumerate() {
+        acc += if i % 2 == 0 {
+            (*byte as u32) << 8
+        } else {
+            *byte as u32
+        };
+    }
+    acc += (acc >> 16) & 0xFFFF;
+
+    (acc & 0xFFFF) as u16
+}
+
+fn digest_algorithm(digest_type: u8) -> Option<&'static digest::Algorithm> {
+    match digest_type {
+        1 => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        2 => Some(&digest::SHA256),
+        4 => Some(&digest::SHA384),
+        _ => None,
+    }
+}
+
+/// Whether `ds` is a digest of `key` (RFC 4034 section 5.1.4).
+fn ds_matches(ds: &DnsRecord, key: &DnsRecord) -> bool {
+    let (
+        DnsRecord::DS {
+            domain,
+            key_tag: tag,
+            algorithm,
+            digest_type,
+            digest,
+            ..
+        },
+        DnsRecord::DNSKEY {
+            domain: key_domain,
+            algorithm: key_algorithm,
+            ..
+        },
+    ) = (ds, key)
+    else {
+        return false;
+    };
+
+    let Some(digest_algorithm) = digest_algorithm(*digest_type) else {
+        return false;
+    };
+    if domain != key_domain || algorithm != key_algorithm || *tag != key_tag(key) {
+        return false;
+    }
+
+    let mut owner = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+    if owner.write_qname(domain).is_err() {
+        return false;
+    }
+
+    let mut context = digest::Context::new(digest_algorithm);
+    context.update(&owner.buf[..owner.pos()]);
+    context.update(&key.canonical_rdata().unwrap_or_default());
+
+    context.finish().as_ref() == digest.as_slice()
+}
+
+/// RSA/SHA-256, ECDSA P-256 and P-384, and Ed25519.
+fn is_supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 8 | 13 | 14 | 15)
+}
+
+fn verify_signature(algorithm: u8, public_key: &[u8], data: &[u8], sig: &[u8]) -> bool {
+    match algorithm {
+        8 => {
+            // Exponent length, in one byte or, if that is zero, two more
+            // (RFC 3110 section 2).
+            let (e_len, rest) = match public_key {
+                [0, hi, lo, rest @ ..] => (u16::from_be_bytes([*hi, *lo]) as usize, rest),
+                [len, rest @ ..] => (*len as usize, rest),
+                [] => return false,
+            };
+            if rest.len() <= e_len {
+                return false;
+            }
+            let (e, n) = rest.split_at(e_len);
+            let n = &n[n.iter().take_while(|b| **b == 0).count()..];
+
+            // Zones still sign with 1024 bit keys, which ring only allows
+            // under this name.
+            signature::RsaPublicKeyComponents { n, e }
+                .verify(
+                    &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                    data,
+                    sig,
+                )
+                .is_ok()
+        }
+        13 | 14 => {
+            let algorithm = if algorithm == 13 {
+                &signature::ECDSA_P256_SHA256_FIXED
+            } else {
+                &signature::ECDSA_P384_SHA384_FIXED
+            };
+
+            // DNSKEY holds the bare point, ring wants it uncompressed.
+            let mut point = Vec::with_capacity(public_key.len() + 1);
+            point.push(4);
+            point.extend_from_slice(public_key);
+
+            signature::UnparsedPublicKey::new(algorithm, point)
+                .verify(data, sig)
+                .is_ok()
+        }
+        15 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(data, sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+    use super::*;
+
+    // KSK-2017, the root zone key the first of ROOT_ANCHORS is a digest of.
+    const ROOT_KSK: &str = "AwEAAaz/tAm8yTn4Mfeh5eyI96WSVexTBAvkMgJzkKTOiW1vkIbzxeF3+/4RgWOq7HrxRixHlFlExOLAJr5emLvN7SWXgnLh4+B5xQlNVz8Og8kvArMtNROxVQuCaSnIDdD5LKyWbRd2n9WGe2R8PzgCmr3EgVLrjyBxWezF0jLHwVN8efS3rCj/EWgvIWgb9tarpVUDK/b58Da+sqqls3eNbuv7pr+eoZG+SrDK6nWeL3c6H5Apxz7LjVc1uTIdsIXxuOLYA4/ilBmSVIzuDWfdRUfhHdY6+cn8HFRm+2hM8AnXGXws9555KrUB5qihylGa8subX2Nn6UwNR1AkUTV74bU=";
+
+    // The key of the DS example in RFC 4034 section 5.4.
+    const RFC_4034_KEY: &str = "AQOeiiR0GOMYkDshWoSKz9XzfwJr1AYtsmx3TGkJaNXVbfi/2pHm822aJ5iI9BMzNXxeYCmZDRD99WYwYqUSdjMmmAphXdvxegXd/M5+X7OrzKBaMbCVdFLUUh6DhweJBjEVv5f2wwjM9XzcnOf+EPbtG9DMBmADjFDc2w/rljwvFw==";
+
+    fn dnskey(domain: &str, flags: u16, algorithm: u8, key: &str) -> DnsRecord {
+        DnsRecord::DNSKEY {
+            domain: domain.to_string(),
+            flags,
+            protocol: 3,
+            algorithm,
+            public_key: BASE64.decode(key).unwrap(),
+            ttl: 3600,
+        }
+    }
+
+    /// The Ed25519 example of RFC 8080 section 6.1: a key, the MX set it
+    /// signs and the signature.
+    fn rfc_8080_example() -> (DnsRecord, DnsRecord, DnsRecord) {
+        let key = dnskey(
+            "example.com",
+            257,
+            15,
+            "l02Woi0iS8Aa25FQkUd9RMzZHJpBoRQwAQEX1SxZJA4=",
+        );
+        let mx = DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 10,
+            host: "mail.example.com".to_string(),
+            ttl: 3600,
+        };
+        let sig = DnsRecord::RRSIG {
+            domain: "example.com".to_string(),
+            type_covered: QueryType::MX,
+            algorithm: 15,
+            labels: 2,
+            original_ttl: 3600,
+            expiration: 1_440_021_600,
+            inception: 1_438_207_200,
+            key_tag: 3613,
+            signer: "example.com".to_string(),
+            signature: BASE64
+                .decode("oL9krJun7xfBOIWcGHi7mag5/hdZrKWw15jPGrHpjQeRAvTdszaPD+QLs3fx8A4M3e23mRZ9VrbpMngwcrqNAg==")
+                .unwrap(),
+            ttl: 3600,
+        };
+
+        (key, mx, sig)
+    }
+
+    #[test]
+    fn key_tags_match_published_values() {
+        let key = dnskey("dskey.example.com", 256, 5, RFC_4034_KEY);
+        assert_eq!(key_tag(&key), 60485);
+
+        assert_eq!(key_tag(&dnskey("", 257, 8, ROOT_KSK)), 20326);
+        assert_eq!(key_tag(&rfc_8080_example().0), 3613);
+    }
+
+    #[test]
+    fn ds_digests_match_their_keys() {
+        let root = dnskey("", 257, 8, ROOT_KSK);
+        let anchor = parse_ds(ROOT_ANCHORS[0]).unwrap();
+        assert!(ds_matches(&anchor, &root));
+        assert!(!ds_matches(&parse_ds(ROOT_ANCHORS[1]).unwrap(), &root));
+
+        // A SHA-1 digest, split in two as master files may.
+        let key = dnskey("dskey.example.com", 256, 5, RFC_4034_KEY);
+        let ds = parse_ds(
+            "dskey.example.com. 86400 IN DS 60485 5 1 2BB183AF5F2258817 9A53B0A98631FAD1A292118",
+        )
+        .unwrap();
+        assert!(ds_matches(&ds, &key));
+
+        // The same key under another owner has another digest.
+        let moved = key.with_domain("other.example.com");
+        let ds =
+            parse_ds("other.example.com. IN DS 60485 5 1 2BB183AF5F22588179A53B0A98631FAD1A292118")
+                .unwrap();
+        assert!(!ds_matches(&ds, &moved));
+    }
+
+    #[test]
+    fn parse_ds_rejects_malformed_anchors() {
+        for text in [
+            "",
+            ". IN DS 20326 8 2",
+            ". IN DS 20326 8 2 E06D4",
+            ". IN DS 20326 8 2 ZZ",
+            ". IN DS 70000 8 2 E06D",
+            ". IN DNSKEY 257 3 8 AwEAAaz",
+        ] {
+            assert!(parse_ds(text).is_err(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn signed_data_matches_rfc_8080_example() {
+        let (_, mx, sig) = rfc_8080_example();
+
+        let expected = "000f0f0200000e1055d4fc6055b94ce00e1d076578616d706c6503636f6d00\
+                        076578616d706c6503636f6d00000f000100000e100014000a046d61696c07\
+                        6578616d706c6503636f6d00";
+        let data: String = signed_data(&sig, &[&mx])
+            .unwrap()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn signed_data_uses_the_wildcard_owner() {
+        let (_, mx, sig) = rfc_8080_example();
+        let expanded = mx.with_domain("a.b.example.com");
+        let wildcard = mx.with_domain("*.example.com");
+
+        assert_eq!(
+            signed_data(&sig, &[&expanded]).unwrap(),
+            signed_data(&sig, &[&wildcard]).unwrap()
+        );
+        assert_ne!(
+            signed_data(&sig, &[&expanded]).unwrap(),
+            signed_data(&sig, &[&mx]).unwrap()
+        );
+    }
+
+    #[test]
+    fn verifies_rfc_8080_signature() {
+        let (key, mx, sig) = rfc_8080_example();
+        let keys = [key];
+        let now = 1_439_000_000;
+
+        assert!(verify_rrset(&[&mx], &[&sig], &keys, now).is_ok());
+
+        // Outside the validity period.
+        assert!(verify_rrset(&[&mx], &[&sig], &keys, 1_440_021_601).is_err());
+        assert!(verify_rrset(&[&mx], &[&sig], &keys, 1_438_207_199).is_err());
+
+        // Changed data.
+        let forged = DnsRecord::MX {
+            domain: "example.com".to_string(),
+            priority: 20,
+            host: "mail.example.com".to_string(),
+            ttl: 3600,
+        };
+        assert!(verify_rrset(&[&forged], &[&sig], &keys, now).is_err());
+
+        // A key that isn't a zone key can't sign.
+        let DnsRecord::DNSKEY { public_key, .. } = &keys[0] else {
+            unreachable!()
+        };
+        let not_zone_key = DnsRecord::DNSKEY {
+            domain: "example.com".to_string(),
+            flags: 1,
+            protocol: 3,
+            algorithm: 15,
+            public_key: public_key.clone(),
+            ttl: 3600,
+        };
+        assert!(verify_rrset(&[&mx], &[&sig], &[not_zone_key], now).is_err());
+    }
+
+    #[test]
+    fn compares_timestamps_across_the_wrap() {
+        assert!(serial_le(1, 2));
+        assert!(serial_le(5, 5));
+        assert!(!serial_le(2, 1));
+        assert!(serial_le(u32::MAX - 10, 10));
+        assert!(!serial_le(10, u32::MAX - 10));
+    }
+
+    #[test]
+    fn counts_labels_and_follows_cnames() {
+        assert_eq!(label_count("www.example.com"), 3);
+        assert_eq!(label_count("*.example.com"), 2);
+        assert_eq!(label_count(""), 0);
+
+        let cname = |domain: &str, host: &str| DnsRecord::CNAME {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 60,
+        };
+        let answers = [
+            cname("a.example", "b.example"),
+            cname("b.example", "c.example"),
+        ];
+        assert_eq!(
+            cname_target("a.example", QueryType::A, &answers),
+            "c.example"
+        );
+        assert_eq!(
+            cname_target("a.example", QueryType::CNAME, &answers),
+            "a.example"
+        );
+
+        let looped = [
+            cname("a.example", "b.example"),
+            cname("b.example", "a.example"),
+        ];
+        assert_eq!(
+            cname_target("a.example", QueryType::A, &looped),
+            "a.example"
+        );
+    }
+
+    #[test]
+    fn combines_results_weakest_first() {
+        let bogus = || Security::Bogus("x".to_string());
+        assert_eq!(
+            combine(Security::Secure, Security::Secure),
+            Security::Secure
+        );
+        assert_eq!(
+            combine(Security::Secure, Security::Insecure),
+            Security::Insecure
+        );
+        assert_eq!(combine(Security::Insecure, bogus()), bogus());
+        assert_eq!(combine(bogus(), Security::Secure), bogus());
+    }
+}
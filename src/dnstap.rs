@@ -0,0 +1,555 @@
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::UnixStream,
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{config::DnstapConfig, util::Transport, DNSTAP, METRICS};
+
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// Frame Streams control frame types and fields.
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+
+// How long to wait before reconnecting to a socket that went away.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// dnstap message types, as numbered in dnstap.proto.
+#[derive(Debug, Clone, Copy)]
+enum MessageType {
+    ResolverQuery = 3,
+    ResolverResponse = 4,
+    ClientQuery = 5,
+    ClientResponse = 6,
+}
+
+fn socket_protocol(transport: Transport) -> u64 {
+    match transport {
+        Transport::Udp => 1,
+        Transport::Tcp => 2,
+        Transport::Tls => 3,
+        Transport::Https => 4,
+        Transport::Quic => 7,
+    }
+}
+
+/// One dnstap `Message`, borrowing the wire data it carries.
+struct Message<'a> {
+    kind: MessageType,
+    protocol: u64,
+    query_address: Option<SocketAddr>,
+    response_address: Option<SocketAddr>,
+    query_time: Option<SystemTime>,
+    query_message: Option<&'a [u8]>,
+    response_time: Option<SystemTime>,
+    response_message: Option<&'a [u8]>,
+}
+
+impl Message<'_> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_uint(out, 1, self.kind as u64);
+
+        if let Some(addr) = self.query_address.or(self.response_address) {
+            put_uint(out, 2, if addr.is_ipv4() { 1 } else { 2 });
+        }
+        put_uint(out, 3, self.protocol);
+
+        if let Some(addr) = self.query_address {
+            put_bytes(out, 4, &ip_bytes(addr.ip()));
+            put_uint(out, 6, addr.port() as u64);
+        }
+        if let Some(addr) = self.response_address {
+            put_bytes(out, 5, &ip_bytes(addr.ip()));
+            put_uint(out, 7, addr.port() as u64);
+        }
+
+        if let Some(time) = self.query_time {
+            put_time(out, 8, 9, time);
+        }
+        if let Some(message) = self.query_message {
+            put_bytes(out, 10, message);
+        }
+        if let Some(time) = self.response_time {
+            put_time(out, 12, 13, time);
+        }
+        if let Some(message) = self.response_message {
+            put_bytes(out, 14, message);
+        }
+    }
+}
+
+/// Where frames are written to.
+#[derive(Debug, Clone)]
+enum Output {
+    Socket(PathBuf),
+    File(PathBuf),
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::Socket(path) => write!(f, "socket {}", path.display()),
+            Output::File(path) => write!(f, "file {}", path.display()),
+        }
+    }
+}
+
+/// Sends dnstap frames to a background writer. Frames are dropped, not
+/// waited on, when the queue is full.
+#[derive(Debug)]
+pub struct Dnstap {
+    sender: mpsc::Sender<Vec<u8>>,
+    stop: CancellationToken,
+    writer: Mutex<Option<JoinHandle<()>>>,
+    identity: Option<String>,
+    client: bool,
+    resolver: bool,
+}
+
+impl Dnstap {
+    /// Starts the writer, or returns `None` when no output is configured.
+    pub fn start(config: &DnstapConfig) -> Result<Option<Dnstap>, String> {
+        let output = match (&config.socket, &config.file) {
+            (Some(_), Some(_)) => return Err("dnstap takes a socket or a file, not both".into()),
+            (Some(path), None) => Output::Socket(path.into()),
+            (None, Some(path)) => Output::File(path.into()),
+            (None, None) => return Ok(None),
+        };
+
+        if config.queue_size == 0 {
+            return Err("dnstap queue_size must be positive".into());
+        }
+
+        let (sender, receiver) = mpsc::channel(config.queue_size);
+        info!("writing dnstap to {output}");
+        let stop = CancellationToken::new();
+        let writer = tokio::spawn(run_writer(output, receiver, stop.clone()));
+
+        Ok(Some(Dnstap {
+            sender,
+            stop,
+            writer: Mutex::new(Some(writer)),
+            identity: config.identity.clone(),
+            client: config.client,
+            resolver: config.resolver,
+        }))
+    }
+
+    /// Writes out the frames still queued, ends the stream and waits up to
+    /// `limit` for the writer to finish, aborting it after that. Frames sent
+    /// afterwards are discarded.
+    pub async fn finish(&self, limit: Duration) {
+        self.stop.cancel();
+
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(mut writer) = writer {
+            if timeout(limit, &mut writer).await.is_err() {
+                warn!("dnstap writer didn't finish in time, dropping queued frames");
+                writer.abort();
+            }
+        }
+    }
+
+    fn send(&self, message: Message) {
+        let mut encoded = Vec::new();
+        message.encode(&mut encoded);
+
+        let mut frame = Vec::with_capacity(encoded.len() + 64);
+        if let Some(identity) = &self.identity {
+            put_bytes(&mut frame, 1, identity.as_bytes());
+        }
+        put_bytes(
+            &mut frame,
+            2,
+            concat!("dns-server ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        );
+        put_bytes(&mut frame, 14, &encoded);
+        // Dnstap.Type MESSAGE
+        put_uint(&mut frame, 15, 1);
+
+        match self.sender.try_send(frame) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => METRICS.dnstap_dropped.inc(&[]),
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Records a query as received from a client.
+pub fn client_query(client: SocketAddr, transport: Transport, query: &[u8], time: SystemTime) {
+    let Some(tap) = DNSTAP.get().filter(|tap| tap.client) else {
+        return;
+    };
+
+    tap.send(Message {
+        kind: MessageType::ClientQuery,
+        protocol: socket_protocol(transport),
+        query_address: Some(client),
+        response_address: None,
+        query_time: Some(time),
+        query_message: Some(query),
+        response_time: None,
+        response_message: None,
+    });
+}
+
+/// Records a response as sent back to a client.
+pub fn client_response(
+    client: SocketAddr,
+    transport: Transport,
+    query_time: SystemTime,
+    response: &[u8],
+) {
+    let Some(tap) = DNSTAP.get().filter(|tap| tap.client) else {
+        return;
+    };
+
+    tap.send(Message {
+        kind: MessageType::ClientResponse,
+        protocol: socket_protocol(transport),
+        query_address: Some(client),
+        response_address: None,
+        query_time: Some(query_time),
+        query_message: None,
+        response_time: Some(SystemTime::now()),
+        response_message: Some(response),
+    });
+}
+
+/// Records a query sent to an upstream name server.
+pub fn resolver_query(server: SocketAddr, transport: Transport, query: &[u8], time: SystemTime) {
+    let Some(tap) = DNSTAP.get().filter(|tap| tap.resolver) else {
+        return;
+    };
+
+    tap.send(Message {
+        kind: MessageType::ResolverQuery,
+        protocol: socket_protocol(transport),
+        query_address: None,
+        response_address: Some(server),
+        query_time: Some(time),
+        query_message: Some(query),
+        response_time: None,
+        response_message: None,
+    });
+}
+
+/// Records a response received from an upstream name server.
+pub fn resolver_response(
+    server: SocketAddr,
+    transport: Transport,
+    query_time: SystemTime,
+    response: &[u8],
+) {
+    let Some(tap) = DNSTAP.get().filter(|tap| tap.resolver) else {
+        return;
+    };
+
+    tap.send(Message {
+        kind: MessageType::ResolverResponse,
+        protocol: socket_protocol(transport),
+        query_address: None,
+        response_address: Some(server),
+        query_time: Some(query_time),
+        query_message: None,
+        response_time: Some(SystemTime::now()),
+        response_message: Some(response),
+    });
+}
+
+type Writer = BufWriter<Box<dyn AsyncWrite + Unpin + Send>>;
+
+/// Writes queued frames until `stop` is cancelled, reconnecting to the socket
+/// whenever the consumer disconnects.
+async fn run_writer(
+    output: Output,
+    mut receiver: mpsc::Receiver<Vec<u8>>,
+    stop: CancellationToken,
+) {
+    loop {
+        match open(&output).await {
+            Ok(mut writer) => match write_frames(&mut writer, &mut receiver, &stop).await {
+                Ok(()) => return,
+                Err(e) => warn!("dnstap {output} failed: {e}"),
+            },
+            Err(e) => warn!("failed to open dnstap {output}: {e}"),
+        }
+
+        if let Output::File(_) = output {
+            return;
+        }
+        tokio::select! {
+            _ = sleep(RECONNECT_DELAY) => {}
+            _ = stop.cancelled() => return,
+        }
+    }
+}
+
+/// Opens the output and starts a stream on it. Sockets get the bidirectional
+/// Frame Streams handshake; files are unidirectional.
+async fn open(output: &Output) -> io::Result<Writer> {
+    let mut writer: Writer = match output {
+        Output::Socket(path) => {
+            let mut stream = UnixStream::connect(path).await?;
+
+            write_control(&mut stream, CONTROL_READY).await?;
+            let kind = read_control(&mut stream).await?;
+            if kind != CONTROL_ACCEPT {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("expected ACCEPT, got control frame {kind}"),
+                ));
+            }
+
+            BufWriter::new(Box::new(stream))
+        }
+        Output::File(path) => BufWriter::new(Box::new(File::create(path).await?)),
+    };
+
+    write_control(&mut writer, CONTROL_START).await?;
+    writer.flush().await?;
+
+    Ok(writer)
+}
+
+async fn write_frames(
+    writer: &mut Writer,
+    receiver: &mut mpsc::Receiver<Vec<u8>>,
+    stop: &CancellationToken,
+) -> io::Result<()> {
+    loop {
+        let frame = tokio::select! {
+            frame = receiver.recv() => frame,
+            _ = stop.cancelled() => None,
+        };
+
+        let Some(frame) = frame else {
+            while let Ok(frame) = receiver.try_recv() {
+                write_frame(writer, &frame).await?;
+            }
+            write_control(writer, CONTROL_STOP).await?;
+            return writer.flush().await;
+        };
+        write_frame(writer, &frame).await?;
+
+        // Batch up whatever else is queued before flushing.
+        while let Ok(frame) = receiver.try_recv() {
+            write_frame(writer, &frame).await?;
+        }
+        writer.flush().await?;
+    }
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8]) -> io::Result<()> {
+    writer.write_u32(frame.len() as u32).await?;
+    writer.write_all(frame).await
+}
+
+/// Writes a control frame; all but STOP carry the content type.
+async fn write_control<W: AsyncWrite + Unpin>(writer: &mut W, kind: u32) -> io::Result<()> {
+    let mut payload = kind.to_be_bytes().to_vec();
+    if kind != CONTROL_STOP {
+        payload.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+        payload.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        payload.extend_from_slice(CONTENT_TYPE);
+    }
+
+    // A zero length marks a control frame.
+    writer.write_u32(0).await?;
+    writer.write_u32(payload.len() as u32).await?;
+    writer.write_all(&payload).await
+}
+
+/// Reads a control frame and returns its type.
+async fn read_control<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<u32> {
+    let escape = reader.read_u32().await?;
+    let len = reader.read_u32().await? as usize;
+    if escape != 0 || !(4..=512).contains(&len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed control frame",
+        ));
+    }
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(u32::from_be_bytes([
+        payload[0], payload[1], payload[2], payload[3],
+    ]))
+}
+
+// Protocol buffers encoding of the few field kinds dnstap uses.
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_uint(out: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(out, field << 3);
+    put_varint(out, value);
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u64, data: &[u8]) {
+    put_varint(out, (field << 3) | 2);
+    put_varint(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+fn put_fixed32(out: &mut Vec<u8>, field: u64, value: u32) {
+    put_varint(out, (field << 3) | 5);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_time(out: &mut Vec<u8>, sec_field: u64, nsec_field: u64, time: SystemTime) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    put_uint(out, sec_field, since_epoch.as_secs());
+    put_fixed32(out, nsec_field, since_epoch.subsec_nanos());
+}
+
+fn ip_bytes(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn varint(value: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        put_varint(&mut out, value);
+        out
+    }
+
+    #[test]
+    fn encodes_protobuf_fields() {
+        // Examples from the protocol buffers encoding guide.
+        assert_eq!(varint(1), [0x01]);
+        assert_eq!(varint(150), [0x96, 0x01]);
+        assert_eq!(varint(300), [0xac, 0x02]);
+        assert_eq!(varint(u64::MAX).len(), 10);
+
+        let mut out = Vec::new();
+        put_uint(&mut out, 1, 150);
+        assert_eq!(out, [0x08, 0x96, 0x01]);
+
+        let mut out = Vec::new();
+        put_bytes(&mut out, 2, b"testing");
+        assert_eq!(out, b"\x12\x07testing");
+
+        let mut out = Vec::new();
+        put_fixed32(&mut out, 9, 0x0102_0304);
+        assert_eq!(out, [0x4d, 0x04, 0x03, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn numbers_transports_as_dnstap_proto() {
+        assert_eq!(socket_protocol(Transport::Udp), 1);
+        assert_eq!(socket_protocol(Transport::Tcp), 2);
+        assert_eq!(socket_protocol(Transport::Tls), 3);
+        assert_eq!(socket_protocol(Transport::Https), 4);
+        assert_eq!(socket_protocol(Transport::Quic), 7);
+    }
+
+    #[test]
+    fn encodes_client_query_message() {
+        let message = Message {
+            kind: MessageType::ClientQuery,
+            protocol: socket_protocol(Transport::Udp),
+            query_address: Some("192.0.2.1:5353".parse().unwrap()),
+            response_address: None,
+            query_time: Some(UNIX_EPOCH + Duration::new(1_700_000_000, 5)),
+            query_message: Some(b"\xab\xcd"),
+            response_time: None,
+            response_message: None,
+        };
+        let mut out = Vec::new();
+        message.encode(&mut out);
+
+        let expected = [
+            &[0x08, 0x05][..],                     // type CLIENT_QUERY
+            &[0x10, 0x01],                         // socket_family INET
+            &[0x18, 0x01],                         // socket_protocol UDP
+            &[0x22, 0x04, 192, 0, 2, 1],           // query_address
+            &[0x30, 0xe9, 0x29],                   // query_port 5353
+            &[0x40, 0x80, 0xe2, 0xcf, 0xaa, 0x06], // query_time_sec
+            &[0x4d, 0x05, 0x00, 0x00, 0x00],       // query_time_nsec
+            &[0x52, 0x02, 0xab, 0xcd],             // query_message
+        ]
+        .concat();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn encodes_resolver_response_over_ipv6() {
+        let message = Message {
+            kind: MessageType::ResolverResponse,
+            protocol: socket_protocol(Transport::Tcp),
+            query_address: None,
+            response_address: Some("[2001:db8::53]:53".parse().unwrap()),
+            query_time: None,
+            query_message: None,
+            response_time: Some(UNIX_EPOCH + Duration::from_secs(1)),
+            response_message: Some(b"\x01"),
+        };
+        let mut out = Vec::new();
+        message.encode(&mut out);
+
+        let mut expected = vec![0x08, 0x04, 0x10, 0x02, 0x18, 0x02, 0x2a, 0x10];
+        expected.extend_from_slice(&"2001:db8::53".parse::<Ipv6Addr>().unwrap().octets());
+        expected.extend_from_slice(&[0x38, 0x35]);
+        expected.extend_from_slice(&[0x60, 0x01, 0x6d, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[0x72, 0x01, 0x01]);
+        assert_eq!(out, expected);
+    }
+
+    #[tokio::test]
+    async fn writes_and_reads_control_frames() {
+        let mut out = Vec::new();
+        write_control(&mut out, CONTROL_START).await.unwrap();
+
+        let mut expected = vec![
+            0, 0, 0, 0, 0, 0, 0, 0x22, 0, 0, 0, 0x02, 0, 0, 0, 0x01, 0, 0, 0, 0x16,
+        ];
+        expected.extend_from_slice(CONTENT_TYPE);
+        assert_eq!(out, expected);
+        assert_eq!(
+            read_control(&mut out.as_slice()).await.unwrap(),
+            CONTROL_START
+        );
+
+        let mut out = Vec::new();
+        write_control(&mut out, CONTROL_STOP).await.unwrap();
+        assert_eq!(out, [0, 0, 0, 0, 0, 0, 0, 0x04, 0, 0, 0, 0x03]);
+
+        // A data frame where a control frame belongs.
+        let mut data: &[u8] = &[0, 0, 0, 4, 1, 2, 3, 4];
+        assert!(read_control(&mut data).await.is_err());
+    }
+}
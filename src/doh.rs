@@ -0,0 +1,289 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::Incoming,
+    header::{self, HeaderValue},
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use tokio::{
+    net::TcpListener,
+    sync::Semaphore,
+    time::{sleep_until, timeout, Instant},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, warn};
+
+use crate::{
+    buffer::{BytePacketBuffer, MAX_MESSAGE_SIZE},
+    config::DohConfig,
+    dns::{DnsPacket, HEADER_SIZE},
+    util::{handle_query, Transport},
+    QUERY_LIMITER,
+};
+
+// ALPN protocol names for HTTP/2 and HTTP/1.1, in order of preference.
+pub const ALPN: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+// The path queries are taken at (RFC 8484 section 4.1).
+const PATH: &str = "/dns-query";
+
+// Media type of DNS messages in requests and responses.
+const DNS_MESSAGE: &str = "application/dns-message";
+
+// How long a client gets to complete the TLS handshake, and to send the
+// headers of an HTTP/1.1 request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests in flight on a connection and when it last finished one, to
+/// close connections that sit idle.
+struct Activity {
+    in_flight: AtomicUsize,
+    last_active: Mutex<Instant>,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            in_flight: AtomicUsize::new(0),
+            last_active: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Counts a request as in flight until the guard is dropped, which also
+    /// covers streams the client resets.
+    fn start(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self)
+    }
+
+    /// When the connection will have been idle for `idle_timeout`, if
+    /// nothing happens on it before then.
+    fn idle_at(&self, idle_timeout: Duration) -> Instant {
+        if self.in_flight.load(Ordering::SeqCst) > 0 {
+            return Instant::now() + idle_timeout;
+        }
+        *self.last_active.lock().unwrap() + idle_timeout
+    }
+}
+
+struct InFlight<'a>(&'a Activity);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        *self.0.last_active.lock().unwrap() = Instant::now();
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves queries over HTTPS (RFC 8484) at `/dns-query` until `shutdown` is
+/// cancelled, over HTTP/2 or HTTP/1.1. Connections beyond the configured
+/// limit are closed straight away. Connections run on `tracker`.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    config: DohConfig,
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+) {
+    let idle_timeout = Duration::from_secs(config.idle_timeout);
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return,
+        };
+
+        let (stream, src) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("failed to accept HTTPS connection: {e}");
+                continue;
+            }
+        };
+
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            debug!("refusing HTTPS connection from {src}: too many connections");
+            continue;
+        };
+
+        let acceptor = acceptor.clone();
+        let shutdown = shutdown.clone();
+        tracker.spawn(async move {
+            let _permit = permit;
+
+            let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {src} failed: {e}");
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {src} timed out");
+                    return;
+                }
+            };
+
+            let mut builder = auto::Builder::new(TokioExecutor::new());
+            builder
+                .http1()
+                .timer(TokioTimer::new())
+                .header_read_timeout(HEADER_READ_TIMEOUT);
+
+            let activity = Arc::new(Activity::new());
+            let service = {
+                let activity = activity.clone();
+                service_fn(move |request| {
+                    let activity = activity.clone();
+                    async move {
+                        let _in_flight = activity.start();
+                        handle_request(request, src).await
+                    }
+                })
+            };
+            let conn = builder.serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(conn);
+
+            let result = loop {
+                tokio::select! {
+                    result = conn.as_mut() => break result,
+                    _ = shutdown.cancelled() => {
+                        conn.as_mut().graceful_shutdown();
+                        break conn.await;
+                    }
+                    _ = sleep_until(activity.idle_at(idle_timeout)) => {
+                        if activity.idle_at(idle_timeout) <= Instant::now() {
+                            debug!("closing idle HTTPS connection from {src}");
+                            conn.as_mut().graceful_shutdown();
+                            break conn.await;
+                        }
+                    }
+                }
+            };
+            if let Err(e) = result {
+                debug!("HTTPS connection from {src} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    request: Request<Incoming>,
+    src: SocketAddr,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if request.uri().path() != PATH {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+
+    let query = match *request.method() {
+        Method::GET => {
+            // The query is the `dns` parameter, in unpadded base64url.
+            let param = request
+                .uri()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("dns="));
+            match param.map(|param| URL_SAFE_NO_PAD.decode(param.trim_end_matches('='))) {
+                Some(Ok(query)) => query,
+                _ => return Ok(status(StatusCode::BAD_REQUEST)),
+            }
+        }
+        Method::POST => {
+            let content_type = request.headers().get(header::CONTENT_TYPE);
+            if content_type.and_then(|v| v.to_str().ok()) != Some(DNS_MESSAGE) {
+                return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+
+            match Limited::new(request.into_body(), MAX_MESSAGE_SIZE)
+                .collect()
+                .await
+            {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(_) => return Ok(status(StatusCode::PAYLOAD_TOO_LARGE)),
+            }
+        }
+        _ => {
+            let mut response = status(StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("GET, POST"));
+            return Ok(response);
+        }
+    };
+
+    if query.len() < HEADER_SIZE || query.len() > MAX_MESSAGE_SIZE {
+        return Ok(status(StatusCode::BAD_REQUEST));
+    }
+    if !QUERY_LIMITER
+        .get()
+        .unwrap()
+        .allow(src.ip(), Instant::now().into_std())
+    {
+        return Ok(status(StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    let mut req_buffer = BytePacketBuffer::with_size(query.len());
+    req_buffer.buf.copy_from_slice(&query);
+
+    let mut res_buffer =
+        match handle_query(&mut req_buffer, query.len(), src, Transport::Https).await {
+            Ok(Some(res_buffer)) => res_buffer,
+            Ok(None) => return Ok(status(StatusCode::FORBIDDEN)),
+            Err(e) => {
+                error!("failed to answer query from {src}: {e}");
+                return Ok(status(StatusCode::BAD_REQUEST));
+            }
+        };
+
+    let body = Bytes::copy_from_slice(&res_buffer.buf[..res_buffer.pos()]);
+    let mut response = Response::new(Full::new(body));
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+
+    // HTTP caches may keep the answer as long as its shortest TTL (RFC 8484
+    // section 5.1).
+    let max_age = res_buffer
+        .seek(0)
+        .and_then(|()| DnsPacket::from_buffer(&mut res_buffer))
+        .ok()
+        .and_then(|packet| {
+            packet
+                .answers
+                .iter()
+                .chain(&packet.authorities)
+                .map(|rec| rec.ttl())
+                .min()
+        });
+    if let Some(max_age) = max_age {
+        let value = HeaderValue::from_str(&format!("max-age={max_age}")).unwrap();
+        response.headers_mut().insert(header::CACHE_CONTROL, value);
+    }
+
+    Ok(response)
+}
+
+fn status(code: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = code;
+    response
+}
@@ -0,0 +1,195 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use quinn::{
+    crypto::rustls::QuicServerConfig, Connection, Endpoint, IdleTimeout, RecvStream, SendStream,
+    ServerConfig, TransportConfig, VarInt,
+};
+use tokio::{sync::Semaphore, time::timeout};
+use tokio_rustls::rustls;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error};
+
+use crate::{
+    config::DoqConfig,
+    tcp,
+    util::{handle_query, Transport},
+    QUERY_LIMITER,
+};
+
+// ALPN protocol name for DNS over QUIC (RFC 9250 section 4.1.1).
+pub const ALPN: &[u8] = b"doq";
+
+// Error codes for closing connections and resetting streams (RFC 9250
+// section 4.3).
+const DOQ_NO_ERROR: VarInt = VarInt::from_u32(0x0);
+const DOQ_INTERNAL_ERROR: VarInt = VarInt::from_u32(0x1);
+const DOQ_PROTOCOL_ERROR: VarInt = VarInt::from_u32(0x2);
+const DOQ_EXCESSIVE_LOAD: VarInt = VarInt::from_u32(0x4);
+const DOQ_UNSPECIFIED_ERROR: VarInt = VarInt::from_u32(0x5);
+
+// Longest a client may take to send a query once it has opened a stream.
+const STREAM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Opens the QUIC endpoint on `config.listen`, using `tls` for the
+/// handshake.
+pub fn bind(config: &DoqConfig, tls: Arc<rustls::ServerConfig>) -> Result<Endpoint, String> {
+    let Some(addr) = config.listen else {
+        return Err("doq.listen isn't set".to_string());
+    };
+
+    let crypto = QuicServerConfig::try_from(tls).map_err(|e| e.to_string())?;
+    let idle_timeout = IdleTimeout::try_from(Duration::from_secs(config.idle_timeout))
+        .map_err(|e| format!("doq.idle_timeout: {e}"))?;
+
+    // Queries only come on bidirectional streams the client opens.
+    let mut transport = TransportConfig::default();
+    transport
+        .max_idle_timeout(Some(idle_timeout))
+        .max_concurrent_bidi_streams(VarInt::from_u32(config.max_streams))
+        .max_concurrent_uni_streams(VarInt::from_u32(0));
+
+    let mut server_config = ServerConfig::with_crypto(Arc::new(crypto));
+    server_config.transport_config(Arc::new(transport));
+
+    Endpoint::server(server_config, addr).map_err(|e| format!("{addr}: {e}"))
+}
+
+/// Serves queries over QUIC (RFC 9250) until `shutdown` is cancelled, one
+/// per stream, with the same framing as TCP. Connections beyond
+/// `max_connections` are refused. Connections run on `tracker`.
+pub async fn serve(
+    endpoint: Endpoint,
+    max_connections: usize,
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+) {
+    let connections = Arc::new(Semaphore::new(max_connections));
+
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => incoming,
+            _ = shutdown.cancelled() => break,
+        };
+        let Some(incoming) = incoming else {
+            break;
+        };
+
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            debug!(
+                "refusing QUIC connection from {}: too many connections",
+                incoming.remote_address()
+            );
+            incoming.refuse();
+            continue;
+        };
+
+        let shutdown = shutdown.clone();
+        tracker.spawn(async move {
+            let _permit = permit;
+
+            let src = incoming.remote_address();
+            match incoming.await {
+                Ok(conn) => handle_connection(conn, src, shutdown).await,
+                Err(e) => debug!("QUIC handshake with {src} failed: {e}"),
+            }
+        });
+    }
+
+    endpoint.wait_idle().await;
+}
+
+/// Answers the queries on one connection, each on its own task, until the
+/// client closes it, it times out, or the server shuts down.
+async fn handle_connection(conn: Connection, src: SocketAddr, shutdown: CancellationToken) {
+    let streams = TaskTracker::new();
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = conn.accept_bi() => accepted,
+            _ = shutdown.cancelled() => break,
+        };
+
+        let (send, recv) = match accepted {
+            Ok(stream) => stream,
+            Err(e) => {
+                debug!("QUIC connection from {src} ended: {e}");
+                break;
+            }
+        };
+
+        let conn = conn.clone();
+        streams.spawn(async move {
+            handle_stream(&conn, send, recv, src).await;
+        });
+    }
+
+    // Let the queries in flight finish before closing.
+    streams.close();
+    streams.wait().await;
+    conn.close(DOQ_NO_ERROR, b"");
+}
+
+async fn handle_stream(
+    conn: &Connection,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    src: SocketAddr,
+) {
+    let (mut req_buffer, len) = match timeout(STREAM_TIMEOUT, tcp::read_message(&mut recv)).await {
+        Ok(Ok(Some(message))) => message,
+        Ok(Ok(None)) => {
+            debug!("empty DoQ stream from {src}");
+            conn.close(DOQ_PROTOCOL_ERROR, b"empty stream");
+            return;
+        }
+        Ok(Err(e)) if e.kind() == io::ErrorKind::InvalidData => {
+            debug!("malformed DoQ query from {src}: {e}");
+            conn.close(DOQ_PROTOCOL_ERROR, b"malformed query");
+            return;
+        }
+        // Most likely the client cancelled the query.
+        Ok(Err(e)) => {
+            debug!("DoQ stream from {src} failed: {e}");
+            return;
+        }
+        Err(_) => {
+            let _ = send.reset(DOQ_UNSPECIFIED_ERROR);
+            return;
+        }
+    };
+
+    // The message ID is unused over QUIC and must be zero (RFC 9250 section
+    // 4.2.1).
+    if req_buffer.buf[..2] != [0, 0] {
+        debug!("DoQ query from {src} has a non-zero message ID");
+        conn.close(DOQ_PROTOCOL_ERROR, b"non-zero message ID");
+        return;
+    }
+
+    if !QUERY_LIMITER.get().unwrap().allow(src.ip(), Instant::now()) {
+        let _ = send.reset(DOQ_EXCESSIVE_LOAD);
+        return;
+    }
+
+    match handle_query(&mut req_buffer, len, src, Transport::Quic).await {
+        Ok(Some(res_buffer)) => {
+            if let Err(e) = tcp::write_message(&mut send, &res_buffer).await {
+                debug!("failed to send DoQ response to {src}: {e}");
+                return;
+            }
+            let _ = send.finish();
+        }
+        Ok(None) => {
+            let _ = send.reset(DOQ_UNSPECIFIED_ERROR);
+        }
+        Err(e) => {
+            error!("failed to answer query from {src}: {e}");
+            let _ = send.reset(DOQ_INTERNAL_ERROR);
+        }
+    }
+}
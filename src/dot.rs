@@ -0,0 +1,72 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{net::TcpListener, sync::Semaphore, time::timeout};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, warn};
+
+use crate::{config::DotConfig, tcp, util::Transport};
+
+// ALPN protocol name for DNS over TLS (RFC 7858 section 3.2).
+pub const ALPN: &[u8] = b"dot";
+
+// How long a client gets to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves queries over TLS (RFC 7858) until `shutdown` is cancelled, with
+/// the same framing as plain TCP. Connections beyond the configured limit
+/// are closed straight away. Connections run on `tracker`.
+pub async fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    config: DotConfig,
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+) {
+    let idle_timeout = Duration::from_secs(config.idle_timeout);
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return,
+        };
+
+        let (stream, src) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("failed to accept TLS connection: {e}");
+                continue;
+            }
+        };
+
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            debug!("refusing TLS connection from {src}: too many connections");
+            continue;
+        };
+
+        let acceptor = acceptor.clone();
+        let shutdown = shutdown.clone();
+        tracker.spawn(async move {
+            let _permit = permit;
+
+            let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("TLS handshake with {src} failed: {e}");
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {src} timed out");
+                    return;
+                }
+            };
+
+            let result =
+                tcp::handle_connection(stream, src, Transport::Tls, idle_timeout, shutdown).await;
+            if let Err(e) = result {
+                debug!("TLS connection from {src} failed: {e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,747 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Instant, SystemTime},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    client::conn::http2::{self, SendRequest},
+    header, Method, Request, StatusCode,
+};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use ring::digest;
+use tokio::{
+    io::{self, WriteHalf},
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{
+        self,
+        client::{
+            danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            WebPkiServerVerifier,
+        },
+        crypto::ring as provider,
+        pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    },
+    TlsConnector,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+use webpki::EndEntityCert;
+
+use crate::{
+    buffer::{BytePacketBuffer, MAX_MESSAGE_SIZE},
+    config::{ForwardConfig, UpstreamConfig, UpstreamProtocol},
+    dns::{DnsPacket, QueryType},
+    dnstap, tcp,
+    util::{query_packet, Transport, UPSTREAM_TIMEOUT},
+    METRICS,
+};
+
+// Queries waiting for an answer on one DNS over TLS connection.
+type Pending = Mutex<HashMap<u16, oneshot::Sender<BytePacketBuffer>>>;
+
+/// Sends queries to encrypted upstream resolvers instead of recursing.
+/// Connections are kept open between queries and carry many at once.
+pub struct Forwarder {
+    upstreams: Vec<Upstream>,
+}
+
+impl Forwarder {
+    /// Sets up the upstreams in `config`, or returns `None` if there are
+    /// none. Connections are only opened once queries need them.
+    pub fn new(config: &ForwardConfig) -> Result<Option<Forwarder>, String> {
+        if config.upstreams.is_empty() {
+            return Ok(None);
+        }
+
+        let roots = load_roots(&config.ca_file)?;
+        let upstreams = config
+            .upstreams
+            .iter()
+            .map(|upstream| Upstream::new(upstream, roots.clone()))
+            .collect::<Result<_, String>>()?;
+
+        Ok(Some(Forwarder { upstreams }))
+    }
+
+    /// Asks the upstreams in turn until one answers.
+    pub async fn lookup(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket, String> {
+        let mut last_error = String::new();
+        for upstream in &self.upstreams {
+            match upstream.query(query_packet(qname, qtype)).await {
+                Ok(response) => return Ok(response),
+                Err(e) => {
+                    warn!(
+                        "forwarding {qtype:?} {qname} to {} failed: {e}",
+                        upstream.address
+                    );
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+impl fmt::Debug for Forwarder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.upstreams.iter().map(|upstream| upstream.address))
+            .finish()
+    }
+}
+
+struct Upstream {
+    address: SocketAddr,
+    name: ServerName<'static>,
+    connector: TlsConnector,
+    transport: UpstreamTransport,
+}
+
+enum UpstreamTransport {
+    Tls(tokio::sync::Mutex<Option<Arc<DotConnection>>>),
+    Https {
+        /// URI the queries are POSTed to.
+        uri: String,
+        sender: tokio::sync::Mutex<Option<SendRequest<Full<Bytes>>>>,
+    },
+}
+
+impl Upstream {
+    fn new(config: &UpstreamConfig, roots: Arc<RootCertStore>) -> Result<Upstream, String> {
+        let name = ServerName::try_from(config.name.clone())
+            .map_err(|e| format!("upstream name {}: {e}", config.name))?;
+
+        let pins = config
+            .spki_pins
+            .iter()
+            .map(|pin| {
+                BASE64
+                    .decode(pin)
+                    .map_err(|e| format!("SPKI pin {pin}: {e}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (alpn, transport): (&[u8], _) = match config.protocol {
+            UpstreamProtocol::Tls => (
+                b"dot",
+                UpstreamTransport::Tls(tokio::sync::Mutex::new(None)),
+            ),
+            UpstreamProtocol::Https => (
+                b"h2",
+                UpstreamTransport::Https {
+                    uri: format!("https://{}{}", config.name, config.path),
+                    sender: tokio::sync::Mutex::new(None),
+                },
+            ),
+        };
+
+        Ok(Upstream {
+            address: config.address,
+            name,
+            connector: TlsConnector::from(client_config(roots, pins, alpn)?),
+            transport,
+        })
+    }
+
+    async fn query(&self, packet: DnsPacket) -> Result<DnsPacket, String> {
+        let server_label = self.address.to_string();
+        METRICS.upstream_queries.inc(&[&server_label]);
+        let start = Instant::now();
+
+        let result = timeout(UPSTREAM_TIMEOUT, async {
+            match &self.transport {
+                UpstreamTransport::Tls(conn) => self.query_tls(conn, packet).await,
+                UpstreamTransport::Https { uri, sender } => {
+                    self.query_https(uri, sender, packet).await
+                }
+            }
+        })
+        .await;
+
+        match result {
+            Ok(Ok(response)) => {
+                METRICS
+                    .upstream_duration
+                    .observe(&[&server_label], start.elapsed());
+                Ok(response)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                METRICS.upstream_timeouts.inc(&[&server_label]);
+                Err("timed out".to_string())
+            }
+        }
+    }
+
+    async fn connect(&self) -> Result<TlsStream<TcpStream>, String> {
+        let stream = TcpStream::connect(self.address)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.connector
+            .connect(self.name.clone(), stream)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Sends the query on the open connection, or a new one if it has gone
+    /// away. A query that fails because the upstream just closed the
+    /// connection is retried once on a fresh one.
+    async fn query_tls(
+        &self,
+        slot: &tokio::sync::Mutex<Option<Arc<DotConnection>>>,
+        mut packet: DnsPacket,
+    ) -> Result<DnsPacket, String> {
+        let conn = self.tls_connection(slot).await?;
+        match conn.exchange(&mut packet).await {
+            Err(e) if conn.is_closed() => {
+                debug!("retrying query to {} after {e}", self.address);
+                let conn = self.tls_connection(slot).await?;
+                conn.exchange(&mut packet).await
+            }
+            result => result,
+        }
+    }
+
+    async fn tls_connection(
+        &self,
+        slot: &tokio::sync::Mutex<Option<Arc<DotConnection>>>,
+    ) -> Result<Arc<DotConnection>, String> {
+        let mut slot = slot.lock().await;
+        if let Some(conn) = &*slot {
+            if !conn.is_closed() {
+                return Ok(conn.clone());
+            }
+        }
+
+        debug!("connecting to {} over TLS", self.address);
+        let conn = Arc::new(DotConnection::open(self.connect().await?, self.address));
+        *slot = Some(conn.clone());
+
+        Ok(conn)
+    }
+
+    /// POSTs the query over the shared HTTP/2 connection, opening a new one
+    /// if it has gone away.
+    async fn query_https(
+        &self,
+        uri: &str,
+        slot: &tokio::sync::Mutex<Option<SendRequest<Full<Bytes>>>>,
+        mut packet: DnsPacket,
+    ) -> Result<DnsPacket, String> {
+        let mut sender = {
+            let mut slot = slot.lock().await;
+            match &*slot {
+                Some(sender) if !sender.is_closed() => sender.clone(),
+                _ => {
+                    debug!("connecting to {} over HTTPS", self.address);
+                    let stream = self.connect().await?;
+                    let (sender, conn) =
+                        http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                            .await
+                            .map_err(|e| e.to_string())?;
+
+                    let address = self.address;
+                    tokio::spawn(async move {
+                        if let Err(e) = conn.await {
+                            debug!("HTTPS connection to {address} failed: {e}");
+                        }
+                    });
+
+                    *slot = Some(sender.clone());
+                    sender
+                }
+            }
+        };
+
+        // A zero ID lets HTTP caches share answers (RFC 8484 section 4.1).
+        packet.header.id = 0;
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer)?;
+        let query = &req_buffer.buf[..req_buffer.pos()];
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/dns-message")
+            .header(header::ACCEPT, "application/dns-message")
+            .body(Full::new(Bytes::copy_from_slice(query)))
+            .map_err(|e| e.to_string())?;
+
+        let query_time = SystemTime::now();
+        sender.ready().await.map_err(|e| e.to_string())?;
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|e| e.to_string())?;
+        dnstap::resolver_query(self.address, Transport::Https, query, query_time);
+
+        if response.status() != StatusCode::OK {
+            return Err(format!("HTTP status {}", response.status()));
+        }
+        let body = Limited::new(response.into_body(), MAX_MESSAGE_SIZE)
+            .collect()
+            .await
+            .map_err(|e| e.to_string())?
+            .to_bytes();
+        dnstap::resolver_response(self.address, Transport::Https, query_time, &body);
+
+        let mut res_buffer = BytePacketBuffer::with_size(body.len());
+        res_buffer.buf.copy_from_slice(&body);
+        DnsPacket::from_buffer(&mut res_buffer)
+    }
+}
+
+/// A DNS over TLS connection that queries are pipelined on: each gets its
+/// own ID, and answers are matched up by it in whatever order they come.
+/// The connection is closed once dropped.
+struct DotConnection {
+    address: SocketAddr,
+    writer: mpsc::Sender<BytePacketBuffer>,
+    pending: Arc<Pending>,
+    next_id: AtomicU16,
+    closed: Arc<AtomicBool>,
+    stop: CancellationToken,
+}
+
+impl DotConnection {
+    fn open(stream: TlsStream<TcpStream>, address: SocketAddr) -> DotConnection {
+        let (mut read, write) = io::split(stream);
+        let (writer, requests) = mpsc::channel(64);
+        let pending: Arc<Pending> = Arc::default();
+        let closed = Arc::new(AtomicBool::new(false));
+
+        tokio::spawn(write_queries(write, requests, closed.clone()));
+
+        let stop = CancellationToken::new();
+        let (reader_pending, reader_closed) = (pending.clone(), closed.clone());
+        let reader_stop = stop.clone();
+        tokio::spawn(async move {
+            loop {
+                let message = tokio::select! {
+                    message = tcp::read_message(&mut read) => message,
+                    _ = reader_stop.cancelled() => break,
+                };
+                match message {
+                    Ok(Some((res_buffer, len))) => {
+                        dnstap::resolver_response(
+                            address,
+                            Transport::Tls,
+                            SystemTime::now(),
+                            &res_buffer.buf[..len],
+                        );
+                        let id = u16::from_be_bytes([res_buffer.buf[0], res_buffer.buf[1]]);
+                        if let Some(waiter) = reader_pending.lock().unwrap().remove(&id) {
+                            let _ = waiter.send(res_buffer);
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        debug!("TLS connection to {address} failed: {e}");
+                        break;
+                    }
+                }
+            }
+
+            // Dropping the waiters fails the queries still in flight.
+            reader_closed.store(true, Ordering::Relaxed);
+            reader_pending.lock().unwrap().clear();
+        });
+
+        DotConnection {
+            address,
+            writer,
+            pending,
+            next_id: AtomicU16::new(0),
+            closed,
+            stop,
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    async fn exchange(&self, packet: &mut DnsPacket) -> Result<DnsPacket, String> {
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer)?;
+
+        let (waiter, answer) = oneshot::channel();
+        let id = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.len() > u16::MAX as usize {
+                return Err("no free message ID".to_string());
+            }
+            let id = loop {
+                let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+                if !pending.contains_key(&id) {
+                    break id;
+                }
+            };
+            pending.insert(id, waiter);
+            id
+        };
+        let _pending = PendingQuery { conn: self, id };
+        req_buffer.buf[..2].copy_from_slice(&id.to_be_bytes());
+
+        dnstap::resolver_query(
+            self.address,
+            Transport::Tls,
+            &req_buffer.buf[..req_buffer.pos()],
+            SystemTime::now(),
+        );
+        if self.writer.send(req_buffer).await.is_err() {
+            return Err("connection closed".to_string());
+        }
+
+        match answer.await {
+            Ok(mut res_buffer) => DnsPacket::from_buffer(&mut res_buffer),
+            Err(_) => Err("connection closed".to_string()),
+        }
+    }
+}
+
+impl Drop for DotConnection {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+/// Frees the ID of a query once it is over, however it ended.
+struct PendingQuery<'a> {
+    conn: &'a DotConnection,
+    id: u16,
+}
+
+impl Drop for PendingQuery<'_> {
+    fn drop(&mut self) {
+        // Still waiting means the query was given up on, most likely timed
+        // out. The upstream may have silently dropped the connection, so
+        // later queries get a new one.
+        if self.conn.pending.lock().unwrap().remove(&self.id).is_some() {
+            self.conn.closed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Writes queued queries to the connection until it fails or the
+/// connection is dropped.
+async fn write_queries(
+    mut write: WriteHalf<TlsStream<TcpStream>>,
+    mut requests: mpsc::Receiver<BytePacketBuffer>,
+    closed: Arc<AtomicBool>,
+) {
+    while let Some(req_buffer) = requests.recv().await {
+        if let Err(e) = tcp::write_message(&mut write, &req_buffer).await {
+            debug!("failed to send query upstream: {e}");
+            closed.store(true, Ordering::Relaxed);
+            return;
+        }
+    }
+}
+
+fn load_roots(ca_file: &str) -> Result<Arc<RootCertStore>, String> {
+    let certs = CertificateDer::pem_file_iter(ca_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{ca_file}: {e}"))?;
+
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(certs);
+    if added == 0 {
+        return Err(format!("{ca_file}: no usable CA certificates"));
+    }
+
+    Ok(Arc::new(roots))
+}
+
+fn client_config(
+    roots: Arc<RootCertStore>,
+    pins: Vec<Vec<u8>>,
+    alpn: &[u8],
+) -> Result<Arc<ClientConfig>, String> {
+    let provider = Arc::new(provider::default_provider());
+    let webpki = WebPkiServerVerifier::builder_with_provider(roots, provider.clone())
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier { webpki, pins }))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+/// Checks the certificate chain as usual, then, if there are pins, that the
+/// server's key is one of them.
+#[derive(Debug)]
+struct PinnedVerifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    /// SHA-256 digests of acceptable SubjectPublicKeyInfo.
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        if self.pins.is_empty() {
+            return Ok(verified);
+        }
+
+        let cert = EndEntityCert::try_from(end_entity)
+            .map_err(|e| rustls::Error::General(e.to_string()))?;
+        let spki = digest::digest(&digest::SHA256, &cert.subject_public_key_info());
+        if self.pins.iter().any(|pin| pin.as_slice() == spki.as_ref()) {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(
+                "certificate key matches none of the SPKI pins".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::util::query_packet;
+
+    // A certificate for localhost and the CA that issued it, valid for the
+    // 30 days from 2026-10-19.
+    const CERT: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIIBkDCCATagAwIBAgIUMNWi0RRrmzn1BbGx3n8q4dhM6+cwCgYIKoZIzj0EAwIw\n\
+ETEPMA0GA1UEAwwGdGVzdGNhMB4XDTI2MTAxOTA5NTcyMFoXDTI2MTExODA5NTcy\n\
+MFowFDESMBAGA1UEAwwJbG9jYWxob3N0MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcD\n\
+QgAEk3M7plRpkjOC8BXe3EK+YON+HGWfTESBC8ZjQVfKm1JsJzJPt6Pco0OE+0u8\n\
+93mprUqmrxxacKGgRt+mUqUgeKNpMGcwGgYDVR0RBBMwEYIJbG9jYWxob3N0hwR/\n\
+AAABMAkGA1UdEwQCMAAwHQYDVR0OBBYEFOBgxlmWF7rxfQ8CtSZO8yYutPePMB8G\n\
+A1UdIwQYMBaAFFuj3aK027uHVU/eC8JXqn7Wmma8MAoGCCqGSM49BAMCA0gAMEUC\n\
+ICdlRu44rLkVyaFc5SmkYb1gN4lh/jOsDcS5jwInH1E1AiEAnSZye4mQGlgjZOFx\n\
+WMhIoXv8KDrlP6hzKYPBwvtxprk=\n\
+-----END CERTIFICATE-----\n\
+";
+    const CA: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIIBdjCCAR2gAwIBAgIUSRTAfju00GZnaWfLgBCz3ZY1tR4wCgYIKoZIzj0EAwIw\n\
+ETEPMA0GA1UEAwwGdGVzdGNhMB4XDTI2MTAxOTA5NTcyMFoXDTI2MTExODA5NTcy\n\
+MFowETEPMA0GA1UEAwwGdGVzdGNhMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE\n\
+PHtDCzJ/aCJyOQJ6g4w8k+RZvRiarM4jM6Whc3IXlknr6vDdSCjCCEMEWEo1nVFQ\n\
+DLD2uSzFz7EjfUsJDBFnO6NTMFEwHQYDVR0OBBYEFFuj3aK027uHVU/eC8JXqn7W\n\
+mma8MB8GA1UdIwQYMBaAFFuj3aK027uHVU/eC8JXqn7Wmma8MA8GA1UdEwEB/wQF\n\
+MAMBAf8wCgYIKoZIzj0EAwIDRwAwRAIgCukI/LHHWonvAx0brA3RqtQfQfTr9bqu\n\
+4QC1ykwliNMCIDgLHo13OlivKRMqxsaOxVPSd6XEoLei8vKYQuMguNxf\n\
+-----END CERTIFICATE-----\n\
+";
+
+    // Base64 SHA-256 of the certificate's SubjectPublicKeyInfo.
+    const PIN: &str = "emJ7ulWllqx7FedacOSXRqq6zYUV4bVL1hfKpNisTdU=";
+    const OTHER_PIN: &str = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+
+    /// A connection whose queries end up in the returned channel, with the
+    /// test standing in for the upstream.
+    fn connection() -> (DotConnection, mpsc::Receiver<BytePacketBuffer>) {
+        let (writer, requests) = mpsc::channel(64);
+        let conn = DotConnection {
+            address: "192.0.2.53:853".parse().unwrap(),
+            writer,
+            pending: Arc::default(),
+            next_id: AtomicU16::new(0),
+            closed: Arc::new(AtomicBool::new(false)),
+            stop: CancellationToken::new(),
+        };
+
+        (conn, requests)
+    }
+
+    /// Answers a query taken off the connection, as the reader task would.
+    fn answer(conn: &DotConnection, query: &BytePacketBuffer) -> u16 {
+        let id = u16::from_be_bytes([query.buf[0], query.buf[1]]);
+
+        let mut response = query_packet("example.com", QueryType::A);
+        response.header.id = id;
+        response.header.response = true;
+        let mut res_buffer = BytePacketBuffer::new();
+        response.write(&mut res_buffer).unwrap();
+        res_buffer.seek(0).unwrap();
+
+        let waiter = conn.pending.lock().unwrap().remove(&id).unwrap();
+        waiter.send(res_buffer).unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn exchange_matches_answers_by_id_and_frees_them() {
+        let (conn, mut requests) = connection();
+
+        let upstream = async {
+            let first = requests.recv().await.unwrap();
+            let second = requests.recv().await.unwrap();
+            // Answered in the opposite order.
+            (answer(&conn, &second), answer(&conn, &first))
+        };
+        let mut query = query_packet("example.com", QueryType::A);
+        let mut other = query_packet("example.com", QueryType::A);
+        let (first, second, ids) = tokio::join!(
+            conn.exchange(&mut query),
+            conn.exchange(&mut other),
+            upstream
+        );
+
+        let mut ids = [ids.0, ids.1];
+        ids.sort();
+        assert_eq!(ids, [0, 1]);
+        let mut answered = [first.unwrap().header.id, second.unwrap().header.id];
+        answered.sort();
+        assert_eq!(answered, ids);
+
+        assert!(conn.pending.lock().unwrap().is_empty());
+        assert!(!conn.is_closed());
+    }
+
+    #[tokio::test]
+    async fn exchange_skips_ids_in_use_and_wraps_around() {
+        let (conn, mut requests) = connection();
+        conn.next_id.store(u16::MAX - 1, Ordering::Relaxed);
+        let (waiter, _answer) = oneshot::channel();
+        conn.pending.lock().unwrap().insert(u16::MAX - 1, waiter);
+
+        for expected in [u16::MAX, 0] {
+            let upstream = async { answer(&conn, &requests.recv().await.unwrap()) };
+            let mut query = query_packet("example.com", QueryType::A);
+            let (response, id) = tokio::join!(conn.exchange(&mut query), upstream);
+
+            assert_eq!(id, expected);
+            assert_eq!(response.unwrap().header.id, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn exchange_fails_without_a_free_id() {
+        let (conn, _requests) = connection();
+        {
+            let mut pending = conn.pending.lock().unwrap();
+            for id in 0..=u16::MAX {
+                pending.insert(id, oneshot::channel().0);
+            }
+        }
+
+        let mut query = query_packet("example.com", QueryType::A);
+        let err = conn.exchange(&mut query).await.unwrap_err();
+        assert_eq!(err, "no free message ID");
+    }
+
+    #[tokio::test]
+    async fn abandoned_queries_free_their_id_and_close_the_connection() {
+        let (conn, mut requests) = connection();
+
+        let mut query = query_packet("example.com", QueryType::A);
+        let exchange = timeout(Duration::from_millis(20), conn.exchange(&mut query));
+        assert!(exchange.await.is_err());
+
+        // The query was sent but never answered.
+        assert!(requests.try_recv().is_ok());
+        assert!(conn.pending.lock().unwrap().is_empty());
+        assert!(conn.is_closed());
+    }
+
+    fn verifier(pins: &[&str]) -> PinnedVerifier {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(CA.as_bytes()).unwrap())
+            .unwrap();
+        let webpki = WebPkiServerVerifier::builder_with_provider(
+            Arc::new(roots),
+            Arc::new(provider::default_provider()),
+        )
+        .build()
+        .unwrap();
+
+        PinnedVerifier {
+            webpki,
+            pins: pins.iter().map(|pin| BASE64.decode(pin).unwrap()).collect(),
+        }
+    }
+
+    fn verify(verifier: &PinnedVerifier, name: &str) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = CertificateDer::from_pem_slice(CERT.as_bytes()).unwrap();
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        // 2026-10-20, while the certificate is valid.
+        let now = UnixTime::since_unix_epoch(Duration::from_secs(1_792_454_400));
+
+        verifier.verify_server_cert(&cert, &[], &name, &[], now)
+    }
+
+    #[test]
+    fn accepts_keys_matching_a_pin() {
+        assert!(verify(&verifier(&[]), "localhost").is_ok());
+        assert!(verify(&verifier(&[OTHER_PIN, PIN]), "localhost").is_ok());
+    }
+
+    #[test]
+    fn rejects_keys_matching_no_pin() {
+        let err = verify(&verifier(&[OTHER_PIN]), "localhost").unwrap_err();
+        assert!(err.to_string().contains("none of the SPKI pins"), "{err}");
+    }
+
+    #[test]
+    fn checks_the_chain_before_the_pins() {
+        assert!(verify(&verifier(&[PIN]), "example.com").is_err());
+    }
+}
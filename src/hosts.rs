@@ -0,0 +1,258 @@
+use std::{collections::HashMap, fs, net::IpAddr};
+
+use crate::{
+    config::HostsConfig,
+    dns::{DnsPacket, DnsRecord, QueryType},
+    master::normalize_name,
+};
+
+/// Static name to address mappings from hosts files and the config, answered
+/// ahead of zones, the cache and recursion.
+#[derive(Debug, Default)]
+pub struct Hosts {
+    ttl: u32,
+    addrs: HashMap<String, Vec<IpAddr>>,
+    // Reverse (in-addr.arpa / ip6.arpa) name to host names, for PTR queries.
+    names: HashMap<String, Vec<String>>,
+}
+
+impl Hosts {
+    pub fn load(config: &HostsConfig) -> Result<Hosts, String> {
+        let mut hosts = Hosts {
+            ttl: config.ttl,
+            ..Default::default()
+        };
+
+        for path in &config.files {
+            let raw = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+
+            for line in raw.lines() {
+                let line = line.split('#').next().unwrap_or_default();
+                let mut fields = line.split_whitespace();
+
+                let Some(addr) = fields.next() else {
+                    continue;
+                };
+                // Entries such as `fe80::1%lo0` carry a scope we can't serve.
+                let Ok(addr) = addr.parse::<IpAddr>() else {
+                    continue;
+                };
+
+                for name in fields {
+                    hosts.insert(name, addr);
+                }
+            }
+        }
+
+        for (name, addrs) in &config.records {
+            for addr in addrs {
+                let addr = addr
+                    .parse::<IpAddr>()
+                    .map_err(|e| format!("static record {name}: {e}"))?;
+                hosts.insert(name, addr);
+            }
+        }
+
+        Ok(hosts)
+    }
+
+    fn insert(&mut self, name: &str, addr: IpAddr) {
+        let name = normalize_name(name);
+
+        let addrs = self.addrs.entry(name.clone()).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+
+        let names = self.names.entry(reverse_name(addr)).or_default();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    /// Answers A, AAAA and PTR queries for names we hold mappings for. A name
+    /// known only with addresses of the other family gets an empty answer.
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.header.authoritative_answer = true;
+
+        let domain = qname.to_string();
+        let ttl = self.ttl;
+
+        match qtype {
+            QueryType::A | QueryType::AAAA => {
+                let addrs = self.addrs.get(qname)?;
+
+                for addr in addrs {
+                    match (qtype, *addr) {
+                        (QueryType::A, IpAddr::V4(addr)) => packet.answers.push(DnsRecord::A {
+                            domain: domain.clone(),
+                            addr,
+                            ttl,
+                        }),
+                        (QueryType::AAAA, IpAddr::V6(addr)) => {
+                            packet.answers.push(DnsRecord::AAAA {
+                                domain: domain.clone(),
+                                addr,
+                                ttl,
+                            })
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            QueryType::PTR => {
+                for host in self.names.get(qname)? {
+                    packet.answers.push(DnsRecord::PTR {
+                        domain: domain.clone(),
+                        host: host.clone(),
+                        ttl,
+                    });
+                }
+            }
+            _ => return None,
+        }
+
+        Some(packet)
+    }
+}
+
+/// Builds the `in-addr.arpa` or `ip6.arpa` name used for reverse lookups.
+pub fn reverse_name(addr: IpAddr) -> String {
+    match addr {
+        IpAddr::V4(addr) => {
+            let [a, b, c, d] = addr.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(addr) => {
+            let mut name = String::new();
+            for octet in addr.octets().iter().rev() {
+                name.push_str(&format!("{:x}.{:x}.", octet & 0x0F, octet >> 4));
+            }
+            name.push_str("ip6.arpa");
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempFile;
+
+    const HOSTS: &str = "\
+# Comments and blank lines are skipped.
+
+192.0.2.1    router.lan   router    # trailing comment
+192.0.2.2    nas.lan
+2001:db8::2  nas.lan
+fe80::1%lo0  localhost
+not-an-address  ignored.lan
+192.0.2.1    Gateway.LAN.
+";
+
+    fn load(records: &[(&str, &[&str])]) -> Hosts {
+        let file = TempFile::new(HOSTS);
+        let config = HostsConfig {
+            files: vec![file.path().to_str().unwrap().to_string()],
+            records: records
+                .iter()
+                .map(|(name, addrs)| {
+                    (
+                        name.to_string(),
+                        addrs.iter().map(|a| a.to_string()).collect(),
+                    )
+                })
+                .collect(),
+            ttl: 60,
+            ..Default::default()
+        };
+
+        Hosts::load(&config).unwrap()
+    }
+
+    fn answers(hosts: &Hosts, qname: &str, qtype: QueryType) -> Option<Vec<String>> {
+        let packet = hosts.lookup(qname, qtype)?;
+        assert!(packet.header.authoritative_answer);
+        Some(packet.answers.iter().map(|rec| rec.to_string()).collect())
+    }
+
+    #[test]
+    fn parses_hosts_files() {
+        let hosts = load(&[]);
+
+        assert_eq!(
+            answers(&hosts, "router", QueryType::A).unwrap(),
+            ["router. 60 IN A 192.0.2.1"]
+        );
+        assert_eq!(
+            answers(&hosts, "gateway.lan", QueryType::A).unwrap(),
+            ["gateway.lan. 60 IN A 192.0.2.1"]
+        );
+        assert_eq!(
+            answers(&hosts, "nas.lan", QueryType::AAAA).unwrap(),
+            ["nas.lan. 60 IN AAAA 2001:db8::2"]
+        );
+
+        // Scoped and malformed addresses are skipped.
+        assert!(hosts.lookup("localhost", QueryType::AAAA).is_none());
+        assert!(hosts.lookup("ignored.lan", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn answers_reverse_lookups_with_every_name() {
+        let hosts = load(&[]);
+
+        assert_eq!(
+            answers(&hosts, "1.2.0.192.in-addr.arpa", QueryType::PTR).unwrap(),
+            [
+                "1.2.0.192.in-addr.arpa. 60 IN PTR router.lan.",
+                "1.2.0.192.in-addr.arpa. 60 IN PTR router.",
+                "1.2.0.192.in-addr.arpa. 60 IN PTR gateway.lan.",
+            ]
+        );
+
+        let reverse = reverse_name("2001:db8::2".parse().unwrap());
+        assert_eq!(
+            reverse,
+            "2.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+        assert_eq!(
+            answers(&hosts, &reverse, QueryType::PTR).unwrap(),
+            [format!("{reverse}. 60 IN PTR nas.lan.")]
+        );
+    }
+
+    #[test]
+    fn gives_empty_answers_for_the_other_family() {
+        let hosts = load(&[]);
+
+        assert_eq!(
+            answers(&hosts, "router.lan", QueryType::AAAA),
+            Some(Vec::new())
+        );
+        assert!(hosts.lookup("router.lan", QueryType::MX).is_none());
+        assert!(hosts.lookup("unknown.lan", QueryType::A).is_none());
+    }
+
+    #[test]
+    fn adds_static_records_from_the_config() {
+        let hosts = load(&[("printer.lan", &["192.0.2.9", "192.0.2.1"])]);
+
+        assert_eq!(
+            answers(&hosts, "printer.lan", QueryType::A).unwrap(),
+            [
+                "printer.lan. 60 IN A 192.0.2.9",
+                "printer.lan. 60 IN A 192.0.2.1"
+            ]
+        );
+
+        let config = HostsConfig {
+            records: [("bad.lan".to_string(), vec!["192.0.2.300".to_string()])].into(),
+            ..Default::default()
+        };
+        let err = Hosts::load(&config).unwrap_err();
+        assert!(err.starts_with("static record bad.lan:"), "{err}");
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    io::{self, IsTerminal},
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    config::{LogConfig, LogFormat},
+    dns::DnsPacket,
+    metrics::qtype_label,
+    util::Transport,
+};
+
+// Target of the per-query log lines, so they can be filtered on their own,
+// e.g. `info,query=off`.
+const QUERY_TARGET: &str = "query";
+
+// Whether query lines are logged at all, switchable at runtime.
+static QUERY_LOG: AtomicBool = AtomicBool::new(true);
+
+/// Installs the global logger. Must be called once, before anything logs.
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => EnvFilter::try_new(directives),
+        Err(_) => EnvFilter::try_new(&config.level),
+    }
+    .map_err(|e| format!("invalid log level: {e}"))?;
+
+    set_query_log(config.queries);
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(io::stdout().is_terminal());
+    match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// Turns the per-query log lines on or off.
+pub fn set_query_log(enabled: bool) {
+    QUERY_LOG.store(enabled, Ordering::Relaxed);
+}
+
+pub fn query_log_enabled() -> bool {
+    QUERY_LOG.load(Ordering::Relaxed)
+}
+
+/// Logs one line summarising an answered query.
+pub fn log_query(
+    client: SocketAddr,
+    packet: &DnsPacket,
+    cache_hit: bool,
+    latency: Duration,
+    transport: Transport,
+) {
+    if !query_log_enabled() {
+        return;
+    }
+
+    let (qname, qtype) = match packet.questions.first() {
+        Some(question) => (question.name.as_str(), qtype_label(question.qtype)),
+        None => ("", String::new()),
+    };
+    info!(
+        target: QUERY_TARGET,
+        client = %client.ip(),
+        qname,
+        qtype,
+        rcode = ?packet.header.rescode,
+        answers = packet.answers.len(),
+        cache_hit,
+        latency_ms = latency.as_secs_f64() * 1000.0,
+        transport = transport.as_str(),
+        truncated = packet.header.truncated_message,
+        "query"
+    );
+}
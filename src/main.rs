@@ -1,53 +1,338 @@
-use std::sync::Arc;
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
+use acl::Acl;
+use blocklist::Blocklist;
 use buffer::BytePacketBuffer;
-use dashmap::DashMap;
-use dns::{DnsQuestion, DnsRecord};
-use once_cell::sync::OnceCell;
-use tokio::{net::UdpSocket, task};
-use util::handle_query;
+use cache::Cache;
+use config::Config;
+use dns::HEADER_SIZE;
+use dnssec::Validator;
+use dnstap::Dnstap;
+use forward::Forwarder;
+use hosts::Hosts;
+use metrics::Metrics;
+use once_cell::sync::{Lazy, OnceCell};
+use ratelimit::QueryLimiter;
+use rpz::Rpz;
+use rrl::RateLimiter;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    signal::{
+        self,
+        unix::{signal, SignalKind},
+    },
+    task,
+    time::{timeout_at, Instant},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, warn};
+use util::{handle_query, Transport};
+use zone::Authority;
 
+mod acl;
+mod blocklist;
 mod buffer;
+mod cache;
+mod cidr;
+mod config;
+mod control;
 mod dns;
+mod dnssec;
+mod dnstap;
+mod doh;
+mod doq;
+mod dot;
+mod forward;
+mod hosts;
+mod logging;
+mod master;
+mod metrics;
+mod nsec;
+mod ratelimit;
+mod rpz;
+mod rrl;
+mod tcp;
+mod tls;
 mod util;
+mod zone;
 
-static DNS_CACHE: OnceCell<Arc<DashMap<DnsQuestion, Vec<DnsRecord>>>> = OnceCell::new();
+static CONFIG: OnceCell<Arc<RwLock<Config>>> = OnceCell::new();
+static DNS_CACHE: OnceCell<Arc<Cache>> = OnceCell::new();
+static AUTHORITY: OnceCell<Arc<RwLock<Authority>>> = OnceCell::new();
+static HOSTS: OnceCell<Arc<RwLock<Hosts>>> = OnceCell::new();
+static BLOCKLIST: OnceCell<Arc<RwLock<Blocklist>>> = OnceCell::new();
+static RPZ: OnceCell<Arc<RwLock<Rpz>>> = OnceCell::new();
+static ACL: OnceCell<Arc<RwLock<Acl>>> = OnceCell::new();
+static RRL: OnceCell<Arc<RateLimiter>> = OnceCell::new();
+static QUERY_LIMITER: OnceCell<Arc<QueryLimiter>> = OnceCell::new();
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+static DNSTAP: OnceCell<Dnstap> = OnceCell::new();
+static VALIDATOR: OnceCell<Validator> = OnceCell::new();
+static FORWARDER: OnceCell<Forwarder> = OnceCell::new();
 
 #[tokio::main]
-async fn main() -> Result<(), String> {
+async fn main() -> Result<ExitCode, String> {
+    let config_path = std::env::args().nth(1).map(PathBuf::from);
+    let config = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    logging::init(&config.log)?;
+
+    // Set first: the reloaders spawned below read it.
+    CONFIG
+        .set(Arc::new(RwLock::new(config.clone())))
+        .expect("ERROR SETTING UP CONFIG");
+
+    let cache = Cache::new(&config.cache);
+    if let Some(path) = &config.cache.file {
+        // A bad or outdated file only costs a cold start.
+        match cache.load(Path::new(path)) {
+            Ok(loaded) => info!("loaded {loaded} cache entries from {path}"),
+            Err(e) => warn!("starting with an empty cache, failed to load {e}"),
+        }
+        cache::spawn_saver(path.clone())?;
+    }
+    DNS_CACHE
+        .set(Arc::new(cache))
+        .expect("ERROR SETTING UP CACHE");
+
+    let authority = Authority::load(&config.zones)?;
+    info!("loaded {} authoritative zone(s)", config.zones.len());
+    AUTHORITY
+        .set(Arc::new(RwLock::new(authority)))
+        .expect("ERROR SETTING UP ZONES");
+
+    let hosts = Hosts::load(&config.hosts)?;
+    HOSTS
+        .set(Arc::new(RwLock::new(hosts)))
+        .expect("ERROR SETTING UP HOSTS");
+    util::spawn_reloader(
+        "hosts files",
+        config.hosts.reload_interval,
+        |config| &config.hosts.files,
+        |config| Hosts::load(&config.hosts),
+        HOSTS.get().unwrap().clone(),
+    );
+
+    let blocklist = Blocklist::load(&config.blocklist)?;
+    info!("loaded blocklists: {} domains", blocklist.len());
+    BLOCKLIST
+        .set(Arc::new(RwLock::new(blocklist)))
+        .expect("ERROR SETTING UP BLOCKLIST");
+    util::spawn_reloader(
+        "blocklists",
+        config.blocklist.reload_interval,
+        |config| &config.blocklist.files,
+        |config| Blocklist::load(&config.blocklist),
+        BLOCKLIST.get().unwrap().clone(),
+    );
+
+    let rpz = Rpz::load(&config.rpz, !config.forward.upstreams.is_empty())?;
+    info!("loaded {} response policy zone(s)", config.rpz.len());
+    RPZ.set(Arc::new(RwLock::new(rpz)))
+        .expect("ERROR SETTING UP RPZ");
+
+    let acl = Acl::new(&config.acl)?;
+    ACL.set(Arc::new(RwLock::new(acl)))
+        .expect("ERROR SETTING UP ACL");
+
+    let rrl = RateLimiter::new(config.rrl.clone())?;
+    RRL.set(Arc::new(rrl)).expect("ERROR SETTING UP RRL");
+    rrl::spawn_pruner();
+
+    let limiter = QueryLimiter::new(config.ratelimit.clone())?;
+    QUERY_LIMITER
+        .set(Arc::new(limiter))
+        .expect("ERROR SETTING UP RATE LIMITER");
+    ratelimit::spawn_reporter();
+
+    if config.dnssec.validate {
+        let validator = Validator::new(&config.dnssec)?;
+        VALIDATOR.set(validator).expect("ERROR SETTING UP DNSSEC");
+        info!("validating DNSSEC");
+    }
+
+    if let Some(forwarder) = Forwarder::new(&config.forward)? {
+        FORWARDER
+            .set(forwarder)
+            .expect("ERROR SETTING UP FORWARDING");
+        info!(
+            "forwarding to {} encrypted upstream(s)",
+            config.forward.upstreams.len()
+        );
+    }
+
+    if let Some(dnstap) = Dnstap::start(&config.dnstap)? {
+        DNSTAP.set(dnstap).expect("ERROR SETTING UP DNSTAP");
+    }
+
+    let shutdown = CancellationToken::new();
+    let tracker = TaskTracker::new();
+
     let socket = Arc::new(
         UdpSocket::bind(("0.0.0.0", 2053))
             .await
             .map_err(|e| e.to_string())?,
     );
 
-    println!("Starting DNS server at port 2053");
-    DNS_CACHE
-        .set(Arc::new(DashMap::new()))
-        .expect("ERROR SETTING UP CACHE");
+    let listener = TcpListener::bind(("0.0.0.0", 2053))
+        .await
+        .map_err(|e| e.to_string())?;
+    task::spawn(tcp::serve(
+        listener,
+        config.server.max_tcp_connections,
+        shutdown.clone(),
+        tracker.clone(),
+    ));
+
+    if let Some(addr) = config.dot.listen {
+        let tls_config = tls::server_config(&config.tls, &[dot::ALPN])?;
+        let listener = TcpListener::bind(addr).await.map_err(|e| e.to_string())?;
+        info!("serving DNS over TLS at {addr}");
+        task::spawn(dot::serve(
+            listener,
+            TlsAcceptor::from(tls_config),
+            config.dot.clone(),
+            shutdown.clone(),
+            tracker.clone(),
+        ));
+    }
 
+    if let Some(addr) = config.doh.listen {
+        let tls_config = tls::server_config(&config.tls, doh::ALPN)?;
+        let listener = TcpListener::bind(addr).await.map_err(|e| e.to_string())?;
+        info!("serving DNS over HTTPS at https://{addr}/dns-query");
+        task::spawn(doh::serve(
+            listener,
+            TlsAcceptor::from(tls_config),
+            config.doh.clone(),
+            shutdown.clone(),
+            tracker.clone(),
+        ));
+    }
+
+    if let Some(addr) = config.doq.listen {
+        let tls_config = tls::server_config(&config.tls, &[doq::ALPN])?;
+        let endpoint = doq::bind(&config.doq, tls_config)?;
+        info!("serving DNS over QUIC at {addr}");
+        task::spawn(doq::serve(
+            endpoint,
+            config.doq.max_connections,
+            shutdown.clone(),
+            tracker.clone(),
+        ));
+    }
+
+    if let Some(addr) = config.metrics.listen {
+        let listener = TcpListener::bind(addr).await.map_err(|e| e.to_string())?;
+        info!("serving metrics at http://{addr}/metrics");
+        task::spawn(metrics::serve(listener, config.metrics.clone()));
+    }
+
+    if let Some(path) = &config.control.socket {
+        let listener = control::bind(path)?;
+        info!("taking control commands on {path}");
+        task::spawn(control::serve(listener, config_path));
+    }
+
+    info!("starting DNS server at port 2053");
+
+    tokio::select! {
+        result = serve_udp(socket, &tracker) => result?,
+        _ = shutdown_signal() => info!("shutting down"),
+    }
+
+    // Stop taking queries, then give the ones in flight time to finish.
+    shutdown.cancel();
+    tracker.close();
+
+    let deadline = Instant::now() + Duration::from_secs(config.server.shutdown_timeout);
+    let drained = tokio::select! {
+        result = timeout_at(deadline, tracker.wait()) => result.is_ok(),
+        _ = shutdown_signal() => false,
+    };
+    if !drained {
+        warn!("abandoning {} unfinished task(s)", tracker.len());
+    }
+
+    if let Some(path) = &config.cache.file {
+        cache::save_to(Path::new(path));
+    }
+    if let Some(dnstap) = DNSTAP.get() {
+        dnstap
+            .finish(deadline.saturating_duration_since(Instant::now()))
+            .await;
+    }
+    if let Some(path) = &config.control.socket {
+        let _ = std::fs::remove_file(path);
+    }
+    let _ = io::stdout().flush();
+
+    Ok(if drained {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+/// Answers UDP queries until the socket fails. Dropping the future stops
+/// taking new queries; those already taken finish on `tracker`.
+async fn serve_udp(socket: Arc<UdpSocket>, tracker: &TaskTracker) -> Result<(), String> {
     loop {
         let mut req_buffer = BytePacketBuffer::new();
 
-        let (_, src) = socket
+        let (len, src) = socket
             .recv_from(&mut req_buffer.buf)
             .await
             .map_err(|e| e.to_string())?;
 
+        if len < HEADER_SIZE
+            || !QUERY_LIMITER
+                .get()
+                .unwrap()
+                .allow(src.ip(), Instant::now().into_std())
+        {
+            continue;
+        }
+
         let socket_clone = socket.clone();
-        task::spawn(async move {
-            match handle_query(&mut req_buffer).await {
-                Ok(mut res_buffer) => {
+        tracker.spawn(async move {
+            match handle_query(&mut req_buffer, len, src, Transport::Udp).await {
+                Ok(Some(mut res_buffer)) => {
                     let len = res_buffer.pos();
                     let data = res_buffer.get_range(0, len).unwrap();
-                    socket_clone
-                        .send_to(data, src)
-                        .await
-                        .map_err(|e| e.to_string())
-                        .unwrap();
+                    if let Err(e) = socket_clone.send_to(data, src).await {
+                        warn!("failed to send response to {src}: {e}");
+                    }
                 }
-                Err(e) => eprintln!("an error coccured: {e}"),
+                Ok(None) => {}
+                Err(e) => error!("failed to answer query from {src}: {e}"),
             };
         });
     }
 }
+
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("failed to listen for SIGTERM: {e}");
+            let _ = signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
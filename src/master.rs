@@ -0,0 +1,627 @@
+use std::{
+    fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use crate::dns::DnsRecord;
+
+// Guards against `$INCLUDE` loops.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+#[derive(Debug)]
+struct Token {
+    text: String,
+    quoted: bool,
+}
+
+/// One logical entry of a master file, i.e. a line with any parenthesised
+/// continuation lines folded into it.
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+/// Parses an RFC 1035 master file into records, resolving relative names
+/// against `origin`.
+pub fn parse_file(path: &Path, origin: &str) -> Result<Vec<DnsRecord>, String> {
+    let mut parser = Parser::new(origin);
+    parser.parse_file(path, 0)?;
+
+    Ok(parser.records)
+}
+
+/// Parses master file text as if it had been read from a file in the
+/// current directory, so that tests need no files of their own.
+#[cfg(test)]
+pub(crate) fn parse_str(input: &str, origin: &str) -> Result<Vec<DnsRecord>, String> {
+    let mut parser = Parser::new(origin);
+    parser.parse_input(Path::new("<input>"), input, 0)?;
+
+    Ok(parser.records)
+}
+
+/// Lowercases a name and strips the trailing dot of an absolute name.
+pub fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Parses a TTL, either as plain seconds or in BIND's `1h30m` style.
+pub fn parse_ttl(text: &str) -> Option<u32> {
+    if let Ok(secs) = text.parse::<u32>() {
+        return Some(secs);
+    }
+
+    let mut total: u32 = 0;
+    let mut value: Option<u32> = None;
+
+    for c in text.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            value = Some(value.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+
+        total = total.checked_add(value.take()?.checked_mul(unit)?)?;
+    }
+
+    if value.is_some() {
+        return None;
+    }
+
+    Some(total)
+}
+
+/// Decodes hex digits, as DNSSEC digests are written in master files.
+pub fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if text.is_empty() || !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+struct Parser {
+    origin: String,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_owner: Option<String>,
+    records: Vec<DnsRecord>,
+}
+
+impl Parser {
+    fn new(origin: &str) -> Parser {
+        Parser {
+            origin: normalize_name(origin),
+            default_ttl: None,
+            last_ttl: None,
+            last_owner: None,
+            records: Vec::new(),
+        }
+    }
+
+    fn parse_file(&mut self, path: &Path, depth: usize) -> Result<(), String> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(format!(
+                "{}: $INCLUDE nested deeper than {MAX_INCLUDE_DEPTH}",
+                path.display()
+            ));
+        }
+
+        let input = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        self.parse_input(path, &input, depth)
+    }
+
+    fn parse_input(&mut self, path: &Path, input: &str, depth: usize) -> Result<(), String> {
+        let entries = tokenize(input).map_err(|e| format!("{}: {e}", path.display()))?;
+
+        for entry in entries {
+            self.parse_entry(path, &entry, depth)
+                .map_err(|e| format!("{}:{}: {e}", path.display(), entry.line))?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_entry(&mut self, path: &Path, entry: &Entry, depth: usize) -> Result<(), String> {
+        let first = &entry.tokens[0];
+
+        if !entry.blank_owner && !first.quoted && first.text.starts_with('$') {
+            return self.parse_directive(path, entry, depth);
+        }
+
+        let mut tokens = entry.tokens.iter().peekable();
+
+        let owner = if entry.blank_owner {
+            self.last_owner
+                .clone()
+                .ok_or("no previous owner for blank owner name")?
+        } else {
+            self.name(&tokens.next().unwrap().text)
+        };
+
+        // TTL and class may appear in either order, and both are optional.
+        let mut ttl = None;
+        for _ in 0..2 {
+            let Some(token) = tokens.peek() else {
+                break;
+            };
+
+            match token.text.to_uppercase().as_str() {
+                "IN" => {}
+                "CH" | "CS" | "HS" => return Err(format!("unsupported class {}", token.text)),
+                text => match parse_ttl(text) {
+                    Some(value) if ttl.is_none() => ttl = Some(value),
+                    _ => break,
+                },
+            }
+
+            tokens.next();
+        }
+
+        let rtype = tokens
+            .next()
+            .ok_or("missing record type")?
+            .text
+            .to_uppercase();
+        let rdata: Vec<&Token> = tokens.collect();
+
+        if ttl.is_some() {
+            self.last_ttl = ttl;
+        }
+        let ttl = ttl
+            .or(self.default_ttl)
+            .or(self.last_ttl)
+            .ok_or("no TTL given and no $TTL in effect")?;
+
+        let record = self.parse_rdata(&owner, ttl, &rtype, &rdata)?;
+        self.records.push(record);
+        self.last_owner = Some(owner);
+
+        Ok(())
+    }
+
+    fn parse_directive(&mut self, path: &Path, entry: &Entry, depth: usize) -> Result<(), String> {
+        let args = &entry.tokens[1..];
+
+        match entry.tokens[0].text.to_uppercase().as_str() {
+            "$ORIGIN" => {
+                let origin = args.first().ok_or("$ORIGIN without a name")?;
+                self.origin = self.name(&origin.text);
+            }
+            "$TTL" => {
+                let ttl = args.first().ok_or("$TTL without a value")?;
+                self.default_ttl = Some(parse_ttl(&ttl.text).ok_or("invalid $TTL value")?);
+            }
+            "$INCLUDE" => {
+                let file = args.first().ok_or("$INCLUDE without a file name")?;
+                let include = path
+                    .parent()
+                    .map(|dir| dir.join(&file.text))
+                    .unwrap_or_else(|| file.text.clone().into());
+
+                // The included file may set its own origin, but that never
+                // leaks back into the including file.
+                let saved_origin = self.origin.clone();
+                if let Some(origin) = args.get(1) {
+                    self.origin = self.name(&origin.text);
+                }
+
+                let result = self.parse_file(&include, depth + 1);
+                self.origin = saved_origin;
+                result?;
+            }
+            other => return Err(format!("unknown directive {other}")),
+        }
+
+        Ok(())
+    }
+
+    fn parse_rdata(
+        &self,
+        owner: &str,
+        ttl: u32,
+        rtype: &str,
+        rdata: &[&Token],
+    ) -> Result<DnsRecord, String> {
+        let domain = owner.to_string();
+        let field = |idx: usize| -> Result<&str, String> {
+            rdata
+                .get(idx)
+                .map(|t| t.text.as_str())
+                .ok_or(format!("{rtype} record is missing fields"))
+        };
+        let number = |idx: usize| -> Result<u32, String> {
+            let text = field(idx)?;
+            parse_ttl(text).ok_or(format!("invalid number {text}"))
+        };
+
+        let record = match rtype {
+            "A" => DnsRecord::A {
+                domain,
+                addr: field(0)?.parse::<Ipv4Addr>().map_err(|e| e.to_string())?,
+                ttl,
+            },
+            "AAAA" => DnsRecord::AAAA {
+                domain,
+                addr: field(0)?.parse::<Ipv6Addr>().map_err(|e| e.to_string())?,
+                ttl,
+            },
+            "NS" => DnsRecord::NS {
+                domain,
+                host: self.name(field(0)?),
+                ttl,
+            },
+            "CNAME" => DnsRecord::CNAME {
+                domain,
+                host: self.name(field(0)?),
+                ttl,
+            },
+            "PTR" => DnsRecord::PTR {
+                domain,
+                host: self.name(field(0)?),
+                ttl,
+            },
+            "MX" => DnsRecord::MX {
+                domain,
+                priority: field(0)?.parse::<u16>().map_err(|e| e.to_string())?,
+                host: self.name(field(1)?),
+                ttl,
+            },
+            "SOA" => DnsRecord::SOA {
+                domain,
+                mname: self.name(field(0)?),
+                rname: self.name(field(1)?),
+                serial: field(2)?.parse::<u32>().map_err(|e| e.to_string())?,
+                refresh: number(3)?,
+                retry: number(4)?,
+                expire: number(5)?,
+                minimum: number(6)?,
+                ttl,
+            },
+            // The digest may be split into several tokens.
+            "DS" => DnsRecord::DS {
+                domain,
+                key_tag: field(0)?.parse::<u16>().map_err(|e| e.to_string())?,
+                algorithm: field(1)?.parse::<u8>().map_err(|e| e.to_string())?,
+                digest_type: field(2)?.parse::<u8>().map_err(|e| e.to_string())?,
+                digest: rdata
+                    .get(3..)
+                    .map(|tokens| tokens.iter().map(|t| t.text.as_str()).collect::<String>())
+                    .and_then(|text| parse_hex(&text))
+                    .ok_or("DS record without a valid digest")?,
+                ttl,
+            },
+            "TXT" => {
+                if rdata.is_empty() {
+                    return Err("TXT record without data".into());
+                }
+
+                DnsRecord::TXT {
+                    domain,
+                    data: rdata.iter().map(|t| t.text.clone()).collect(),
+                    ttl,
+                }
+            }
+            other => return Err(format!("unsupported record type {other}")),
+        };
+
+        Ok(record)
+    }
+
+    fn name(&self, text: &str) -> String {
+        if text == "@" {
+            return self.origin.clone();
+        }
+
+        if text.ends_with('.') {
+            return normalize_name(text);
+        }
+
+        if self.origin.is_empty() {
+            text.to_lowercase()
+        } else {
+            format!("{}.{}", text.to_lowercase(), self.origin)
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+
+    let mut line = 1;
+    let mut depth = 0;
+    let mut entry = Entry {
+        line,
+        blank_owner: false,
+        tokens: Vec::new(),
+    };
+    let mut current: Option<String> = None;
+    let mut at_line_start = true;
+
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if at_line_start && depth == 0 {
+            entry.line = line;
+            entry.blank_owner = c == ' ' || c == '\t';
+        }
+        at_line_start = false;
+
+        match c {
+            ';' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '\n' => {
+                if let Some(text) = current.take() {
+                    entry.tokens.push(Token {
+                        text,
+                        quoted: false,
+                    });
+                }
+
+                if depth == 0 {
+                    if !entry.tokens.is_empty() {
+                        entries.push(entry);
+                    }
+                    entry = Entry {
+                        line: line + 1,
+                        blank_owner: false,
+                        tokens: Vec::new(),
+                    };
+                    at_line_start = true;
+                }
+
+                line += 1;
+            }
+            ' ' | '\t' | '\r' | '(' | ')' => {
+                if let Some(text) = current.take() {
+                    entry.tokens.push(Token {
+                        text,
+                        quoted: false,
+                    });
+                }
+
+                if c == '(' {
+                    depth += 1;
+                } else if c == ')' {
+                    if depth == 0 {
+                        return Err(format!("line {line}: unbalanced ')'"));
+                    }
+                    depth -= 1;
+                }
+            }
+            '"' => {
+                if let Some(text) = current.take() {
+                    entry.tokens.push(Token {
+                        text,
+                        quoted: false,
+                    });
+                }
+
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.push(unescape(&mut chars)?),
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            text.push(c);
+                        }
+                        None => return Err(format!("line {line}: unterminated string")),
+                    }
+                }
+
+                entry.tokens.push(Token { text, quoted: true });
+            }
+            '\\' => current
+                .get_or_insert_with(String::new)
+                .push(unescape(&mut chars)?),
+            c => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if depth != 0 {
+        return Err("unbalanced '(' at end of file".into());
+    }
+
+    if let Some(text) = current.take() {
+        entry.tokens.push(Token {
+            text,
+            quoted: false,
+        });
+    }
+    if !entry.tokens.is_empty() {
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+/// Decodes the character following a backslash, either `\X` or `\DDD`.
+fn unescape(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<char, String> {
+    let first = chars.next().ok_or("dangling escape")?;
+
+    if !first.is_ascii_digit() {
+        return Ok(first);
+    }
+
+    let mut value = first.to_digit(10).unwrap();
+    for _ in 0..2 {
+        let digit = chars
+            .next()
+            .and_then(|c| c.to_digit(10))
+            .ok_or("invalid \\DDD escape")?;
+        value = value * 10 + digit;
+    }
+
+    u8::try_from(value)
+        .map(char::from)
+        .map_err(|_| "\\DDD escape out of range".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TempFile;
+
+    fn texts(entry: &Entry) -> Vec<&str> {
+        entry.tokens.iter().map(|t| t.text.as_str()).collect()
+    }
+
+    fn parse(content: &str, origin: &str) -> Result<Vec<String>, String> {
+        let file = TempFile::new(content);
+        let records = parse_file(file.path(), origin)?;
+        Ok(records.iter().map(|rec| rec.to_string()).collect())
+    }
+
+    #[test]
+    fn tokenize_folds_parentheses_and_drops_comments() {
+        let input = "@ IN SOA ns1 admin ( ; primary\n    2024010101 ; serial\n    1h 15m 1w 300 )\nwww A 192.0.2.1\n";
+        let entries = tokenize(input).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            texts(&entries[0]),
+            [
+                "@",
+                "IN",
+                "SOA",
+                "ns1",
+                "admin",
+                "2024010101",
+                "1h",
+                "15m",
+                "1w",
+                "300"
+            ]
+        );
+        assert_eq!(entries[0].line, 1);
+        assert_eq!(texts(&entries[1]), ["www", "A", "192.0.2.1"]);
+        assert_eq!(entries[1].line, 4);
+    }
+
+    #[test]
+    fn tokenize_marks_blank_owners() {
+        let entries = tokenize("www A 192.0.2.1\n\tAAAA 2001:db8::1\n\n  MX 10 mail\n").unwrap();
+
+        assert_eq!(entries.len(), 3);
+        assert!(!entries[0].blank_owner);
+        assert!(entries[1].blank_owner);
+        assert!(entries[2].blank_owner);
+        assert_eq!(entries[2].line, 4);
+    }
+
+    #[test]
+    fn tokenize_handles_quotes_and_escapes() {
+        let entries = tokenize(r#"txt TXT "a; \"b\" (c)" \065\.b"#).unwrap();
+        let tokens = &entries[0].tokens;
+
+        assert_eq!(texts(&entries[0]), ["txt", "TXT", "a; \"b\" (c)", "A.b"]);
+        assert!(tokens[2].quoted);
+        assert!(!tokens[3].quoted);
+    }
+
+    #[test]
+    fn tokenize_rejects_malformed_input() {
+        assert!(tokenize("a A 192.0.2.1 )\n").is_err());
+        assert!(tokenize("@ SOA ( ns1 admin\n").is_err());
+        assert!(tokenize("txt TXT \"open\n").is_err());
+        assert!(tokenize("a\\256 A 192.0.2.1\n").is_err());
+        assert!(tokenize("a\\1x A 192.0.2.1\n").is_err());
+    }
+
+    #[test]
+    fn parse_ttl_accepts_seconds_and_units() {
+        assert_eq!(parse_ttl("3600"), Some(3600));
+        assert_eq!(parse_ttl("1h30m"), Some(5400));
+        assert_eq!(parse_ttl("1W2d"), Some(777_600));
+        assert_eq!(parse_ttl("90s"), Some(90));
+        assert_eq!(parse_ttl("h"), None);
+        assert_eq!(parse_ttl("5m3"), None);
+        assert_eq!(parse_ttl("10x"), None);
+        assert_eq!(parse_ttl("9999999w"), None);
+    }
+
+    #[test]
+    fn parse_file_resolves_names_and_defaults() {
+        let zone = "\
+$TTL 1h
+@ IN SOA ns1 hostmaster.example.com. ( 1 2h 30m 2w 5m )
+  NS ns1
+ns1 300 IN A 192.0.2.53
+www IN 60 A 192.0.2.80
+    AAAA 2001:db8::80
+$ORIGIN sub.example.com.
+mail MX 10 @
+txt TXT \"hello world\" two
+";
+        let records = parse(zone, "Example.COM.").unwrap();
+
+        assert_eq!(
+            records,
+            [
+                "example.com. 3600 IN SOA ns1.example.com. hostmaster.example.com. 1 7200 1800 1209600 300",
+                "example.com. 3600 IN NS ns1.example.com.",
+                "ns1.example.com. 300 IN A 192.0.2.53",
+                "www.example.com. 60 IN A 192.0.2.80",
+                "www.example.com. 3600 IN AAAA 2001:db8::80",
+                "mail.sub.example.com. 3600 IN MX 10 sub.example.com.",
+                "txt.sub.example.com. 3600 IN TXT \"hello world\" \"two\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_file_falls_back_to_the_last_ttl_without_ttl_directive() {
+        let records = parse("a 120 A 192.0.2.1\nb A 192.0.2.2\n", "example.com").unwrap();
+        assert_eq!(records[1], "b.example.com. 120 IN A 192.0.2.2");
+
+        let err = parse("a A 192.0.2.1\n", "example.com").unwrap_err();
+        assert!(err.contains(":1: no TTL"), "{err}");
+    }
+
+    #[test]
+    fn parse_file_includes_files_with_their_own_origin() {
+        let included = TempFile::new("www A 192.0.2.1\n");
+        let zone = format!(
+            "$TTL 60\n$INCLUDE {} other.example.\nmail A 192.0.2.2\n",
+            included.path().file_name().unwrap().to_str().unwrap()
+        );
+        let records = parse(&zone, "example.com");
+
+        assert_eq!(
+            records.unwrap(),
+            [
+                "www.other.example. 60 IN A 192.0.2.1",
+                "mail.example.com. 60 IN A 192.0.2.2",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_file_rejects_unsupported_data() {
+        for zone in [
+            "$TTL 60\na CH A 192.0.2.1\n",
+            "$TTL 60\na A 192.0.2.256\n",
+            "$TTL 60\na SRV 0 0 53 b\n",
+            "$TTL 60\n  A 192.0.2.1\n",
+            "$BOGUS 1\n",
+        ] {
+            assert!(parse(zone, "example.com").is_err(), "{zone:?}");
+        }
+    }
+}
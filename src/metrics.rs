@@ -0,0 +1,450 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+use crate::{config::MetricsConfig, dns::QueryType, DNS_CACHE, METRICS, QUERY_LIMITER};
+
+// How long a client gets to send its request and take the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A monotonically increasing counter, partitioned by label values.
+#[derive(Debug)]
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: DashMap<Vec<String>, u64>,
+}
+
+impl Counter {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: DashMap::new(),
+        }
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        let key = labels.iter().map(|l| l.to_string()).collect();
+        *self.values.entry(key).or_insert(0) += 1;
+    }
+
+    /// Sum over all label values.
+    pub fn total(&self) -> u64 {
+        self.values.iter().map(|entry| *entry.value()).sum()
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+
+        // Unlabelled counters are always exported, even before the first event.
+        if self.labels.is_empty() && self.values.is_empty() {
+            let _ = writeln!(out, "{} 0", self.name);
+        }
+
+        for entry in self.values.iter() {
+            let _ = writeln!(
+                out,
+                "{}{} {}",
+                self.name,
+                label_set(self.labels, entry.key(), None),
+                entry.value()
+            );
+        }
+    }
+}
+
+/// A gauge tracking a value that goes up and down.
+#[derive(Debug)]
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicI64,
+}
+
+impl Gauge {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicI64::new(0),
+        }
+    }
+
+    /// Increments the gauge until the returned guard is dropped.
+    pub fn track(&self) -> GaugeGuard<'_> {
+        self.value.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(self)
+    }
+
+    fn render(&self, out: &mut String) {
+        gauge(
+            out,
+            self.name,
+            self.help,
+            self.value.load(Ordering::Relaxed),
+        );
+    }
+}
+
+pub struct GaugeGuard<'a>(&'a Gauge);
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.value.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+struct HistogramData {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// A latency histogram, partitioned by label values.
+#[derive(Debug)]
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: DashMap<Vec<String>, HistogramData>,
+}
+
+impl Histogram {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: DashMap::new(),
+        }
+    }
+
+    pub fn observe(&self, labels: &[&str], duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let key = labels.iter().map(|l| l.to_string()).collect();
+
+        let mut data = self.values.entry(key).or_default();
+        if data.counts.is_empty() {
+            data.counts = vec![0; LATENCY_BUCKETS.len()];
+        }
+
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(data.counts.iter_mut()) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        data.sum += secs;
+        data.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+
+        for entry in self.values.iter() {
+            let (labels, data) = entry.pair();
+
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&data.counts) {
+                let le = bound.to_string();
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {count}",
+                    self.name,
+                    label_set(self.labels, labels, Some(&le))
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                self.name,
+                label_set(self.labels, labels, Some("+Inf")),
+                data.count
+            );
+
+            let plain = label_set(self.labels, labels, None);
+            let _ = writeln!(out, "{}_sum{plain} {}", self.name, data.sum);
+            let _ = writeln!(out, "{}_count{plain} {}", self.name, data.count);
+        }
+    }
+}
+
+/// Everything the server exports on `/metrics`.
+#[derive(Debug)]
+pub struct Metrics {
+    pub queries: Counter,
+    pub query_duration: Histogram,
+    pub cache_hits: Counter,
+    pub cache_misses: Counter,
+    pub cache_evictions: Counter,
+    pub cache_prefetches: Counter,
+    pub cache_stale: Counter,
+    pub upstream_queries: Counter,
+    pub upstream_duration: Histogram,
+    pub upstream_timeouts: Counter,
+    pub dnssec_validations: Counter,
+    pub blocked: Counter,
+    pub rrl_limited: Counter,
+    pub inflight: Gauge,
+    pub dnstap_dropped: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            queries: Counter::new(
+                "dns_queries_total",
+                "Queries answered, by query type, response code and transport.",
+                &["qtype", "rcode", "transport"],
+            ),
+            query_duration: Histogram::new(
+                "dns_query_duration_seconds",
+                "Time taken to answer a query.",
+                &["transport"],
+            ),
+            cache_hits: Counter::new(
+                "dns_cache_hits_total",
+                "Answers served from the cache.",
+                &[],
+            ),
+            cache_misses: Counter::new(
+                "dns_cache_misses_total",
+                "Lookups not found in the cache.",
+                &[],
+            ),
+            cache_evictions: Counter::new(
+                "dns_cache_evictions_total",
+                "Entries evicted from the cache to stay within its limits.",
+                &["kind"],
+            ),
+            cache_prefetches: Counter::new(
+                "dns_cache_prefetches_total",
+                "Background refreshes of popular entries about to expire.",
+                &[],
+            ),
+            cache_stale: Counter::new(
+                "dns_cache_stale_answers_total",
+                "Expired answers served because a lookup failed or was slow.",
+                &["reason"],
+            ),
+            upstream_queries: Counter::new(
+                "dns_upstream_queries_total",
+                "Queries sent to forwarding upstreams, or to name servers during recursion.",
+                &["server"],
+            ),
+            upstream_duration: Histogram::new(
+                "dns_upstream_duration_seconds",
+                "Round trip time of upstream queries.",
+                &["server"],
+            ),
+            upstream_timeouts: Counter::new(
+                "dns_upstream_timeouts_total",
+                "Upstream queries that got no response in time.",
+                &["server"],
+            ),
+            dnssec_validations: Counter::new(
+                "dns_dnssec_validations_total",
+                "Recursive answers checked by DNSSEC validation, by result.",
+                &["result"],
+            ),
+            blocked: Counter::new(
+                "dns_blocked_queries_total",
+                "Queries answered by the blocklist or a response policy.",
+                &["source"],
+            ),
+            rrl_limited: Counter::new(
+                "dns_rrl_limited_total",
+                "Responses limited by response rate limiting, by decision.",
+                &["decision"],
+            ),
+            inflight: Gauge::new(
+                "dns_inflight_lookups",
+                "Recursive lookups currently in progress.",
+            ),
+            dnstap_dropped: Counter::new(
+                "dns_dnstap_dropped_total",
+                "dnstap frames dropped because the output queue was full.",
+                &[],
+            ),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        self.queries.render(&mut out);
+        self.query_duration.render(&mut out);
+        self.cache_hits.render(&mut out);
+        self.cache_misses.render(&mut out);
+        self.cache_evictions.render(&mut out);
+        self.cache_prefetches.render(&mut out);
+        self.cache_stale.render(&mut out);
+
+        let stats = DNS_CACHE
+            .get()
+            .map(|cache| cache.stats())
+            .unwrap_or_default();
+        header(
+            &mut out,
+            "dns_cache_entries",
+            "Entries currently in the cache.",
+            "gauge",
+        );
+        let _ = writeln!(
+            out,
+            "dns_cache_entries{{kind=\"positive\"}} {}",
+            stats.positive
+        );
+        let _ = writeln!(
+            out,
+            "dns_cache_entries{{kind=\"negative\"}} {}",
+            stats.negative
+        );
+        gauge(
+            &mut out,
+            "dns_cache_bytes",
+            "Estimated memory used by the cache.",
+            stats.bytes as i64,
+        );
+        self.upstream_queries.render(&mut out);
+        self.upstream_duration.render(&mut out);
+        self.upstream_timeouts.render(&mut out);
+        self.dnssec_validations.render(&mut out);
+        self.blocked.render(&mut out);
+        self.rrl_limited.render(&mut out);
+
+        header(
+            &mut out,
+            "dns_ratelimit_dropped_total",
+            "Queries dropped by per-client rate limiting.",
+            "counter",
+        );
+        let dropped = QUERY_LIMITER.get().map(|l| l.dropped()).unwrap_or(0);
+        let _ = writeln!(out, "dns_ratelimit_dropped_total {dropped}");
+
+        self.inflight.render(&mut out);
+        self.dnstap_dropped.render(&mut out);
+
+        out
+    }
+}
+
+/// Formats a query type as a label value, e.g. `A` or `TYPE65`.
+pub fn qtype_label(qtype: QueryType) -> String {
+    qtype.to_string()
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: i64) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn label_set(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{name}=\"{}\"", escape(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{le}\""));
+    }
+
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves `GET /metrics` in the Prometheus text format. Connections beyond
+/// the configured limit are closed straight away.
+pub async fn serve(listener: TcpListener, config: MetricsConfig) {
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("failed to accept metrics connection: {e}");
+                continue;
+            }
+        };
+
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            debug!("refusing metrics connection from {src}: too many connections");
+            continue;
+        };
+
+        tokio::spawn(async move {
+            let _permit = permit;
+
+            match timeout(REQUEST_TIMEOUT, handle_connection(stream)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("metrics request from {src} failed: {e}"),
+                Err(_) => debug!("metrics request from {src} timed out"),
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut chunk = [0; 1024];
+
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&chunk[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
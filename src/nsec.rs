@@ -0,0 +1,670 @@
+use std::cmp::Ordering;
+
+use ring::digest;
+
+use crate::{
+    buffer::{BytePacketBuffer, MAX_MESSAGE_SIZE},
+    dns::{base32hex, DnsRecord, QueryType},
+    dnssec::Security,
+    zone::{is_subdomain, parent_name},
+};
+
+// NSEC3 hash algorithm 1, SHA-1, the only one defined (RFC 5155 section 11).
+const NSEC3_SHA1: u8 = 1;
+
+// NSEC3 flag marking a span that may hide unsigned delegations.
+const NSEC3_OPT_OUT: u8 = 0x01;
+
+// Responses hashed with more NSEC3 iterations than this are treated as
+// insecure rather than spending the CPU on them (RFC 9276 section 3.2).
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+// DNAME, which like a delegation means names below aren't in the zone.
+const DNAME: QueryType = QueryType::UNKNOWN(39);
+
+/// Checks that authenticated NSEC or NSEC3 `records` prove there is no
+/// `qtype` data at `qname`: that the name doesn't exist for NXDOMAIN (RFC
+/// 4035 section 5.4, RFC 5155 sections 8.4 to 8.7), or has no such type for
+/// NODATA. Opt-out spans make the answer insecure rather than secure.
+pub fn prove_denial(
+    qname: &str,
+    qtype: QueryType,
+    nxdomain: bool,
+    records: &[&DnsRecord],
+) -> Security {
+    let nsecs = nsec_records(records);
+    if !nsecs.is_empty() {
+        let proved = if nxdomain {
+            nsec_proves_nxdomain(qname, &nsecs)
+        } else {
+            nsec_proves_nodata(qname, qtype, &nsecs)
+        };
+        return proved.map_or_else(Security::Bogus, |()| Security::Secure);
+    }
+
+    match nsec3_records(records) {
+        Ok(nsec3s) if !nsec3s.is_empty() => {
+            if nxdomain {
+                nsec3_proves_nxdomain(qname, &nsec3s)
+            } else {
+                nsec3_proves_nodata(qname, qtype, &nsec3s)
+            }
+        }
+        Ok(_) => Security::Bogus(format!(
+            "no NSEC or NSEC3 records prove that {qname} {qtype:?} doesn't exist"
+        )),
+        Err(security) => security,
+    }
+}
+
+/// Checks that a wildcard expanded into an answer at `owner`, whose RRSIG
+/// counts `labels` labels, was the closest match: that no name closer to
+/// `owner` exists (RFC 4035 section 5.3.4, RFC 5155 section 8.8).
+pub fn prove_wildcard_expansion(owner: &str, labels: u8, records: &[&DnsRecord]) -> Security {
+    let nsecs = nsec_records(records);
+    if nsecs.iter().any(|nsec| nsec.covers(owner)) {
+        return Security::Secure;
+    }
+
+    let nsec3s = match nsec3_records(records) {
+        Ok(nsec3s) => nsec3s,
+        Err(security) => return security,
+    };
+    let owner_labels: Vec<&str> = owner.split('.').filter(|l| !l.is_empty()).collect();
+    let next_closer = owner_labels[owner_labels.len() - labels as usize - 1..].join(".");
+    match nsec3s.iter().find(|nsec3| nsec3.covers(&next_closer)) {
+        Some(nsec3) if nsec3.opt_out => Security::Insecure,
+        Some(_) => Security::Secure,
+        None => Security::Bogus(format!(
+            "no proof that {owner} doesn't exist for its wildcard answer"
+        )),
+    }
+}
+
+/// Orders names as DNSSEC does (RFC 4034 section 6.1): label by label from
+/// the root, each compared as lowercased bytes.
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name: &str| {
+        name.split('.')
+            .filter(|l| !l.is_empty())
+            .rev()
+            .map(|l| l.to_ascii_lowercase())
+            .collect::<Vec<_>>()
+    };
+
+    labels(a).cmp(&labels(b))
+}
+
+/// Hashes `name` as NSEC3 does (RFC 5155 section 5), returning it in the
+/// lowercased base32hex form it takes as the first label of owner names.
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Option<String> {
+    let mut wire = BytePacketBuffer::with_size(MAX_MESSAGE_SIZE);
+    wire.write_qname(&name.to_ascii_lowercase()).ok()?;
+
+    let sha1 = |data: &[u8]| {
+        let mut context = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        context.update(data);
+        context.update(salt);
+        context.finish()
+    };
+    let mut hash = sha1(&wire.buf[..wire.pos()]);
+    for _ in 0..iterations {
+        hash = sha1(hash.as_ref());
+    }
+
+    Some(base32hex(hash.as_ref()).to_ascii_lowercase())
+}
+
+struct Nsec<'a> {
+    owner: &'a str,
+    next: &'a str,
+    types: &'a [QueryType],
+}
+
+impl Nsec<'_> {
+    fn matches(&self, name: &str) -> bool {
+        canonical_cmp(self.owner, name) == Ordering::Equal
+    }
+
+    /// Whether `name` falls strictly between the owner and the next name,
+    /// so doesn't exist. The last NSEC of a zone wraps around to the apex.
+    fn covers(&self, name: &str) -> bool {
+        let after_owner = canonical_cmp(self.owner, name) == Ordering::Less;
+        let before_next = canonical_cmp(name, self.next) == Ordering::Less;
+
+        if canonical_cmp(self.owner, self.next) == Ordering::Less {
+            after_owner && before_next
+        } else {
+            after_owner || before_next
+        }
+    }
+
+    /// Whether the owner is a delegation point, whose NSEC is the parent's
+    /// and says nothing about names below it (RFC 6840 section 4.1).
+    fn is_delegation(&self) -> bool {
+        is_delegation(self.types)
+    }
+}
+
+fn nsec_records<'a>(records: &[&'a DnsRecord]) -> Vec<Nsec<'a>> {
+    records
+        .iter()
+        .filter_map(|rec| match rec {
+            DnsRecord::NSEC {
+                domain,
+                next_domain,
+                types,
+                ..
+            } => Some(Nsec {
+                owner: domain,
+                next: next_domain,
+                types,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Finds the NSEC covering `qname` that isn't a delegation above it, and
+/// from it the closest encloser: the longest ancestor `qname` shares with
+/// either end of the span.
+fn nsec_closest_encloser(qname: &str, nsecs: &[Nsec]) -> Result<String, String> {
+    let cover = nsecs
+        .iter()
+        .find(|nsec| nsec.covers(qname))
+        .ok_or_else(|| format!("no NSEC covers {qname}"))?;
+    if cover.is_delegation() && is_subdomain(qname, cover.owner) {
+        return Err(format!("NSEC covering {qname} is from above a delegation"));
+    }
+
+    let owner = common_ancestor(qname, cover.owner);
+    let next = common_ancestor(qname, &cover.next.to_ascii_lowercase());
+    Ok(if owner.len() >= next.len() {
+        owner
+    } else {
+        next
+    })
+}
+
+fn nsec_proves_nxdomain(qname: &str, nsecs: &[Nsec]) -> Result<(), String> {
+    if nsecs.iter().any(|nsec| nsec.matches(qname)) {
+        return Err(format!("NSEC shows that {qname} exists"));
+    }
+
+    let wildcard = wildcard_of(&nsec_closest_encloser(qname, nsecs)?);
+    if nsecs.iter().any(|nsec| nsec.matches(&wildcard)) {
+        return Err(format!("NSEC shows that {wildcard} exists"));
+    }
+    if !nsecs.iter().any(|nsec| nsec.covers(&wildcard)) {
+        return Err(format!("no NSEC covers {wildcard}"));
+    }
+
+    Ok(())
+}
+
+fn nsec_proves_nodata(qname: &str, qtype: QueryType, nsecs: &[Nsec]) -> Result<(), String> {
+    if let Some(nsec) = nsecs.iter().find(|nsec| nsec.matches(qname)) {
+        if lacks_type(nsec.types, qtype) {
+            return Ok(());
+        }
+        return Err(format!("NSEC at {qname} doesn't rule out {qtype:?} data"));
+    }
+
+    // An empty non-terminal has no NSEC of its own, but the name after it
+    // is below it.
+    let empty_non_terminal = nsecs.iter().any(|nsec| {
+        let next = nsec.next.to_ascii_lowercase();
+        nsec.covers(qname) && next != qname && is_subdomain(&next, qname)
+    });
+    if empty_non_terminal {
+        return Ok(());
+    }
+
+    // Otherwise the answer came from a wildcard without the type.
+    let wildcard = wildcard_of(&nsec_closest_encloser(qname, nsecs)?);
+    match nsecs.iter().find(|nsec| nsec.matches(&wildcard)) {
+        Some(nsec) if lacks_type(nsec.types, qtype) => Ok(()),
+        _ => Err(format!("no NSEC proves that {qname} has no {qtype:?} data")),
+    }
+}
+
+struct Nsec3<'a> {
+    zone: &'a str,
+    /// The owner's hash and the next one, as lowercased base32hex, which
+    /// sorts the same as the hashes themselves.
+    hash: &'a str,
+    next: String,
+    salt: &'a [u8],
+    iterations: u16,
+    opt_out: bool,
+    types: &'a [QueryType],
+}
+
+impl Nsec3<'_> {
+    fn hash_of(&self, name: &str) -> Option<String> {
+        if !is_subdomain(name, self.zone) {
+            return None;
+        }
+        nsec3_hash(name, self.salt, self.iterations)
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        self.hash_of(name).is_some_and(|hash| hash == self.hash)
+    }
+
+    fn covers(&self, name: &str) -> bool {
+        let Some(hash) = self.hash_of(name) else {
+            return false;
+        };
+
+        let (hash, owner, next) = (hash.as_str(), self.hash, self.next.as_str());
+        if owner < next {
+            owner < hash && hash < next
+        } else {
+            owner < hash || hash < next
+        }
+    }
+}
+
+/// Picks out the NSEC3 records that can be used. If there are some but none
+/// we can, or they take too much hashing, the denial is insecure.
+fn nsec3_records<'a>(records: &[&'a DnsRecord]) -> Result<Vec<Nsec3<'a>>, Security> {
+    let mut found = false;
+    let mut nsec3s = Vec::new();
+
+    for rec in records {
+        let DnsRecord::NSEC3 {
+            domain,
+            hash_algorithm,
+            flags,
+            iterations,
+            salt,
+            next_hashed,
+            types,
+            ..
+        } = rec
+        else {
+            continue;
+        };
+        found = true;
+
+        if *hash_algorithm != NSEC3_SHA1 {
+            continue;
+        }
+        if *iterations > MAX_NSEC3_ITERATIONS {
+            return Err(Security::Insecure);
+        }
+
+        // The hash is the first label; in the root zone it's the only one.
+        let (hash, zone) = domain.split_once('.').unwrap_or((domain, ""));
+        nsec3s.push(Nsec3 {
+            zone,
+            hash,
+            next: base32hex(next_hashed).to_ascii_lowercase(),
+            salt,
+            iterations: *iterations,
+            opt_out: flags & NSEC3_OPT_OUT != 0,
+            types,
+        });
+    }
+
+    if found && nsec3s.is_empty() {
+        return Err(Security::Insecure);
+    }
+
+    Ok(nsec3s)
+}
+
+/// The closest encloser proof (RFC 5155 section 8.3): the longest ancestor
+/// of `qname` with a matching NSEC3, and a covering NSEC3 for the name one
+/// label longer, the next closer name. Returns the closest encloser and
+/// whether the covering NSEC3 is an opt-out span.
+fn nsec3_closest_encloser(qname: &str, nsec3s: &[Nsec3]) -> Result<(String, bool), String> {
+    let mut next_closer = qname;
+    let mut name = parent_name(qname);
+
+    while let Some(encloser) = name {
+        if let Some(nsec3) = nsec3s.iter().find(|nsec3| nsec3.matches(encloser)) {
+            if is_delegation(nsec3.types) || nsec3.types.contains(&DNAME) {
+                return Err(format!("closest encloser {encloser} is a delegation"));
+            }
+
+            return match nsec3s.iter().find(|nsec3| nsec3.covers(next_closer)) {
+                Some(cover) => Ok((encloser.to_string(), cover.opt_out)),
+                None => Err(format!("no NSEC3 covers {next_closer}")),
+            };
+        }
+
+        next_closer = encloser;
+        name = parent_name(encloser);
+    }
+
+    Err(format!("no NSEC3 proves a closest encloser of {qname}"))
+}
+
+fn nsec3_proves_nxdomain(qname: &str, nsec3s: &[Nsec3]) -> Security {
+    if nsec3s.iter().any(|nsec3| nsec3.matches(qname)) {
+        return Security::Bogus(format!("NSEC3 shows that {qname} exists"));
+    }
+
+    let (encloser, opt_out) = match nsec3_closest_encloser(qname, nsec3s) {
+        Ok(proof) => proof,
+        Err(e) => return Security::Bogus(e),
+    };
+
+    let wildcard = wildcard_of(&encloser);
+    if !nsec3s.iter().any(|nsec3| nsec3.covers(&wildcard)) {
+        return Security::Bogus(format!("no NSEC3 covers {wildcard}"));
+    }
+
+    // The name may be below an unsigned delegation the span skips over.
+    if opt_out {
+        Security::Insecure
+    } else {
+        Security::Secure
+    }
+}
+
+fn nsec3_proves_nodata(qname: &str, qtype: QueryType, nsec3s: &[Nsec3]) -> Security {
+    if let Some(nsec3) = nsec3s.iter().find(|nsec3| nsec3.matches(qname)) {
+        return if lacks_type(nsec3.types, qtype) {
+            Security::Secure
+        } else {
+            Security::Bogus(format!("NSEC3 at {qname} doesn't rule out {qtype:?} data"))
+        };
+    }
+
+    let (encloser, opt_out) = match nsec3_closest_encloser(qname, nsec3s) {
+        Ok(proof) => proof,
+        Err(e) => return Security::Bogus(e),
+    };
+
+    // No DS under an opt-out span: an unsigned delegation (section 8.6).
+    if qtype == QueryType::DS && opt_out {
+        return Security::Insecure;
+    }
+
+    // A wildcard without the type (section 8.7).
+    let wildcard = wildcard_of(&encloser);
+    match nsec3s.iter().find(|nsec3| nsec3.matches(&wildcard)) {
+        Some(nsec3) if lacks_type(nsec3.types, qtype) => Security::Secure,
+        _ => Security::Bogus(format!(
+            "no NSEC3 proves that {qname} has no {qtype:?} data"
+        )),
+    }
+}
+
+/// Whether a name with `types` can't answer `qtype`, directly or through a
+/// CNAME, and isn't a delegation whose NSEC belongs to the parent (except
+/// for DS, which the parent answers).
+fn lacks_type(types: &[QueryType], qtype: QueryType) -> bool {
+    !types.contains(&qtype)
+        && !types.contains(&QueryType::CNAME)
+        && (qtype == QueryType::DS || !is_delegation(types))
+}
+
+fn is_delegation(types: &[QueryType]) -> bool {
+    types.contains(&QueryType::NS) && !types.contains(&QueryType::SOA)
+}
+
+fn wildcard_of(name: &str) -> String {
+    if name.is_empty() {
+        "*".to_string()
+    } else {
+        format!("*.{name}")
+    }
+}
+
+/// The longest name that both `a` and `b` are at or below.
+fn common_ancestor(a: &str, b: &str) -> String {
+    let a_labels = a.split('.').filter(|l| !l.is_empty()).rev();
+    let b_labels = b.split('.').filter(|l| !l.is_empty()).rev();
+
+    let mut common: Vec<&str> = a_labels
+        .zip(b_labels)
+        .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+        .map(|(a, _)| a)
+        .collect();
+    common.reverse();
+
+    common.join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SALT: [u8; 4] = [0xaa, 0xbb, 0xcc, 0xdd];
+
+    fn nsec(owner: &str, next: &str, types: &[QueryType]) -> DnsRecord {
+        DnsRecord::NSEC {
+            domain: owner.to_string(),
+            next_domain: next.to_string(),
+            types: types.to_vec(),
+            ttl: 3600,
+        }
+    }
+
+    fn base32hex_decode(text: &str) -> Vec<u8> {
+        const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+        let mut bits = 0u64;
+        let mut count = 0;
+        let mut out = Vec::new();
+        for c in text.bytes() {
+            let value = ALPHABET.iter().position(|&a| a == c).unwrap() as u64;
+            bits = (bits << 5) | value;
+            count += 5;
+            if count >= 8 {
+                count -= 8;
+                out.push((bits >> count) as u8);
+            }
+        }
+        out
+    }
+
+    /// The NSEC3 chain for zone "example" with these names and types,
+    /// hashed as in RFC 5155 appendix A. Spans from `opt_out` owners are
+    /// flagged opt-out.
+    fn nsec3_chain(names: &[(&str, &[QueryType])], opt_out: &[&str]) -> Vec<DnsRecord> {
+        let mut hashed: Vec<_> = names
+            .iter()
+            .map(|(name, types)| {
+                let flags = if opt_out.contains(name) {
+                    NSEC3_OPT_OUT
+                } else {
+                    0
+                };
+                (nsec3_hash(name, &SALT, 12).unwrap(), flags, *types)
+            })
+            .collect();
+        hashed.sort_by(|a, b| a.0.cmp(&b.0));
+
+        (0..hashed.len())
+            .map(|i| {
+                let (hash, flags, types) = &hashed[i];
+                let next = &hashed[(i + 1) % hashed.len()].0;
+                DnsRecord::NSEC3 {
+                    domain: format!("{hash}.example"),
+                    hash_algorithm: NSEC3_SHA1,
+                    flags: *flags,
+                    iterations: 12,
+                    salt: SALT.to_vec(),
+                    next_hashed: base32hex_decode(next),
+                    types: types.to_vec(),
+                    ttl: 3600,
+                }
+            })
+            .collect()
+    }
+
+    fn example_chain(opt_out: &[&str]) -> Vec<DnsRecord> {
+        // w.example is an empty non-terminal above x.w.example.
+        nsec3_chain(
+            &[
+                ("example", &[QueryType::NS, QueryType::SOA, QueryType::MX]),
+                ("a.example", &[QueryType::NS, QueryType::DS]),
+                ("ns1.example", &[QueryType::A]),
+                ("w.example", &[]),
+                ("x.w.example", &[QueryType::MX]),
+            ],
+            opt_out,
+        )
+    }
+
+    #[test]
+    fn nsec3_hash_matches_rfc_5155_vectors() {
+        assert_eq!(
+            nsec3_hash("example", &SALT, 12).unwrap(),
+            "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"
+        );
+        assert_eq!(
+            nsec3_hash("A.Example", &SALT, 12).unwrap(),
+            "35mthgpgcu1qg68fab165klnsnk3dpvl"
+        );
+        assert_eq!(
+            nsec3_hash("x.w.example", &SALT, 12).unwrap(),
+            "b4um86eghhds6nea196smvmlo4ors995"
+        );
+    }
+
+    #[test]
+    fn canonical_order_matches_rfc_4034_example() {
+        let ordered = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "*.z.example",
+        ];
+        for pair in ordered.windows(2) {
+            assert_eq!(canonical_cmp(pair[0], pair[1]), Ordering::Less, "{pair:?}");
+        }
+        assert_eq!(canonical_cmp("Z.a.example", "z.A.example"), Ordering::Equal);
+    }
+
+    #[test]
+    fn nsec_proves_nxdomain() {
+        // RFC 4035 appendix B.2.
+        let cover = nsec("b.example", "ns1.example", &[QueryType::NS, QueryType::DS]);
+        let wildcard = nsec("example", "a.example", &[QueryType::NS, QueryType::SOA]);
+
+        let proof = prove_denial("ml.example", QueryType::A, true, &[&cover, &wildcard]);
+        assert!(matches!(proof, Security::Secure), "{proof:?}");
+
+        let proof = prove_denial("ml.example", QueryType::A, true, &[&cover]);
+        assert!(matches!(proof, Security::Bogus(_)), "{proof:?}");
+
+        let proof = prove_denial("b.example", QueryType::A, true, &[&cover, &wildcard]);
+        assert!(matches!(proof, Security::Bogus(_)), "{proof:?}");
+    }
+
+    #[test]
+    fn nsec_proves_nodata() {
+        // RFC 4035 appendix B.3.
+        let types = [QueryType::A, QueryType::RRSIG, QueryType::NSEC];
+        let ns1 = nsec("ns1.example", "ns2.example", &types);
+
+        let proof = prove_denial("ns1.example", QueryType::MX, false, &[&ns1]);
+        assert!(matches!(proof, Security::Secure), "{proof:?}");
+
+        let proof = prove_denial("ns1.example", QueryType::A, false, &[&ns1]);
+        assert!(matches!(proof, Security::Bogus(_)), "{proof:?}");
+    }
+
+    #[test]
+    fn parent_side_nsec_doesnt_prove_nodata_below_a_delegation() {
+        let types = [
+            QueryType::NS,
+            QueryType::DS,
+            QueryType::RRSIG,
+            QueryType::NSEC,
+        ];
+        let delegation = nsec("b.example", "ns1.example", &types);
+
+        let proof = prove_denial("b.example", QueryType::A, false, &[&delegation]);
+        assert!(matches!(proof, Security::Bogus(_)), "{proof:?}");
+
+        let types = [QueryType::NS, QueryType::RRSIG, QueryType::NSEC];
+        let unsigned = nsec("b.example", "ns1.example", &types);
+        let proof = prove_denial("b.example", QueryType::DS, false, &[&unsigned]);
+        assert!(matches!(proof, Security::Secure), "{proof:?}");
+    }
+
+    #[test]
+    fn nsec_proves_wildcard_expansion() {
+        // RFC 4035 appendix B.6: a.z.w.example MX from *.w.example.
+        let cover = nsec("x.y.w.example", "xx.example", &[QueryType::MX]);
+
+        let proof = prove_wildcard_expansion("a.z.w.example", 2, &[&cover]);
+        assert!(matches!(proof, Security::Secure), "{proof:?}");
+
+        let proof = prove_wildcard_expansion("a.z.w.example", 2, &[]);
+        assert!(matches!(proof, Security::Bogus(_)), "{proof:?}");
+    }
+
+    #[test]
+    fn nsec3_proves_nxdomain() {
+        let chain = example_chain(&[]);
+        let records: Vec<&DnsRecord> = chain.iter().collect();
+
+        let proof = prove_denial("c.example", QueryType::A, true, &records);
+        assert!(matches!(proof, Security::Secure), "{proof:?}");
+
+        let proof = prove_denial("ns1.example", QueryType::A, true, &records);
+        assert!(matches!(proof, Security::Bogus(_)), "{proof:?}");
+
+        // Without the span covering *.example, a wildcard might exist.
+        let partial: Vec<&DnsRecord> = chain
+            .iter()
+            .filter(|rec| !nsec3_records(&[rec]).unwrap()[0].covers("*.example"))
+            .collect();
+        assert_eq!(partial.len(), chain.len() - 1);
+        let proof = prove_denial("c.example", QueryType::A, true, &partial);
+        assert!(matches!(proof, Security::Bogus(_)), "{proof:?}");
+    }
+
+    #[test]
+    fn nsec3_opt_out_makes_nxdomain_insecure() {
+        // c.example falls in the span after a.example.
+        let chain = example_chain(&["a.example"]);
+        let records: Vec<&DnsRecord> = chain.iter().collect();
+
+        let proof = prove_denial("c.example", QueryType::A, true, &records);
+        assert!(matches!(proof, Security::Insecure), "{proof:?}");
+    }
+
+    #[test]
+    fn nsec3_proves_nodata() {
+        let chain = example_chain(&[]);
+        let records: Vec<&DnsRecord> = chain.iter().collect();
+
+        let proof = prove_denial("ns1.example", QueryType::MX, false, &records);
+        assert!(matches!(proof, Security::Secure), "{proof:?}");
+
+        let proof = prove_denial("w.example", QueryType::A, false, &records);
+        assert!(matches!(proof, Security::Secure), "{proof:?}");
+
+        let proof = prove_denial("ns1.example", QueryType::A, false, &records);
+        assert!(matches!(proof, Security::Bogus(_)), "{proof:?}");
+
+        // Names below a delegation aren't the zone's to deny.
+        let proof = prove_denial("b.a.example", QueryType::A, false, &records);
+        assert!(matches!(proof, Security::Bogus(_)), "{proof:?}");
+    }
+
+    #[test]
+    fn nsec3_with_too_many_iterations_is_insecure() {
+        let mut chain = example_chain(&[]);
+        for rec in &mut chain {
+            if let DnsRecord::NSEC3 { iterations, .. } = rec {
+                *iterations = MAX_NSEC3_ITERATIONS + 1;
+            }
+        }
+        let records: Vec<&DnsRecord> = chain.iter().collect();
+
+        let proof = prove_denial("c.example", QueryType::A, true, &records);
+        assert!(matches!(proof, Security::Insecure), "{proof:?}");
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::time::interval;
+use tracing::{info, warn};
+
+use crate::{
+    cidr::{mask, Cidr},
+    config::RateLimitConfig,
+    QUERY_LIMITER,
+};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    dropped: u64,
+}
+
+/// Per-client token buckets limiting how many queries a source address (or
+/// network) may send, checked before a query is handed to a task.
+#[derive(Debug)]
+pub struct QueryLimiter {
+    config: RateLimitConfig,
+    exempt: Vec<Cidr>,
+    buckets: DashMap<IpAddr, Bucket>,
+    dropped: AtomicU64,
+}
+
+impl QueryLimiter {
+    pub fn new(config: RateLimitConfig) -> Result<QueryLimiter, String> {
+        if config.ipv4_prefix > 32 || config.ipv6_prefix > 128 {
+            return Err("ratelimit prefix length out of range".into());
+        }
+
+        let exempt = config
+            .exempt
+            .iter()
+            .map(|network| network.parse::<Cidr>())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(QueryLimiter {
+            config,
+            exempt,
+            buckets: DashMap::new(),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Takes a token for a query from `client` arriving at `now`, returning
+    /// false if the query should be dropped.
+    pub fn allow(&self, client: IpAddr, now: Instant) -> bool {
+        let rate = self.config.queries_per_second as f64;
+        if rate == 0.0 || self.exempt.iter().any(|network| network.contains(client)) {
+            return true;
+        }
+
+        let prefix = match client {
+            IpAddr::V4(_) => self.config.ipv4_prefix,
+            IpAddr::V6(_) => self.config.ipv6_prefix,
+        };
+        let burst = self.config.burst.max(1) as f64;
+
+        let mut bucket = self
+            .buckets
+            .entry(mask(client, prefix))
+            .or_insert_with(|| Bucket {
+                tokens: burst,
+                last: now,
+                dropped: 0,
+            });
+
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.last = now;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+
+        bucket.dropped += 1;
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+
+        false
+    }
+
+    /// Total number of queries dropped since startup.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Reports the clients that were limited since the last report, and
+    /// forgets buckets that have refilled.
+    fn report_and_prune(&self) {
+        let rate = self.config.queries_per_second.max(1) as f64;
+        let burst = self.config.burst.max(1) as f64;
+        let refill = Duration::from_secs_f64(burst / rate);
+
+        let mut limited = false;
+        self.buckets.retain(|network, bucket| {
+            if bucket.dropped > 0 {
+                warn!("rate limited {network}: dropped {} queries", bucket.dropped);
+                bucket.dropped = 0;
+                limited = true;
+            }
+
+            bucket.last.elapsed() < refill
+        });
+
+        if limited {
+            info!(
+                "rate limiting dropped {} queries since startup",
+                self.dropped()
+            );
+        }
+    }
+}
+
+pub fn spawn_reporter() {
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(60));
+
+        loop {
+            ticker.tick().await;
+            QUERY_LIMITER.get().unwrap().report_and_prune();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(config: RateLimitConfig) -> QueryLimiter {
+        QueryLimiter::new(RateLimitConfig {
+            queries_per_second: 2,
+            ..config
+        })
+        .unwrap()
+    }
+
+    fn allow(limiter: &QueryLimiter, client: &str, now: Instant) -> bool {
+        limiter.allow(client.parse().unwrap(), now)
+    }
+
+    #[test]
+    fn allows_bursts_and_refills_at_the_rate() {
+        let limiter = limiter(RateLimitConfig {
+            burst: 3,
+            ..Default::default()
+        });
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(allow(&limiter, "192.0.2.1", start));
+        }
+        assert!(!allow(&limiter, "192.0.2.1", start));
+
+        // Half a second buys one query, and a long pause no more than the
+        // burst.
+        let later = start + Duration::from_millis(500);
+        assert!(allow(&limiter, "192.0.2.1", later));
+        assert!(!allow(&limiter, "192.0.2.1", later));
+
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(allow(&limiter, "192.0.2.1", much_later));
+        }
+        assert!(!allow(&limiter, "192.0.2.1", much_later));
+
+        assert_eq!(limiter.dropped(), 3);
+    }
+
+    #[test]
+    fn groups_clients_by_prefix() {
+        let limiter = limiter(RateLimitConfig {
+            burst: 1,
+            ipv4_prefix: 24,
+            ipv6_prefix: 48,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        assert!(allow(&limiter, "192.0.2.1", now));
+        assert!(!allow(&limiter, "192.0.2.254", now));
+        assert!(allow(&limiter, "192.0.3.1", now));
+
+        assert!(allow(&limiter, "2001:db8::1", now));
+        assert!(!allow(&limiter, "2001:db8:0:ffff::1", now));
+        assert!(allow(&limiter, "2001:db8:1::1", now));
+    }
+
+    #[test]
+    fn never_limits_exempt_networks() {
+        let limiter = limiter(RateLimitConfig {
+            burst: 1,
+            exempt: vec!["10.0.0.0/8".to_string()],
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            assert!(allow(&limiter, "10.1.2.3", now));
+        }
+        assert_eq!(limiter.dropped(), 0);
+    }
+
+    #[test]
+    fn zero_rate_disables_limiting() {
+        let limiter = QueryLimiter::new(RateLimitConfig::default()).unwrap();
+        let now = Instant::now();
+
+        for _ in 0..1000 {
+            assert!(allow(&limiter, "192.0.2.1", now));
+        }
+    }
+}
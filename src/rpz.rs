@@ -0,0 +1,552 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use tracing::warn;
+
+use crate::{
+    cidr::Cidr,
+    config::ZoneConfig,
+    dns::{DnsPacket, DnsQuestion, DnsRecord, ResultCode},
+    master,
+    zone::parent_name,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    ClientIp,
+    Qname,
+    ResponseIp,
+    Nsdname,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Trigger::ClientIp => "client-ip",
+            Trigger::Qname => "qname",
+            Trigger::ResponseIp => "response-ip",
+            Trigger::Nsdname => "nsdname",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// What to do with a query matching a policy rule, encoded in RPZ as the
+/// rule's CNAME target or, for local data, any other records.
+#[derive(Debug, Clone)]
+pub enum PolicyAction {
+    Nxdomain,
+    Nodata,
+    Passthru,
+    Drop,
+    LocalData(Vec<DnsRecord>),
+}
+
+impl PolicyAction {
+    fn from_records(records: &[DnsRecord]) -> PolicyAction {
+        if let [DnsRecord::CNAME { host, .. }] = records {
+            match host.as_str() {
+                "" => return PolicyAction::Nxdomain,
+                "*" => return PolicyAction::Nodata,
+                "rpz-passthru" => return PolicyAction::Passthru,
+                "rpz-drop" => return PolicyAction::Drop,
+                // We answer over the transport the query came in on.
+                "rpz-tcp-only" => return PolicyAction::Passthru,
+                _ => {}
+            }
+        }
+
+        PolicyAction::LocalData(records.to_vec())
+    }
+}
+
+impl fmt::Display for PolicyAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyAction::Nxdomain => f.write_str("NXDOMAIN"),
+            PolicyAction::Nodata => f.write_str("NODATA"),
+            PolicyAction::Passthru => f.write_str("PASSTHRU"),
+            PolicyAction::Drop => f.write_str("DROP"),
+            PolicyAction::LocalData(records) => match records.as_slice() {
+                [DnsRecord::CNAME { host, .. }] => write!(f, "CNAME {host}"),
+                _ => write!(f, "local data ({} records)", records.len()),
+            },
+        }
+    }
+}
+
+/// A matched policy rule.
+#[derive(Debug, Clone)]
+pub struct PolicyHit {
+    pub zone: String,
+    pub trigger: Trigger,
+    /// The name or network the trigger matched on.
+    pub matched: String,
+    pub action: PolicyAction,
+}
+
+impl PolicyHit {
+    pub fn is_passthru(&self) -> bool {
+        matches!(self.action, PolicyAction::Passthru)
+    }
+
+    /// Builds the response the policy calls for, or `None` when the query is
+    /// to be dropped. Passthru hits are left for the caller to resolve.
+    pub fn respond(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+
+        match &self.action {
+            PolicyAction::Drop => return None,
+            PolicyAction::Nxdomain => packet.header.rescode = ResultCode::NXDOMAIN,
+            PolicyAction::Nodata | PolicyAction::Passthru => {}
+            PolicyAction::LocalData(records) => {
+                for rec in records {
+                    let rec = match rec {
+                        // `CNAME *.example.` rewrites to the query name
+                        // prepended to the target.
+                        DnsRecord::CNAME { host, ttl, .. } if host.starts_with("*.") => {
+                            DnsRecord::CNAME {
+                                domain: question.name.clone(),
+                                host: format!("{}{}", question.name, &host[1..]),
+                                ttl: *ttl,
+                            }
+                        }
+                        DnsRecord::CNAME { .. } => rec.with_domain(&question.name),
+                        rec if rec.qtype() == question.qtype => rec.with_domain(&question.name),
+                        _ => continue,
+                    };
+
+                    packet.answers.push(rec);
+                }
+            }
+        }
+
+        Some(packet)
+    }
+}
+
+impl fmt::Display for PolicyHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rpz {} {} {} -> {}",
+            self.zone, self.trigger, self.matched, self.action
+        )
+    }
+}
+
+/// One response policy zone, with its rules split by trigger.
+#[derive(Debug)]
+struct PolicyZone {
+    name: String,
+    qname: HashMap<String, Vec<DnsRecord>>,
+    nsdname: HashMap<String, Vec<DnsRecord>>,
+    client_ip: Vec<(Cidr, Vec<DnsRecord>)>,
+    response_ip: Vec<(Cidr, Vec<DnsRecord>)>,
+}
+
+impl PolicyZone {
+    fn load(config: &ZoneConfig) -> Result<PolicyZone, String> {
+        let origin = master::normalize_name(&config.origin);
+        let records = master::parse_file(Path::new(&config.file), &origin)?;
+
+        PolicyZone::from_records(&origin, records).map_err(|e| format!("{}: {e}", config.file))
+    }
+
+    fn from_records(origin: &str, records: Vec<DnsRecord>) -> Result<PolicyZone, String> {
+        let mut zone = PolicyZone {
+            name: origin.to_string(),
+            qname: HashMap::new(),
+            nsdname: HashMap::new(),
+            client_ip: Vec::new(),
+            response_ip: Vec::new(),
+        };
+
+        let mut rules: HashMap<String, Vec<DnsRecord>> = HashMap::new();
+        for rec in records {
+            // The apex only holds the SOA and NS needed to make a valid zone.
+            let Some(owner) = rec.domain().strip_suffix(&format!(".{origin}")) else {
+                continue;
+            };
+
+            rules.entry(owner.to_string()).or_default().push(rec);
+        }
+
+        for (owner, records) in rules {
+            if let Some(ip) = owner.strip_suffix(".rpz-client-ip") {
+                let net = parse_ip_trigger(ip).map_err(|e| format!("{owner}: {e}"))?;
+                zone.client_ip.push((net, records));
+            } else if let Some(ip) = owner.strip_suffix(".rpz-ip") {
+                let net = parse_ip_trigger(ip).map_err(|e| format!("{owner}: {e}"))?;
+                zone.response_ip.push((net, records));
+            } else if let Some(name) = owner.strip_suffix(".rpz-nsdname") {
+                zone.nsdname.insert(name.to_string(), records);
+            } else if owner.ends_with(".rpz-nsip") {
+                warn!("{origin}: nsip trigger {owner} is not supported");
+            } else {
+                zone.qname.insert(owner, records);
+            }
+        }
+
+        // Longest prefixes first, so the most specific network wins.
+        zone.client_ip
+            .sort_by_key(|(net, _)| std::cmp::Reverse(net.prefix()));
+        zone.response_ip
+            .sort_by_key(|(net, _)| std::cmp::Reverse(net.prefix()));
+
+        Ok(zone)
+    }
+
+    fn hit(&self, trigger: Trigger, matched: String, records: &[DnsRecord]) -> PolicyHit {
+        PolicyHit {
+            zone: self.name.clone(),
+            trigger,
+            matched,
+            action: PolicyAction::from_records(records),
+        }
+    }
+
+    fn match_ip(
+        &self,
+        trigger: Trigger,
+        rules: &[(Cidr, Vec<DnsRecord>)],
+        addr: IpAddr,
+    ) -> Option<PolicyHit> {
+        rules
+            .iter()
+            .find(|(net, _)| net.contains(addr))
+            .map(|(net, records)| self.hit(trigger, net.to_string(), records))
+    }
+
+    fn match_name(
+        &self,
+        trigger: Trigger,
+        rules: &HashMap<String, Vec<DnsRecord>>,
+        name: &str,
+    ) -> Option<PolicyHit> {
+        if let Some(records) = rules.get(name) {
+            return Some(self.hit(trigger, name.to_string(), records));
+        }
+
+        // `*.example.com` covers every name below example.com, but not
+        // example.com itself.
+        let mut node = parent_name(name);
+        while let Some(parent) = node {
+            let wildcard = format!("*.{parent}");
+            if let Some(records) = rules.get(&wildcard) {
+                return Some(self.hit(trigger, wildcard, records));
+            }
+            node = parent_name(parent);
+        }
+
+        None
+    }
+}
+
+/// The configured response policy zones, consulted in order; the first zone
+/// with a matching rule decides.
+#[derive(Debug, Default)]
+pub struct Rpz {
+    zones: Vec<PolicyZone>,
+}
+
+impl Rpz {
+    /// Loads the zones in `configs`. With `forwarding`, no name servers are
+    /// consulted, so zones with NSDNAME rules get a warning that they can't
+    /// fire.
+    pub fn load(configs: &[ZoneConfig], forwarding: bool) -> Result<Rpz, String> {
+        let zones = configs
+            .iter()
+            .map(PolicyZone::load)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if forwarding {
+            for (zone, config) in zones.iter().zip(configs) {
+                if !zone.nsdname.is_empty() {
+                    warn!(
+                        "{}: {} nsdname trigger(s) never fire while forwarding",
+                        config.file,
+                        zone.nsdname.len()
+                    );
+                }
+            }
+        }
+
+        Ok(Rpz { zones })
+    }
+
+    /// Checks the triggers known before resolution: client IP, then QNAME.
+    pub fn check_query(&self, qname: &str, client: IpAddr) -> Option<PolicyHit> {
+        self.zones.iter().find_map(|zone| {
+            zone.match_ip(Trigger::ClientIp, &zone.client_ip, client)
+                .or_else(|| zone.match_name(Trigger::Qname, &zone.qname, qname))
+        })
+    }
+
+    /// Checks the triggers that depend on resolution: addresses in the
+    /// answer, then the names of the name servers that were consulted.
+    pub fn check_response(
+        &self,
+        response: &DnsPacket,
+        nameservers: &[String],
+    ) -> Option<PolicyHit> {
+        let addrs: Vec<IpAddr> = response
+            .answers
+            .iter()
+            .filter_map(|rec| match rec {
+                DnsRecord::A { addr, .. } => Some(IpAddr::V4(*addr)),
+                DnsRecord::AAAA { addr, .. } => Some(IpAddr::V6(*addr)),
+                _ => None,
+            })
+            .collect();
+
+        self.zones.iter().find_map(|zone| {
+            addrs
+                .iter()
+                .find_map(|addr| zone.match_ip(Trigger::ResponseIp, &zone.response_ip, *addr))
+                .or_else(|| {
+                    nameservers
+                        .iter()
+                        .find_map(|ns| zone.match_name(Trigger::Nsdname, &zone.nsdname, ns))
+                })
+        })
+    }
+}
+
+/// Decodes the owner of an IP trigger, e.g. `24.0.2.0.192` for 192.0.2.0/24
+/// or `64.zz.2.0.8b0d.2001` for 2001:8b0d:0:2::/64, with `zz` standing for a
+/// run of zero groups.
+fn parse_ip_trigger(name: &str) -> Result<Cidr, String> {
+    let labels: Vec<&str> = name.split('.').collect();
+    let prefix = labels[0]
+        .parse::<u8>()
+        .map_err(|_| "invalid prefix length".to_string())?;
+    let parts: Vec<&str> = labels[1..].iter().rev().copied().collect();
+
+    if parts.len() == 4 && !parts.contains(&"zz") {
+        if let Ok(octets) = parts
+            .iter()
+            .map(|p| p.parse::<u8>())
+            .collect::<Result<Vec<_>, _>>()
+        {
+            let addr = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+            return Cidr::new(IpAddr::V4(addr), prefix);
+        }
+    }
+
+    let mut groups = Vec::with_capacity(8);
+    for part in &parts {
+        if *part == "zz" {
+            let fill = 8usize
+                .checked_sub(parts.len() - 1)
+                .ok_or("too many IPv6 groups")?;
+            groups.extend(std::iter::repeat_n(0, fill));
+        } else {
+            groups.push(u16::from_str_radix(part, 16).map_err(|e| e.to_string())?);
+        }
+    }
+
+    let groups: [u16; 8] = groups
+        .try_into()
+        .map_err(|_| "IPv6 trigger needs 8 groups".to_string())?;
+
+    Cidr::new(IpAddr::V6(Ipv6Addr::from(groups)), prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::QueryType;
+
+    const ZONE: &str = "\
+$TTL 60
+@ SOA localhost. root.localhost. 1 3600 600 86400 60
+  NS localhost.
+bad.example.com CNAME .
+*.bad.example.com CNAME .
+empty.example.com CNAME *.
+ok.bad.example.com CNAME rpz-passthru.
+drop.example.com CNAME rpz-drop.
+walled.example.com CNAME garden.example.net.
+*.rewrite.example.com CNAME *.rewritten.example.net.
+local.example.com A 192.0.2.1
+  TXT \"policy\"
+32.1.2.0.192.rpz-client-ip CNAME rpz-drop.
+24.0.2.0.192.rpz-ip CNAME .
+128.1.zz.db8.2001.rpz-ip CNAME *.
+ns.evil.example.rpz-nsdname CNAME .
+*.sinkhole.example.rpz-nsdname CNAME rpz-passthru.
+";
+
+    fn load(zone: &str) -> Result<Rpz, String> {
+        let records = master::parse_str(zone, "rpz.local")?;
+        let zone = PolicyZone::from_records("rpz.local", records)?;
+
+        Ok(Rpz { zones: vec![zone] })
+    }
+
+    fn query(rpz: &Rpz, qname: &str, client: &str) -> Option<String> {
+        let hit = rpz.check_query(qname, client.parse().unwrap())?;
+        Some(hit.to_string())
+    }
+
+    fn answer(records: Vec<DnsRecord>) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers = records;
+        packet
+    }
+
+    #[test]
+    fn parses_ip_triggers() {
+        let cases = [
+            ("32.1.0.0.127", "127.0.0.1/32"),
+            ("24.0.2.0.192", "192.0.2.0/24"),
+            ("128.1.zz.3.2.2001", "2001:2:3::1/128"),
+            ("48.zz.8b0d.2001", "2001:8b0d::/48"),
+            ("64.zz.2.0.8b0d.2001", "2001:8b0d:0:2::/64"),
+            ("128.8.7.6.5.4.3.2.1", "1:2:3:4:5:6:7:8/128"),
+        ];
+        for (trigger, net) in cases {
+            assert_eq!(parse_ip_trigger(trigger).unwrap().to_string(), net);
+        }
+
+        for trigger in [
+            "33.1.0.0.127",
+            "x.1.0.0.127",
+            "64.3.2.2001",
+            "64.zz.9.8.7.6.5.4.3.2.1",
+            "64.zz.g.2001",
+        ] {
+            assert!(parse_ip_trigger(trigger).is_err(), "{trigger}");
+        }
+    }
+
+    #[test]
+    fn matches_qname_triggers_and_wildcards() {
+        let rpz = load(ZONE).unwrap();
+        let client = "203.0.113.1";
+
+        assert_eq!(
+            query(&rpz, "bad.example.com", client).unwrap(),
+            "rpz rpz.local qname bad.example.com -> NXDOMAIN"
+        );
+        assert_eq!(
+            query(&rpz, "a.b.bad.example.com", client).unwrap(),
+            "rpz rpz.local qname *.bad.example.com -> NXDOMAIN"
+        );
+        assert_eq!(
+            query(&rpz, "ok.bad.example.com", client).unwrap(),
+            "rpz rpz.local qname ok.bad.example.com -> PASSTHRU"
+        );
+        assert_eq!(
+            query(&rpz, "empty.example.com", client).unwrap(),
+            "rpz rpz.local qname empty.example.com -> NODATA"
+        );
+        assert_eq!(
+            query(&rpz, "walled.example.com", client).unwrap(),
+            "rpz rpz.local qname walled.example.com -> CNAME garden.example.net"
+        );
+        assert!(query(&rpz, "rewrite.example.com", client).is_none());
+        assert!(query(&rpz, "example.com", client).is_none());
+    }
+
+    #[test]
+    fn matches_client_ip_before_qname() {
+        let rpz = load(ZONE).unwrap();
+
+        assert_eq!(
+            query(&rpz, "bad.example.com", "192.0.2.1").unwrap(),
+            "rpz rpz.local client-ip 192.0.2.1/32 -> DROP"
+        );
+        assert_eq!(
+            query(&rpz, "bad.example.com", "::ffff:192.0.2.1").unwrap(),
+            "rpz rpz.local client-ip 192.0.2.1/32 -> DROP"
+        );
+    }
+
+    #[test]
+    fn matches_response_ip_and_nsdname_triggers() {
+        let rpz = load(ZONE).unwrap();
+
+        let response = answer(vec![DnsRecord::A {
+            domain: "www.example.org".to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 200),
+            ttl: 60,
+        }]);
+        let hit = rpz.check_response(&response, &[]).unwrap();
+        assert_eq!(
+            hit.to_string(),
+            "rpz rpz.local response-ip 192.0.2.0/24 -> NXDOMAIN"
+        );
+
+        let response = answer(vec![DnsRecord::AAAA {
+            domain: "www.example.org".to_string(),
+            addr: "2001:db8::1".parse().unwrap(),
+            ttl: 60,
+        }]);
+        let hit = rpz.check_response(&response, &[]).unwrap();
+        assert_eq!(
+            hit.to_string(),
+            "rpz rpz.local response-ip 2001:db8::1/128 -> NODATA"
+        );
+
+        let response = answer(Vec::new());
+        let nameservers = ["a.example.org".to_string(), "ns.evil.example".to_string()];
+        let hit = rpz.check_response(&response, &nameservers).unwrap();
+        assert_eq!(
+            hit.to_string(),
+            "rpz rpz.local nsdname ns.evil.example -> NXDOMAIN"
+        );
+
+        let hit = rpz
+            .check_response(&response, &["ns1.sinkhole.example".to_string()])
+            .unwrap();
+        assert!(hit.is_passthru());
+
+        assert!(rpz
+            .check_response(&response, &["ns.example.org".to_string()])
+            .is_none());
+    }
+
+    #[test]
+    fn builds_responses_from_local_data() {
+        let rpz = load(ZONE).unwrap();
+        let client = "203.0.113.1".parse().unwrap();
+
+        let hit = rpz.check_query("local.example.com", client).unwrap();
+        let question = DnsQuestion::new("local.example.com".to_string(), QueryType::TXT);
+        let packet = hit.respond(&question).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(
+            packet.answers[0].to_string(),
+            "local.example.com. 60 IN TXT \"policy\""
+        );
+
+        let hit = rpz.check_query("www.rewrite.example.com", client).unwrap();
+        let question = DnsQuestion::new("www.rewrite.example.com".to_string(), QueryType::A);
+        let packet = hit.respond(&question).unwrap();
+        assert_eq!(
+            packet.answers[0].to_string(),
+            "www.rewrite.example.com. 60 IN CNAME www.rewrite.example.com.rewritten.example.net."
+        );
+
+        let hit = rpz.check_query("bad.example.com", client).unwrap();
+        let packet = hit.respond(&question).unwrap();
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+
+        let hit = rpz.check_query("drop.example.com", client).unwrap();
+        assert!(hit.respond(&question).is_none());
+    }
+
+    #[test]
+    fn rejects_bad_ip_triggers() {
+        let err = load("$TTL 60\n40.1.2.3.4.rpz-ip.rpz.local. CNAME .\n").unwrap_err();
+        assert!(err.contains("40.1.2.3.4.rpz-ip"), "{err}");
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::time::interval;
+use tracing::{debug, info};
+
+use crate::{
+    cidr::mask,
+    config::RrlConfig,
+    dns::{QueryType, ResultCode},
+    RRL,
+};
+
+/// What to do with a UDP response after rate limiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RrlDecision {
+    Send,
+    /// Send an empty, truncated (TC=1) response so that genuine clients
+    /// retry over TCP.
+    Slip,
+    Drop,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RrlKey {
+    network: IpAddr,
+    qname: String,
+    qtype: QueryType,
+    rcode: u8,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    limited: u64,
+}
+
+/// Response Rate Limiting: caps how many identical responses a client network
+/// receives per second, to blunt reflection and amplification attacks.
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RrlConfig,
+    buckets: DashMap<RrlKey, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RrlConfig) -> Result<RateLimiter, String> {
+        if config.ipv4_prefix > 32 || config.ipv6_prefix > 128 {
+            return Err("rrl prefix length out of range".into());
+        }
+
+        Ok(RateLimiter {
+            config,
+            buckets: DashMap::new(),
+        })
+    }
+
+    /// Decides on a response to `client` sent at `now`.
+    pub fn check(
+        &self,
+        client: IpAddr,
+        qname: &str,
+        qtype: QueryType,
+        rcode: ResultCode,
+        now: Instant,
+    ) -> RrlDecision {
+        let rate = self.config.responses_per_second as f64;
+        if rate == 0.0 {
+            return RrlDecision::Send;
+        }
+
+        let prefix = match client {
+            IpAddr::V4(_) => self.config.ipv4_prefix,
+            IpAddr::V6(_) => self.config.ipv6_prefix,
+        };
+        let key = RrlKey {
+            network: mask(client, prefix),
+            qname: qname.to_string(),
+            qtype,
+            rcode: rcode as u8,
+        };
+
+        let mut bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: rate,
+            last: now,
+            limited: 0,
+        });
+
+        // Credit the elapsed time, and let a flood run up a debt of up to
+        // `window` seconds so that it has to stop for a while to recover.
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.last = now;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - 1.0;
+        bucket.tokens = bucket.tokens.max(-rate * self.config.window as f64);
+
+        if bucket.tokens >= 0.0 {
+            return RrlDecision::Send;
+        }
+
+        bucket.limited += 1;
+        let decision = match self.config.slip {
+            0 => RrlDecision::Drop,
+            slip if bucket.limited.is_multiple_of(slip as u64) => RrlDecision::Slip,
+            _ => RrlDecision::Drop,
+        };
+
+        if self.config.log_only {
+            info!("RRL would {decision:?} response to {client} for {qname} {qtype:?} {rcode:?}");
+            return RrlDecision::Send;
+        }
+
+        debug!("RRL {decision:?} response to {client} for {qname} {qtype:?} {rcode:?}");
+        decision
+    }
+
+    /// Forgets buckets that have been idle long enough to be full again.
+    fn prune(&self) {
+        let idle = Duration::from_secs(self.config.window + 1);
+        self.buckets
+            .retain(|_, bucket| bucket.last.elapsed() < idle);
+    }
+}
+
+pub fn spawn_pruner() {
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(60));
+
+        loop {
+            ticker.tick().await;
+            RRL.get().unwrap().prune();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(config: RrlConfig) -> RateLimiter {
+        RateLimiter::new(RrlConfig {
+            responses_per_second: 2,
+            ..config
+        })
+        .unwrap()
+    }
+
+    fn check(rrl: &RateLimiter, client: &str, now: Instant) -> RrlDecision {
+        let client = client.parse().unwrap();
+        rrl.check(
+            client,
+            "example.com",
+            QueryType::A,
+            ResultCode::NOERROR,
+            now,
+        )
+    }
+
+    #[test]
+    fn refills_tokens_over_time() {
+        let rrl = limiter(RrlConfig {
+            slip: 0,
+            ..Default::default()
+        });
+        let start = Instant::now();
+
+        assert_eq!(check(&rrl, "192.0.2.1", start), RrlDecision::Send);
+        assert_eq!(check(&rrl, "192.0.2.1", start), RrlDecision::Send);
+        assert_eq!(check(&rrl, "192.0.2.1", start), RrlDecision::Drop);
+
+        // The drop put the bucket half a second into debt, so after a second
+        // it holds one token again.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(check(&rrl, "192.0.2.1", later), RrlDecision::Send);
+        assert_eq!(check(&rrl, "192.0.2.1", later), RrlDecision::Drop);
+
+        // Other names and types have buckets of their own.
+        let client = "192.0.2.1".parse().unwrap();
+        let decision = rrl.check(
+            client,
+            "example.com",
+            QueryType::AAAA,
+            ResultCode::NOERROR,
+            later,
+        );
+        assert_eq!(decision, RrlDecision::Send);
+    }
+
+    #[test]
+    fn floods_run_up_a_debt_of_at_most_the_window() {
+        let rrl = limiter(RrlConfig {
+            slip: 0,
+            window: 5,
+            ..Default::default()
+        });
+        let start = Instant::now();
+
+        for _ in 0..1000 {
+            check(&rrl, "192.0.2.1", start);
+        }
+
+        // Ten tokens of debt take five seconds to pay off, however long the
+        // flood went on.
+        let almost = start + Duration::from_millis(5400);
+        assert_eq!(check(&rrl, "192.0.2.1", almost), RrlDecision::Drop);
+        let after = almost + Duration::from_secs(6);
+        assert_eq!(check(&rrl, "192.0.2.1", after), RrlDecision::Send);
+    }
+
+    #[test]
+    fn slips_every_slipth_limited_response() {
+        let rrl = limiter(RrlConfig {
+            slip: 3,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        let decisions: Vec<RrlDecision> = (0..8).map(|_| check(&rrl, "192.0.2.1", now)).collect();
+        assert_eq!(
+            decisions,
+            [
+                RrlDecision::Send,
+                RrlDecision::Send,
+                RrlDecision::Drop,
+                RrlDecision::Drop,
+                RrlDecision::Slip,
+                RrlDecision::Drop,
+                RrlDecision::Drop,
+                RrlDecision::Slip,
+            ]
+        );
+    }
+
+    #[test]
+    fn log_only_sends_everything() {
+        let rrl = limiter(RrlConfig {
+            log_only: true,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert_eq!(check(&rrl, "192.0.2.1", now), RrlDecision::Send);
+        }
+    }
+
+    #[test]
+    fn groups_clients_by_prefix() {
+        let rrl = limiter(RrlConfig {
+            slip: 0,
+            ..Default::default()
+        });
+        let now = Instant::now();
+
+        // 192.0.2.0/24 shares one bucket, 192.0.3.0/24 has its own.
+        assert_eq!(check(&rrl, "192.0.2.1", now), RrlDecision::Send);
+        assert_eq!(check(&rrl, "192.0.2.200", now), RrlDecision::Send);
+        assert_eq!(check(&rrl, "192.0.2.7", now), RrlDecision::Drop);
+        assert_eq!(check(&rrl, "192.0.3.1", now), RrlDecision::Send);
+
+        // Likewise 2001:db8::/56.
+        assert_eq!(check(&rrl, "2001:db8::1", now), RrlDecision::Send);
+        assert_eq!(check(&rrl, "2001:db8:0:ff::1", now), RrlDecision::Send);
+        assert_eq!(check(&rrl, "2001:db8::2", now), RrlDecision::Drop);
+        assert_eq!(check(&rrl, "2001:db8:0:100::1", now), RrlDecision::Send);
+    }
+
+    #[test]
+    fn rejects_prefixes_out_of_range() {
+        let config = RrlConfig {
+            ipv6_prefix: 129,
+            ..Default::default()
+        };
+        assert!(RateLimiter::new(config).is_err());
+    }
+}
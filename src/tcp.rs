@@ -0,0 +1,142 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::Semaphore,
+    time::timeout,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, warn};
+
+use crate::{
+    buffer::BytePacketBuffer,
+    dns::HEADER_SIZE,
+    util::{handle_query, Transport},
+    QUERY_LIMITER,
+};
+
+// How long an open connection may sit without sending a query.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads one message with its two byte length prefix (RFC 1035 section
+/// 4.2.2), returning it with its length. Returns `None` once the peer closes
+/// the connection.
+pub async fn read_message<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> io::Result<Option<(BytePacketBuffer, usize)>> {
+    let len = match stream.read_u16().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    if len < HEADER_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {len} bytes"),
+        ));
+    }
+
+    let mut buffer = BytePacketBuffer::with_size(len);
+
+    stream.read_exact(&mut buffer.buf[..len]).await?;
+
+    Ok(Some((buffer, len)))
+}
+
+/// Writes a response with its two byte length prefix.
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    buffer: &BytePacketBuffer,
+) -> io::Result<()> {
+    let len = buffer.pos();
+
+    let mut data = Vec::with_capacity(len + 2);
+    data.extend_from_slice(&(len as u16).to_be_bytes());
+    data.extend_from_slice(&buffer.buf[..len]);
+
+    stream.write_all(&data).await?;
+    stream.flush().await
+}
+
+/// Serves queries over plain TCP, the fallback for truncated UDP responses,
+/// until `shutdown` is cancelled. Connections beyond `max_connections` are
+/// closed straight away. Connections run on `tracker`.
+pub async fn serve(
+    listener: TcpListener,
+    max_connections: usize,
+    shutdown: CancellationToken,
+    tracker: TaskTracker,
+) {
+    let connections = Arc::new(Semaphore::new(max_connections));
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return,
+        };
+
+        let (stream, src) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("failed to accept TCP connection: {e}");
+                continue;
+            }
+        };
+
+        let Ok(permit) = connections.clone().try_acquire_owned() else {
+            debug!("refusing TCP connection from {src}: too many connections");
+            continue;
+        };
+
+        let shutdown = shutdown.clone();
+        tracker.spawn(async move {
+            let _permit = permit;
+
+            let result =
+                handle_connection(stream, src, Transport::Tcp, IDLE_TIMEOUT, shutdown).await;
+            if let Err(e) = result {
+                debug!("TCP connection from {src} failed: {e}");
+            }
+        });
+    }
+}
+
+/// Answers queries on one connection until it closes, sits idle for
+/// `idle_timeout`, or the server shuts down.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    src: SocketAddr,
+    transport: Transport,
+    idle_timeout: Duration,
+    shutdown: CancellationToken,
+) -> io::Result<()> {
+    loop {
+        let message = tokio::select! {
+            message = timeout(idle_timeout, read_message(&mut stream)) => message,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+
+        let (mut req_buffer, len) = match message {
+            Ok(Ok(Some(message))) => message,
+            Ok(Ok(None)) | Err(_) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+        };
+
+        if !QUERY_LIMITER.get().unwrap().allow(src.ip(), Instant::now()) {
+            continue;
+        }
+
+        match handle_query(&mut req_buffer, len, src, transport).await {
+            Ok(Some(res_buffer)) => write_message(&mut stream, &res_buffer).await?,
+            Ok(None) => {}
+            Err(e) => error!("failed to answer query from {src}: {e}"),
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{
+    self,
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+
+use crate::config::TlsConfig;
+
+/// Builds the server side TLS setup from the certificate and key files in
+/// `config`, offering the ALPN protocols in `alpn`.
+pub fn server_config(config: &TlsConfig, alpn: &[&[u8]]) -> Result<Arc<ServerConfig>, String> {
+    let (Some(cert), Some(key)) = (&config.cert, &config.key) else {
+        return Err("tls.cert and tls.key must be set to serve encrypted DNS".to_string());
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{cert}: {e}"))?;
+    if certs.is_empty() {
+        return Err(format!("{cert}: no certificates found"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("{key}: {e}"))?;
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e: rustls::Error| e.to_string())?;
+    server_config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+    Ok(Arc::new(server_config))
+}
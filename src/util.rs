@@ -1,79 +1,559 @@
-use std::{future::Future, net::Ipv4Addr, pin::Pin, time::Duration};
+use std::{
+    fs,
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
 
-use tokio::{net::UdpSocket, time::timeout};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    task,
+    time::{interval, timeout},
+};
+use tracing::{debug, error, info, trace, warn};
 
 use crate::{
-    buffer::BytePacketBuffer,
-    dns::{DnsPacket, DnsQuestion, QueryType, ResultCode},
-    DNS_CACHE,
+    acl::AclAction,
+    buffer::{BytePacketBuffer, MAX_MESSAGE_SIZE, UDP_MESSAGE_SIZE},
+    config::Config,
+    dns::{
+        DnsHeader, DnsPacket, DnsQuestion, DnsRecord, Edns, QueryType, ResultCode, EDNS_UDP_SIZE,
+        OPCODE_QUERY,
+    },
+    dnssec::{self, Security},
+    dnstap, logging,
+    metrics::qtype_label,
+    rrl::RrlDecision,
+    tcp, ACL, AUTHORITY, BLOCKLIST, CONFIG, DNS_CACHE, FORWARDER, HOSTS, METRICS, RPZ, RRL,
+    VALIDATOR,
 };
 
-pub async fn handle_query(req_buffer: &mut BytePacketBuffer) -> Result<BytePacketBuffer, String> {
-    let mut request = DnsPacket::from_buffer(req_buffer)?;
+/// How long to wait for an upstream server to answer.
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Upstream metrics label shared by every name server contacted during
+// recursion, of which there are far too many for a series each.
+const RECURSIVE_SERVER_LABEL: &str = "recursive";
+
+/// The transport a query arrived on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+    Quic,
+}
+
+impl Transport {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+            Transport::Https => "https",
+            Transport::Quic => "quic",
+        }
+    }
+}
+
+/// Answers the `len` byte query in `req_buffer`, or returns `None` if it is to
+/// be dropped.
+pub async fn handle_query(
+    req_buffer: &mut BytePacketBuffer,
+    len: usize,
+    src: SocketAddr,
+    transport: Transport,
+) -> Result<Option<BytePacketBuffer>, String> {
+    let start = Instant::now();
+    let query_time = SystemTime::now();
+    dnstap::client_query(src, transport, &req_buffer.buf[..len], query_time);
+
+    let mut cache_hit = false;
+    let mut client_edns = None;
+
+    let Some(mut packet) = answer_query(req_buffer, src, &mut cache_hit, &mut client_edns).await?
+    else {
+        return Ok(None);
+    };
+
+    let qtype = packet
+        .questions
+        .first()
+        .map(|question| qtype_label(question.qtype))
+        .unwrap_or_default();
+    METRICS.queries.inc(&[
+        &qtype,
+        &format!("{:?}", packet.header.rescode),
+        transport.as_str(),
+    ]);
+
+    // Only UDP can be spoofed for reflection, TCP is left alone. Errors
+    // without a question are limited together under the root name.
+    if transport == Transport::Udp {
+        let (qname, qtype) = match packet.questions.first() {
+            Some(question) => (question.name.as_str(), question.qtype),
+            None => ("", QueryType::UNKNOWN(0)),
+        };
+        let decision = RRL.get().unwrap().check(
+            src.ip(),
+            qname,
+            qtype,
+            packet.header.rescode,
+            Instant::now(),
+        );
+
+        match decision {
+            RrlDecision::Send => {}
+            RrlDecision::Slip => {
+                METRICS.rrl_limited.inc(&["slip"]);
+                truncate(&mut packet);
+            }
+            RrlDecision::Drop => {
+                METRICS.rrl_limited.inc(&["drop"]);
+                return Ok(None);
+            }
+        }
+    }
+
+    let max_size = match (transport, client_edns) {
+        (Transport::Udp, Some(edns)) => {
+            (edns.udp_size as usize).clamp(UDP_MESSAGE_SIZE, EDNS_UDP_SIZE as usize)
+        }
+        (Transport::Udp, None) => UDP_MESSAGE_SIZE,
+        _ => MAX_MESSAGE_SIZE,
+    };
+    let res_buffer = write_response(&mut packet, max_size)?;
+    let latency = start.elapsed();
+    METRICS
+        .query_duration
+        .observe(&[transport.as_str()], latency);
+    logging::log_query(src, &packet, cache_hit, latency, transport);
+    dnstap::client_response(
+        src,
+        transport,
+        query_time,
+        &res_buffer.buf[..res_buffer.pos()],
+    );
+
+    Ok(Some(res_buffer))
+}
+
+/// Works out the response to a query, or `None` if it is to be dropped.
+/// `cache_hit` is set when the answer came from the cache, and `client_edns`
+/// to the EDNS parameters of the query.
+async fn answer_query(
+    req_buffer: &mut BytePacketBuffer,
+    src: SocketAddr,
+    cache_hit: &mut bool,
+    client_edns: &mut Option<Edns>,
+) -> Result<Option<DnsPacket>, String> {
+    let mut header = DnsHeader::new();
+    if header.read(req_buffer).is_err() {
+        return Ok(None);
+    }
+
+    // Responses are never answered, or two servers could bounce errors back
+    // and forth forever (RFC 1035 section 4.1.1).
+    if header.response {
+        debug!("dropping response from {src}");
+        return Ok(None);
+    }
+
+    let access = ACL.get().unwrap().read().unwrap().check(src.ip());
+    let recurse = access == AclAction::Allow;
 
     let mut packet = DnsPacket::new();
-    packet.header.id = request.header.id;
-    packet.header.recursion_desired = true;
-    packet.header.recursion_available = true;
+    packet.header.id = header.id;
+    packet.header.opcode = header.opcode;
+    packet.header.recursion_desired = header.recursion_desired;
+    packet.header.recursion_available = recurse;
+    packet.header.checking_disabled = header.checking_disabled;
     packet.header.response = true;
 
-    if let Some(question) = request.questions.pop() {
-        if let Some(answers) = DNS_CACHE.get().unwrap().get(&question) {
-            println!("Found in cache: returning");
-            packet.questions.push(question);
-            packet.header.rescode = ResultCode::NOERROR;
+    req_buffer.seek(0)?;
+    let request = match DnsPacket::from_buffer(req_buffer) {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("malformed query from {src}: {e}");
+            if access == AclAction::Refuse {
+                return Ok(None);
+            }
+            packet.header.rescode = ResultCode::FORMERR;
+
+            return Ok(Some(packet));
+        }
+    };
 
-            for rec in answers.clone() {
-                packet.answers.push(rec);
+    *client_edns = request.edns;
+    let dnssec_ok = request.edns.is_some_and(|edns| edns.dnssec_ok);
+    packet.edns = request.edns.map(|_| Edns {
+        udp_size: EDNS_UDP_SIZE,
+        dnssec_ok,
+    });
+
+    if access == AclAction::Refuse {
+        debug!("refusing query from {src}");
+        packet.questions = request.questions;
+        packet.header.rescode = ResultCode::REFUSED;
+    } else if header.opcode != OPCODE_QUERY {
+        packet.questions = request.questions;
+        packet.header.rescode = ResultCode::NOTIMP;
+    } else if request.questions.len() != 1 {
+        packet.header.rescode = ResultCode::FORMERR;
+    } else {
+        let question = request.questions.into_iter().next().unwrap();
+
+        let result = if let Some(result) = local_lookup(&question) {
+            debug!("answering {} from local data", question.name);
+            Some(result)
+        } else if let Some(result) = blocked_lookup(&question) {
+            debug!("blocked {}", question.name);
+            METRICS.blocked.inc(&["blocklist"]);
+            Some(result)
+        } else {
+            match filtered_lookup(
+                &question,
+                src.ip(),
+                recurse,
+                header.checking_disabled,
+                cache_hit,
+            )
+            .await
+            {
+                Ok(Some(result)) => Some(result),
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    warn!("lookup of {} failed: {e}", question.name);
+                    None
+                }
             }
-        } else if let Ok(result) = recursive_lookup(&question.name, question.qtype).await {
-            packet.questions.push(question.clone());
-            packet.header.rescode = result.header.rescode;
-
-            if result.header.rescode.eq(&ResultCode::NOERROR) && !result.answers.is_empty() {
-                DNS_CACHE
-                    .get()
-                    .unwrap()
-                    .insert(question, result.answers.clone());
+        };
+
+        packet.questions.push(question);
+
+        match result {
+            Some(result) => {
+                packet.header.rescode = result.header.rescode;
+                packet.header.authoritative_answer = result.header.authoritative_answer;
+                // Only clients that show they understand AD get it (RFC 6840
+                // section 5.8).
+                packet.header.authed_data =
+                    result.header.authed_data && (header.authed_data || dnssec_ok);
+
+                packet.answers = result.answers;
+                packet.authorities = result.authorities;
+                packet.resources = result.resources;
+
+                // DNSSEC records go only to clients that asked for them with
+                // DO, unless they are what was queried (RFC 4035 section
+                // 3.2.1).
+                if !dnssec_ok {
+                    let qtype = packet.questions[0].qtype;
+                    let wanted = |rec: &DnsRecord| {
+                        rec.qtype() == qtype || !dnssec::is_dnssec_type(rec.qtype())
+                    };
+                    packet.answers.retain(wanted);
+                    packet.authorities.retain(wanted);
+                    packet.resources.retain(wanted);
+                }
             }
+            None => packet.header.rescode = ResultCode::SERVFAIL,
+        }
+    }
 
-            for rec in result.answers {
-                println!("answer: {rec:?}");
-                packet.answers.push(rec);
-            }
+    Ok(Some(packet))
+}
+
+fn write_response(packet: &mut DnsPacket, max_size: usize) -> Result<BytePacketBuffer, String> {
+    let mut res_buffer = BytePacketBuffer::with_size(max_size);
+    if packet.write(&mut res_buffer).is_err() {
+        // Too big for the buffer, the client can retry over TCP.
+        truncate(packet);
+        res_buffer = BytePacketBuffer::with_size(max_size);
+        packet.write(&mut res_buffer)?;
+    }
+
+    Ok(res_buffer)
+}
+
+/// Strips a response down to its header and question, with TC set.
+fn truncate(packet: &mut DnsPacket) {
+    packet.header.truncated_message = true;
+    packet.answers.clear();
+    packet.authorities.clear();
+    packet.resources.clear();
+}
+
+/// Answers from data served locally: static host overrides first, then the
+/// authoritative zones.
+fn local_lookup(question: &DnsQuestion) -> Option<DnsPacket> {
+    let hosts = HOSTS.get().unwrap().read().unwrap();
+    if let Some(result) = hosts.lookup(&question.name, question.qtype) {
+        return Some(result);
+    }
+
+    AUTHORITY
+        .get()
+        .unwrap()
+        .read()
+        .unwrap()
+        .lookup(&question.name, question.qtype)
+}
 
-            for rec in result.authorities {
-                println!("Auth: {rec:?}");
-                packet.authorities.push(rec);
+fn blocked_lookup(question: &DnsQuestion) -> Option<DnsPacket> {
+    BLOCKLIST
+        .get()
+        .unwrap()
+        .read()
+        .unwrap()
+        .lookup(&question.name, question.qtype)
+}
+
+/// Resolves through the cache or, if `recurse` allows, recursion, applying the
+/// response policy zones before and after. Returns `None` when policy says to
+/// drop the query.
+async fn filtered_lookup(
+    question: &DnsQuestion,
+    client: IpAddr,
+    recurse: bool,
+    checking_disabled: bool,
+    cache_hit: &mut bool,
+) -> Result<Option<DnsPacket>, String> {
+    let hit = RPZ
+        .get()
+        .unwrap()
+        .read()
+        .unwrap()
+        .check_query(&question.name, client);
+
+    let passthru = match hit {
+        Some(hit) => {
+            info!("policy hit for {} from {client}: {hit}", question.name);
+            if !hit.is_passthru() {
+                METRICS.blocked.inc(&["rpz"]);
+                return Ok(hit.respond(question));
             }
+            true
+        }
+        None => false,
+    };
+
+    let mut nameservers = Vec::new();
+    let result = cached_lookup(
+        question,
+        recurse,
+        checking_disabled,
+        &mut nameservers,
+        cache_hit,
+    )
+    .await?;
 
-            for rec in result.resources {
-                println!("Resources: {rec:?}");
-                packet.resources.push(rec);
+    if passthru {
+        return Ok(Some(result));
+    }
+
+    let hit = RPZ
+        .get()
+        .unwrap()
+        .read()
+        .unwrap()
+        .check_response(&result, &nameservers);
+
+    match hit {
+        Some(hit) => {
+            info!("policy hit for {} from {client}: {hit}", question.name);
+            if hit.is_passthru() {
+                Ok(Some(result))
+            } else {
+                METRICS.blocked.inc(&["rpz"]);
+                Ok(hit.respond(question))
             }
+        }
+        None => Ok(Some(result)),
+    }
+}
+
+/// Answers from the cache, falling back to a recursive lookup whose answers
+/// are then cached. Name servers consulted on the way end up in `nameservers`.
+/// Without `recurse`, cache misses are refused. When the lookup fails or is
+/// slow, expired data still within the stale window is served instead.
+/// `checking_disabled` lets data that fails DNSSEC validation through.
+async fn cached_lookup(
+    question: &DnsQuestion,
+    recurse: bool,
+    checking_disabled: bool,
+    nameservers: &mut Vec<String>,
+    cache_hit: &mut bool,
+) -> Result<DnsPacket, String> {
+    let cache = DNS_CACHE.get().unwrap();
+
+    if let Some(hit) = cache.get(question) {
+        debug!("found {} {:?} in cache", question.name, question.qtype);
+        *cache_hit = true;
+
+        if hit.stale {
+            METRICS.cache_stale.inc(&["recheck"]);
         } else {
-            packet.header.rescode = ResultCode::SERVFAIL;
+            METRICS.cache_hits.inc(&[]);
         }
-    } else {
-        packet.header.rescode = ResultCode::FORMERR;
+
+        if hit.prefetch && recurse {
+            tokio::spawn(prefetch(question.clone()));
+        }
+
+        *nameservers = hit.nameservers;
+        return Ok(hit.packet);
     }
 
-    let mut res_buffer = BytePacketBuffer::new();
-    packet.write(&mut res_buffer)?;
+    METRICS.cache_misses.inc(&[]);
 
-    Ok(res_buffer)
+    if !recurse {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = ResultCode::REFUSED;
+
+        return Ok(packet);
+    }
+
+    let _inflight = METRICS.inflight.track();
+
+    let Some(stale) = cache.get_stale(question) else {
+        let (result, consulted) = resolve(question.clone(), checking_disabled).await?;
+        *nameservers = consulted;
+
+        return Ok(result);
+    };
+
+    // The lookup runs as its own task so that, if it outlasts the timer,
+    // it carries on refreshing the cache after the stale answer is sent.
+    let refresh = refresh_stale(
+        question.clone(),
+        resolve(question.clone(), checking_disabled),
+    );
+    let mut lookup = tokio::spawn(refresh);
+    let reason = match timeout(cache.stale_timeout(), &mut lookup).await {
+        Err(_) => "timeout",
+        Ok(joined) => match joined.map_err(|e| e.to_string()).and_then(|result| result) {
+            Ok((result, consulted)) if result.header.rescode != ResultCode::SERVFAIL => {
+                *nameservers = consulted;
+                return Ok(result);
+            }
+            _ => "failure",
+        },
+    };
+
+    debug!(
+        "serving stale {} {:?} after {reason}",
+        question.name, question.qtype
+    );
+    METRICS.cache_stale.inc(&[reason]);
+    *cache_hit = true;
+    *nameservers = stale.nameservers;
+
+    Ok(stale.packet)
+}
+
+/// Runs `lookup` to refresh the stale data for `question`. A failure is
+/// recorded in the cache whether or not anyone still waits for the result,
+/// so that the stale data is served without a lookup until a retry is due.
+async fn refresh_stale(
+    question: DnsQuestion,
+    lookup: impl Future<Output = Result<(DnsPacket, Vec<String>), String>>,
+) -> Result<(DnsPacket, Vec<String>), String> {
+    let result = lookup.await;
+
+    let failed = match &result {
+        Ok((response, _)) => response.header.rescode == ResultCode::SERVFAIL,
+        Err(e) => {
+            debug!("lookup of {} failed: {e}", question.name);
+            true
+        }
+    };
+    if failed {
+        DNS_CACHE.get().unwrap().mark_failed(&question);
+    }
+
+    result
+}
+
+/// Resolves `question` recursively and caches the result, returning it with
+/// the name servers that were consulted. With DNSSEC validation on, AD is set
+/// on secure results and bogus ones fail, unless `checking_disabled` asks for
+/// them anyway, in which case they are returned but not cached.
+async fn resolve(
+    question: DnsQuestion,
+    checking_disabled: bool,
+) -> Result<(DnsPacket, Vec<String>), String> {
+    let mut nameservers = Vec::new();
+    let mut result = recursive_lookup(&question.name, question.qtype, &mut nameservers).await?;
+
+    // Only our own validation can vouch for the data.
+    result.header.authed_data = false;
+    if let Some(validator) = VALIDATOR.get() {
+        let security = validator.validate(&question, &result).await;
+        METRICS.dnssec_validations.inc(&[security.as_str()]);
+
+        match security {
+            Security::Secure => result.header.authed_data = true,
+            Security::Insecure => {}
+            Security::Bogus(reason) if checking_disabled => {
+                debug!("passing on bogus {} with CD set: {reason}", question.name);
+                return Ok((result, nameservers));
+            }
+            Security::Bogus(reason) => return Err(format!("DNSSEC validation failed: {reason}")),
+        }
+    }
+
+    for rec in &result.answers {
+        trace!("answer: {rec:?}");
+    }
+
+    for rec in &result.authorities {
+        trace!("authority: {rec:?}");
+    }
+
+    for rec in &result.resources {
+        trace!("additional: {rec:?}");
+    }
+
+    DNS_CACHE
+        .get()
+        .unwrap()
+        .insert(question, &result, &nameservers);
+
+    Ok((result, nameservers))
 }
 
-fn recursive_lookup<'a>(
+/// Refreshes a popular cache entry before it expires.
+async fn prefetch(question: DnsQuestion) {
+    debug!("prefetching {} {:?}", question.name, question.qtype);
+    METRICS.cache_prefetches.inc(&[]);
+
+    if let Err(e) = resolve(question.clone(), false).await {
+        warn!("prefetch of {} failed: {e}", question.name);
+    }
+    DNS_CACHE.get().unwrap().end_prefetch(&question);
+}
+
+/// Resolves `qname` iteratively from the root, without the cache or
+/// validation. Name servers consulted on the way end up in `nameservers`.
+pub fn recursive_lookup<'a>(
     qname: &'a str,
     qtype: QueryType,
+    nameservers: &'a mut Vec<String>,
 ) -> Pin<Box<dyn Future<Output = Result<DnsPacket, String>> + Send + 'a>> {
     Box::pin(async move {
+        if let Some(forwarder) = FORWARDER.get() {
+            return forwarder.lookup(qname, qtype).await;
+        }
+
         // Using one of the root server from the global root server.
         let mut ns = "198.41.0.4".parse::<Ipv4Addr>().unwrap();
 
         loop {
-            println!("atempting lookup of {qtype:?} {qname} with ns {ns}");
+            debug!("attempting lookup of {qtype:?} {qname} with ns {ns}");
 
             let ns_copy = ns;
 
@@ -88,6 +568,8 @@ fn recursive_lookup<'a>(
                 return Ok(response);
             }
 
+            nameservers.extend(response.get_ns(qname).map(|(_, host)| host.to_string()));
+
             if let Some(new_ns) = response.get_resolved_ns(qname) {
                 ns = new_ns;
 
@@ -99,7 +581,8 @@ fn recursive_lookup<'a>(
                 None => return Ok(response),
             };
 
-            let recursive_response = recursive_lookup(new_ns_name, QueryType::A).await?;
+            let recursive_response =
+                recursive_lookup(new_ns_name, QueryType::A, &mut Vec::new()).await?;
 
             if let Some(new_ns) = recursive_response.get_random_a() {
                 ns = new_ns;
@@ -119,49 +602,324 @@ async fn lookup(
         .await
         .map_err(|e| e.to_string())?;
 
+    let mut packet = query_packet(qname, qtype);
+    packet.header.id = 6666;
+
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+
+    let query_time = SystemTime::now();
+    socket
+        .send_to(&req_buffer.buf[0..req_buffer.pos], server)
+        .await
+        .map_err(|e| e.to_string())?;
+    dnstap::resolver_query(
+        server.into(),
+        Transport::Udp,
+        &req_buffer.buf[0..req_buffer.pos],
+        query_time,
+    );
+
+    METRICS.upstream_queries.inc(&[RECURSIVE_SERVER_LABEL]);
+    let start = Instant::now();
+
+    let mut res_buffer = BytePacketBuffer::with_size(EDNS_UDP_SIZE as usize);
+
+    match timeout(UPSTREAM_TIMEOUT, socket.recv_from(&mut res_buffer.buf)).await {
+        Ok(Ok((len, _))) => {
+            METRICS
+                .upstream_duration
+                .observe(&[RECURSIVE_SERVER_LABEL], start.elapsed());
+            dnstap::resolver_response(
+                server.into(),
+                Transport::Udp,
+                query_time,
+                &res_buffer.buf[..len],
+            );
+        }
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => {
+            METRICS.upstream_timeouts.inc(&[RECURSIVE_SERVER_LABEL]);
+            return Err(format!(
+                "query for {qtype:?} {qname} to {} timed out",
+                server.0
+            ));
+        }
+    };
+
+    let response = DnsPacket::from_buffer(&mut res_buffer)?;
+    if response.header.truncated_message {
+        debug!("retrying {qtype:?} {qname} to {} over TCP", server.0);
+        return lookup_tcp(&req_buffer, server.into()).await;
+    }
+
+    Ok(response)
+}
+
+/// Builds a query to send upstream, asking for DNSSEC records when they are
+/// to be validated.
+pub fn query_packet(qname: &str, qtype: QueryType) -> DnsPacket {
     let mut packet = DnsPacket::new();
 
-    packet.header.id = 6666;
     packet.header.questions = 1;
     packet.header.recursion_desired = true;
 
     packet
         .questions
         .push(DnsQuestion::new(qname.to_string(), qtype));
+    packet.edns = Some(Edns {
+        udp_size: EDNS_UDP_SIZE,
+        dnssec_ok: VALIDATOR.get().is_some(),
+    });
 
-    let mut req_buffer = BytePacketBuffer::new();
-    packet.write(&mut req_buffer)?;
+    packet
+}
 
-    socket
-        .send_to(&req_buffer.buf[0..req_buffer.pos], server)
-        .await
-        .map_err(|e| e.to_string())?;
+/// Sends a query over TCP, for responses too big for UDP.
+async fn lookup_tcp(
+    req_buffer: &BytePacketBuffer,
+    server: SocketAddr,
+) -> Result<DnsPacket, String> {
+    METRICS.upstream_queries.inc(&[RECURSIVE_SERVER_LABEL]);
+    let start = Instant::now();
+
+    let query_time = SystemTime::now();
+    let exchange = async {
+        let mut stream = TcpStream::connect(server).await?;
+        tcp::write_message(&mut stream, req_buffer).await?;
+        dnstap::resolver_query(
+            server,
+            Transport::Tcp,
+            &req_buffer.buf[..req_buffer.pos],
+            query_time,
+        );
+
+        tcp::read_message(&mut stream).await
+    };
 
-    let mut res_buffer = BytePacketBuffer::new();
+    match timeout(UPSTREAM_TIMEOUT, exchange).await {
+        Ok(Ok(Some((mut res_buffer, len)))) => {
+            METRICS
+                .upstream_duration
+                .observe(&[RECURSIVE_SERVER_LABEL], start.elapsed());
+            dnstap::resolver_response(server, Transport::Tcp, query_time, &res_buffer.buf[..len]);
 
-    match timeout(
-        Duration::from_secs(5),
-        socket.recv_from(&mut res_buffer.buf),
-    )
-    .await
-    {
-        Ok(Ok(_)) => {}
-        Ok(Err(e)) => return Err(e.to_string()),
+            DnsPacket::from_buffer(&mut res_buffer)
+        }
+        Ok(Ok(None)) => Err(format!("{server} closed the connection")),
+        Ok(Err(e)) => Err(e.to_string()),
         Err(_) => {
-            eprintln!("DNS query timed out!");
-            let mut resp_packet = DnsPacket::new();
-            resp_packet.header.id = packet.header.id;
-            resp_packet.header.recursion_desired = packet.header.recursion_desired;
-            resp_packet.header.recursion_available = true;
-            resp_packet.header.response = true;
-            resp_packet
-                .questions
-                .push(DnsQuestion::new(qname.to_string(), qtype));
-
-            resp_packet.header.rescode = ResultCode::NXDOMAIN;
-            return Ok(resp_packet);
+            METRICS.upstream_timeouts.inc(&[RECURSIVE_SERVER_LABEL]);
+            Err(format!("query over TCP to {server} timed out"))
         }
+    }
+}
+
+/// Returns the modification time of each file, so that reloaders can tell
+/// when any of them changed on disk.
+fn modified_times(files: &[String]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+/// Watches the `files` named in the config and swaps a freshly loaded value
+/// into `target` whenever one of them changes on disk. The config is read
+/// again on every check, as a config reload may change the files. A failed
+/// load keeps the previous value.
+pub fn spawn_reloader<T: Send + Sync + 'static>(
+    what: &'static str,
+    reload_interval: u64,
+    files: fn(&Config) -> &[String],
+    load: fn(&Config) -> Result<T, String>,
+    target: Arc<RwLock<T>>,
+) {
+    if reload_interval == 0 {
+        return;
+    }
+
+    let current = move || modified_times(files(&CONFIG.get().unwrap().read().unwrap()));
+
+    tokio::spawn(async move {
+        let mut ticker = interval(Duration::from_secs(reload_interval));
+        let mut last_seen = current();
+
+        loop {
+            ticker.tick().await;
+
+            let seen = current();
+            if seen == last_seen {
+                continue;
+            }
+            last_seen = seen;
+
+            // Files such as blocklists can run to millions of lines, so they
+            // are parsed on the blocking pool while queries keep being
+            // answered.
+            let config = CONFIG.get().unwrap().read().unwrap().clone();
+            let loaded = task::spawn_blocking(move || load(&config)).await;
+            match loaded.map_err(|e| e.to_string()).and_then(|result| result) {
+                Ok(value) => {
+                    info!("reloaded {what}");
+                    *target.write().unwrap() = value;
+                }
+                Err(e) => error!("failed to reload {what}: {e}"),
+            }
+        }
+    });
+}
+
+/// A file in the temporary directory for tests, named so that tests running
+/// at the same time don't collide, and removed again when dropped.
+#[cfg(test)]
+pub(crate) struct TempFile(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempFile {
+    pub(crate) fn new(content: &str) -> TempFile {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+
+        let n = FILES.fetch_add(1, Ordering::Relaxed);
+        let name = format!("dns-server-test-{}-{n}", std::process::id());
+        let file = TempFile(std::env::temp_dir().join(name));
+        fs::write(&file.0, content).unwrap();
+        file
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        acl::Acl,
+        cache::Cache,
+        config::{AclConfig, CacheConfig, RrlConfig},
+        rrl::RateLimiter,
     };
 
-    DnsPacket::from_buffer(&mut res_buffer)
+    // A query for example.com A, with QDCOUNT 1.
+    const QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+        \x07example\x03com\x00\x00\x01\x00\x01";
+
+    // The same header followed by a name that points at itself.
+    const MALFORMED: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\xc0\x0c";
+
+    /// Sets up the globals the query path reads: the default ACL, and RRL
+    /// allowing two identical responses a second.
+    fn init() {
+        ACL.get_or_init(|| Arc::new(RwLock::new(Acl::new(&AclConfig::default()).unwrap())));
+        RRL.get_or_init(|| {
+            let config = RrlConfig {
+                responses_per_second: 2,
+                slip: 0,
+                ..Default::default()
+            };
+            Arc::new(RateLimiter::new(config).unwrap())
+        });
+    }
+
+    async fn handle(message: &[u8], src: &str) -> Option<DnsPacket> {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.buf[..message.len()].copy_from_slice(message);
+
+        let src = src.parse().unwrap();
+        let mut response = handle_query(&mut buffer, message.len(), src, Transport::Udp)
+            .await
+            .unwrap()?;
+        response.seek(0).unwrap();
+        Some(DnsPacket::from_buffer(&mut response).unwrap())
+    }
+
+    #[tokio::test]
+    async fn drops_responses() {
+        init();
+
+        let mut response = QUERY.to_vec();
+        response[2] |= 0x80;
+        assert!(handle(&response, "127.0.0.1:5300").await.is_none());
+
+        let mut malformed = MALFORMED.to_vec();
+        malformed[2] |= 0x80;
+        assert!(handle(&malformed, "127.0.0.1:5300").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn answers_malformed_queries_with_formerr_within_limits() {
+        init();
+
+        let response = handle(MALFORMED, "10.1.1.1:5300").await.unwrap();
+        assert!(response.header.response);
+        assert_eq!(response.header.id, 0x1234);
+        assert_eq!(response.header.rescode, ResultCode::FORMERR);
+        assert!(response.questions.is_empty());
+
+        // Rate limited like any other response, question or not.
+        assert!(handle(MALFORMED, "10.1.1.2:5300").await.is_some());
+        assert!(handle(MALFORMED, "10.1.1.3:5300").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn drops_malformed_queries_from_refused_clients() {
+        init();
+
+        assert!(handle(MALFORMED, "192.0.2.1:5300").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn records_failed_refreshes_that_outlast_the_stale_timeout() {
+        let cache = DNS_CACHE.get_or_init(|| {
+            let config = CacheConfig {
+                stale_window: 3600,
+                ..Default::default()
+            };
+            Arc::new(Cache::new(&config))
+        });
+
+        let question = DnsQuestion::new("stale.example.com".to_string(), QueryType::A);
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+        response.questions.push(question.clone());
+        response.answers.push(DnsRecord::A {
+            domain: question.name.clone(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 1,
+        });
+        cache.insert(question.clone(), &response, &[]);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(cache.get(&question).is_none());
+
+        // The caller gives up on the refresh before it fails, as when the
+        // stale answer is sent after the stale timeout.
+        let lookup = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Err("upstream unreachable".to_string())
+        };
+        let mut refresh = tokio::spawn(refresh_stale(question.clone(), lookup));
+        assert!(timeout(Duration::from_millis(10), &mut refresh)
+            .await
+            .is_err());
+        refresh.await.unwrap().unwrap_err();
+
+        let hit = cache.get(&question).unwrap();
+        assert!(hit.stale);
+        assert_eq!(
+            hit.packet.answers[0].to_string(),
+            "stale.example.com. 30 IN A 192.0.2.1"
+        );
+    }
 }
@@ -0,0 +1,436 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use crate::{
+    config::ZoneConfig,
+    dns::{DnsPacket, DnsRecord, QueryType, ResultCode},
+    master,
+};
+
+// Bounds how many in-zone CNAMEs are followed for a single answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// Returns true if `name` is `parent` or lies below it.
+pub fn is_subdomain(name: &str, parent: &str) -> bool {
+    parent.is_empty()
+        || name == parent
+        || (name.len() > parent.len()
+            && name.ends_with(parent)
+            && name.as_bytes()[name.len() - parent.len() - 1] == b'.')
+}
+
+/// Strips the leftmost label of `name`, returning `None` for the root.
+pub fn parent_name(name: &str) -> Option<&str> {
+    if name.is_empty() {
+        return None;
+    }
+
+    Some(name.split_once('.').map(|(_, parent)| parent).unwrap_or(""))
+}
+
+#[derive(Debug)]
+pub struct Zone {
+    pub origin: String,
+    records: HashMap<String, Vec<DnsRecord>>,
+    // Every owner name plus all its ancestors down to the origin, so that
+    // empty non-terminals are told apart from names that do not exist.
+    nodes: HashSet<String>,
+}
+
+impl Zone {
+    pub fn load(config: &ZoneConfig) -> Result<Zone, String> {
+        let records = master::parse_file(Path::new(&config.file), &config.origin)?;
+
+        Zone::from_records(&master::normalize_name(&config.origin), records)
+            .map_err(|e| format!("{}: {e}", config.file))
+    }
+
+    pub fn from_records(origin: &str, records: Vec<DnsRecord>) -> Result<Zone, String> {
+        let mut zone = Zone {
+            origin: origin.to_string(),
+            records: HashMap::new(),
+            nodes: HashSet::new(),
+        };
+
+        for rec in records {
+            let owner = rec.domain().to_string();
+            if !is_subdomain(&owner, origin) {
+                return Err(format!("record {owner} is outside of zone {origin}"));
+            }
+
+            let mut node = owner.as_str();
+            while zone.nodes.insert(node.to_string()) && node != origin {
+                node = parent_name(node).unwrap_or("");
+            }
+
+            zone.records.entry(owner).or_default().push(rec);
+        }
+
+        let soa_count = zone
+            .records
+            .get(origin)
+            .map(|rrs| {
+                rrs.iter()
+                    .filter(|rec| rec.qtype() == QueryType::SOA)
+                    .count()
+            })
+            .unwrap_or(0);
+        if soa_count != 1 {
+            return Err(format!("zone {origin} needs exactly one SOA at its apex"));
+        }
+
+        Ok(zone)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        is_subdomain(name, &self.origin)
+    }
+
+    /// Answers a query for a name inside this zone.
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet.header.authoritative_answer = true;
+
+        let mut name = qname.to_string();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            if let Some(cut) = self.find_cut(&name, qtype) {
+                // A CNAME chain leading into a delegated subzone is handed
+                // back as is, the client has to chase the rest.
+                if packet.answers.is_empty() {
+                    return self.referral(cut);
+                }
+
+                return packet;
+            }
+
+            let rrs: Vec<DnsRecord> = match self.records.get(&name) {
+                Some(rrs) => rrs.clone(),
+                // An empty non-terminal exists, it just has no data.
+                None if self.nodes.contains(&name) => Vec::new(),
+                None => match self.wildcard(&name) {
+                    Some(rrs) => rrs.iter().map(|rec| rec.with_domain(&name)).collect(),
+                    None => {
+                        packet.header.rescode = ResultCode::NXDOMAIN;
+                        packet.authorities.push(self.negative_soa());
+
+                        return packet;
+                    }
+                },
+            };
+
+            let matching: Vec<&DnsRecord> = rrs.iter().filter(|rec| rec.qtype() == qtype).collect();
+            if !matching.is_empty() {
+                packet.answers.extend(matching.into_iter().cloned());
+
+                return packet;
+            }
+
+            let cname = rrs.iter().find_map(|rec| match rec {
+                DnsRecord::CNAME { host, .. } if qtype != QueryType::CNAME => Some((rec, host)),
+                _ => None,
+            });
+
+            match cname {
+                Some((rec, host)) => {
+                    packet.answers.push(rec.clone());
+
+                    // Targets outside the zone are left to the client to chase.
+                    if !self.contains(host) {
+                        return packet;
+                    }
+                    name = host.clone();
+                }
+                None => {
+                    packet.authorities.push(self.negative_soa());
+
+                    return packet;
+                }
+            }
+        }
+
+        packet
+    }
+
+    /// Looks up the wildcard that may synthesise answers for the non-existent
+    /// `name`, as per RFC 4592. Only the wildcard directly below the closest
+    /// encloser applies; an empty slice means it exists without any data.
+    fn wildcard(&self, name: &str) -> Option<&[DnsRecord]> {
+        let mut encloser = parent_name(name)?;
+        while !self.nodes.contains(encloser) {
+            encloser = parent_name(encloser)?;
+        }
+
+        let source = format!("*.{encloser}");
+        match self.records.get(&source) {
+            Some(rrs) => Some(rrs),
+            None if self.nodes.contains(&source) => Some(&[]),
+            None => None,
+        }
+    }
+
+    /// Finds the topmost delegation point between the apex and `name`,
+    /// i.e. a non-apex node holding NS records. The DS records of a cut
+    /// belong to this side of it (RFC 4035 section 3.1.4.1), so a DS query
+    /// for the cut itself is answered here.
+    fn find_cut<'a>(&self, name: &'a str, qtype: QueryType) -> Option<&'a str> {
+        let mut cut = None;
+        let mut node = name;
+
+        while node != self.origin {
+            let delegated = self
+                .records
+                .get(node)
+                .is_some_and(|rrs| rrs.iter().any(|rec| rec.qtype() == QueryType::NS));
+            if delegated && !(node == name && qtype == QueryType::DS) {
+                cut = Some(node);
+            }
+
+            node = parent_name(node)?;
+        }
+
+        cut
+    }
+
+    /// Builds a non-authoritative referral to the subzone delegated at `cut`,
+    /// with in-zone glue for its name servers.
+    fn referral(&self, cut: &str) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+
+        for rec in &self.records[cut] {
+            let DnsRecord::NS { host, .. } = rec else {
+                continue;
+            };
+            packet.authorities.push(rec.clone());
+
+            if !self.contains(host) {
+                continue;
+            }
+
+            let glue = self
+                .records
+                .get(host)
+                .into_iter()
+                .flatten()
+                .filter(|rec| matches!(rec.qtype(), QueryType::A | QueryType::AAAA));
+            packet.resources.extend(glue.cloned());
+        }
+
+        packet
+    }
+
+    fn soa(&self) -> &DnsRecord {
+        self.records[&self.origin]
+            .iter()
+            .find(|rec| rec.qtype() == QueryType::SOA)
+            .expect("zone without SOA")
+    }
+
+    /// The apex SOA as it goes into negative answers, with its TTL capped by
+    /// the SOA minimum as RFC 2308 requires.
+    fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa().clone();
+        if let DnsRecord::SOA {
+            ref mut ttl,
+            minimum,
+            ..
+        } = soa
+        {
+            *ttl = (*ttl).min(minimum);
+        }
+
+        soa
+    }
+}
+
+/// The set of zones this server is authoritative for.
+#[derive(Debug, Default)]
+pub struct Authority {
+    zones: Vec<Zone>,
+}
+
+impl Authority {
+    pub fn load(configs: &[ZoneConfig]) -> Result<Authority, String> {
+        let zones = configs
+            .iter()
+            .map(Zone::load)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Authority { zones })
+    }
+
+    /// Finds the closest enclosing zone for `qname`, if any.
+    pub fn find_zone(&self, qname: &str) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|zone| zone.contains(qname))
+            .max_by_key(|zone| zone.origin.len())
+    }
+
+    /// Answers authoritatively when `qname` falls inside one of the zones.
+    pub fn lookup(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        self.find_zone(qname).map(|zone| zone.lookup(qname, qtype))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZONE: &str = "
+$TTL 3600
+@            SOA   ns1 hostmaster 1 7200 900 1209600 300
+@            NS    ns1
+ns1          A     192.0.2.53
+www          A     192.0.2.1
+alias        CNAME www
+*.wild       A     192.0.2.2
+a.ent.wild   A     192.0.2.3
+*.cdn        CNAME www
+sub          NS    ns.sub
+sub          DS    12345 13 2 ( 49FD46E6C4B45C55D4AC69CBD3CD3440
+                                9A5C79D3A5F4C7E2D9E1C0A3B9A7F2E1 )
+ns.sub       A     192.0.2.54
+";
+
+    fn zone() -> Zone {
+        Zone::from_records(
+            "example.com",
+            master::parse_str(ZONE, "example.com").unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn texts(records: &[DnsRecord]) -> Vec<String> {
+        records.iter().map(|rec| rec.to_string()).collect()
+    }
+
+    const NEGATIVE_SOA: &str =
+        "example.com. 300 IN SOA ns1.example.com. hostmaster.example.com. 1 7200 900 1209600 300";
+
+    #[test]
+    fn answers_exact_matches_authoritatively() {
+        let packet = zone().lookup("www.example.com", QueryType::A);
+
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert_eq!(
+            texts(&packet.answers),
+            ["www.example.com. 3600 IN A 192.0.2.1"]
+        );
+        assert!(packet.authorities.is_empty());
+    }
+
+    #[test]
+    fn follows_in_zone_cnames() {
+        let packet = zone().lookup("alias.example.com", QueryType::A);
+
+        assert_eq!(
+            texts(&packet.answers),
+            [
+                "alias.example.com. 3600 IN CNAME www.example.com.",
+                "www.example.com. 3600 IN A 192.0.2.1",
+            ]
+        );
+    }
+
+    #[test]
+    fn denies_missing_names_and_types_with_the_soa() {
+        let nxdomain = zone().lookup("missing.example.com", QueryType::A);
+        assert!(nxdomain.header.authoritative_answer);
+        assert_eq!(nxdomain.header.rescode, ResultCode::NXDOMAIN);
+        assert!(nxdomain.answers.is_empty());
+        assert_eq!(texts(&nxdomain.authorities), [NEGATIVE_SOA]);
+
+        let nodata = zone().lookup("www.example.com", QueryType::AAAA);
+        assert!(nodata.header.authoritative_answer);
+        assert_eq!(nodata.header.rescode, ResultCode::NOERROR);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(texts(&nodata.authorities), [NEGATIVE_SOA]);
+    }
+
+    #[test]
+    fn refers_below_zone_cuts_with_glue() {
+        for qname in ["sub.example.com", "www.sub.example.com"] {
+            let packet = zone().lookup(qname, QueryType::A);
+
+            assert!(!packet.header.authoritative_answer);
+            assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+            assert!(packet.answers.is_empty());
+            assert_eq!(
+                texts(&packet.authorities),
+                ["sub.example.com. 3600 IN NS ns.sub.example.com."]
+            );
+            assert_eq!(
+                texts(&packet.resources),
+                ["ns.sub.example.com. 3600 IN A 192.0.2.54"]
+            );
+        }
+    }
+
+    #[test]
+    fn answers_ds_queries_for_cuts_at_the_parent() {
+        let packet = zone().lookup("sub.example.com", QueryType::DS);
+
+        assert!(packet.header.authoritative_answer);
+        assert_eq!(
+            texts(&packet.answers),
+            ["sub.example.com. 3600 IN DS 12345 13 2 \
+              49FD46E6C4B45C55D4AC69CBD3CD34409A5C79D3A5F4C7E2D9E1C0A3B9A7F2E1"]
+        );
+
+        // Below the cut, DS queries are referred like any other.
+        let packet = zone().lookup("x.sub.example.com", QueryType::DS);
+        assert!(!packet.header.authoritative_answer);
+        assert!(packet.answers.is_empty());
+    }
+
+    #[test]
+    fn synthesises_answers_from_the_wildcard_at_the_closest_encloser() {
+        for qname in ["x.wild.example.com", "x.y.wild.example.com"] {
+            let packet = zone().lookup(qname, QueryType::A);
+
+            assert!(packet.header.authoritative_answer);
+            assert_eq!(
+                texts(&packet.answers),
+                [format!("{qname}. 3600 IN A 192.0.2.2")]
+            );
+        }
+
+        let nodata = zone().lookup("x.wild.example.com", QueryType::AAAA);
+        assert_eq!(nodata.header.rescode, ResultCode::NOERROR);
+        assert!(nodata.answers.is_empty());
+        assert_eq!(texts(&nodata.authorities), [NEGATIVE_SOA]);
+    }
+
+    #[test]
+    fn empty_non_terminals_block_wildcards() {
+        // ent.wild exists without data, so it is not matched by *.wild...
+        let packet = zone().lookup("ent.wild.example.com", QueryType::A);
+        assert_eq!(packet.header.rescode, ResultCode::NOERROR);
+        assert!(packet.answers.is_empty());
+
+        // ...and is the closest encloser of the names below it, which have no
+        // wildcard of their own.
+        let packet = zone().lookup("x.ent.wild.example.com", QueryType::A);
+        assert_eq!(packet.header.rescode, ResultCode::NXDOMAIN);
+        assert_eq!(texts(&packet.authorities), [NEGATIVE_SOA]);
+    }
+
+    #[test]
+    fn follows_wildcard_cnames() {
+        let packet = zone().lookup("img.cdn.example.com", QueryType::A);
+
+        assert_eq!(
+            texts(&packet.answers),
+            [
+                "img.cdn.example.com. 3600 IN CNAME www.example.com.",
+                "www.example.com. 3600 IN A 192.0.2.1",
+            ]
+        );
+    }
+}